@@ -0,0 +1,17 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pngme::fragment;
+use pngme::png::Png;
+
+fuzz_target!(|data: &[u8]| {
+    let (png, _diagnostics) = Png::parse_lenient(data);
+
+    let mut chunk_types: Vec<String> = png.chunks().iter().map(|chunk| chunk.chunk_type().to_string()).collect();
+    chunk_types.sort();
+    chunk_types.dedup();
+    for chunk_type in chunk_types {
+        // Errors are fine, only panics and runaway allocations are not
+        let _ = fragment::extract(&png, &chunk_type);
+    }
+});
@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pngme::chunk::Chunk;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(chunk) = Chunk::try_from(data) {
+        let bytes = chunk.as_bytes();
+        assert_eq!(&data[..bytes.len()], bytes.as_slice());
+    }
+});
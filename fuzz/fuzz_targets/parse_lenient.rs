@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pngme::png::Png;
+
+fuzz_target!(|data: &[u8]| {
+    let (recovered, _diagnostics) = Png::parse_lenient(data);
+
+    // Whatever parses strictly must come out of recovery untouched
+    if let Ok(png) = Png::try_from(data) {
+        assert_eq!(recovered.as_bytes(), png.as_bytes());
+    }
+});
@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pngme::payload::Payload;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(payload) = Payload::from_bytes(data) {
+        // A framed payload must serialize back to the bytes it came from
+        if Payload::is_framed(data) {
+            assert_eq!(payload.to_bytes().unwrap(), data);
+        }
+    }
+});
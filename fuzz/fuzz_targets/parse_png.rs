@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pngme::png::Png;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(png) = Png::try_from(data) {
+        // Anything that parses must serialize back to the bytes it came from
+        assert_eq!(png.as_bytes(), data);
+    }
+});
@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pngme::png::{Png, PngRef};
+
+fuzz_target!(|data: &[u8]| {
+    // The borrowed parser must accept and reject exactly what the owned one does
+    match (PngRef::try_from(data), Png::try_from(data)) {
+        (Ok(borrowed), Ok(owned)) => assert_eq!(borrowed.to_png().as_bytes(), owned.as_bytes()),
+        (Err(borrowed), Err(owned)) => assert_eq!(borrowed.to_string(), owned.to_string()),
+        (borrowed, owned) => panic!("PngRef gave {:?}, Png gave {:?}", borrowed.err(), owned.err()),
+    }
+});
@@ -0,0 +1,18 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pngme::chunk::Chunk;
+use pngme::png::Png;
+use pngme::reader::ChunkReader;
+
+fuzz_target!(|data: &[u8]| {
+    let streamed = ChunkReader::new(data)
+        .and_then(|reader| reader.collect::<Result<Vec<Chunk>, _>>());
+
+    // Streaming must find the same chunks, or fail the same way, as parsing it whole
+    match (streamed, Png::try_from(data)) {
+        (Ok(chunks), Ok(png)) => assert_eq!(chunks.as_slice(), png.chunks()),
+        (Err(streamed), Err(whole)) => assert_eq!(streamed.to_string(), whole.to_string()),
+        (streamed, whole) => panic!("ChunkReader gave {:?}, Png gave {:?}", streamed.err(), whole.err()),
+    }
+});
@@ -0,0 +1,867 @@
+//! Detection of data hidden in PNG files.
+//!
+//! [`Png::analyze`] looks for the usual hiding places: chunks nobody else
+//! would read, data after IEND, oversized text and chunk payloads that look
+//! random. The decoded pixels are checked for the statistical traces of LSB
+//! embedding with the chi-square attack of Westfeld and Pfitzmann and the RS
+//! analysis of Fridrich, Goljan and Du.
+//!
+//! Every finding carries a score between 0 and 1, combined into a score for
+//! the whole file.
+
+use std::fmt::Display;
+
+use crate::fragment;
+use crate::ihdr::ColorType;
+use crate::lsb;
+use crate::payload::{self, Payload};
+use crate::pixels::Pixels;
+use crate::png::Png;
+use crate::validate;
+
+/// Registered extension chunks, not part of the spec itself but known.
+const EXTENSION_CHUNKS: [&str; 12] = [
+    "oFFs", "pCAL", "sCAL", "gIFg", "gIFt", "gIFx", "sTER", "dSIG", "fRAc", "acTL", "fcTL", "fdAT",
+];
+
+/// Chunks holding compressed or otherwise dense data, high entropy is
+/// expected in them.
+const DENSE_CHUNKS: [&str; 7] = ["IDAT", "zTXt", "iTXt", "iCCP", "eXIf", "dSIG", "fdAT"];
+
+/// Magic of data sealed by the `encryption` module. It is matched here
+/// because that module is only built with the `crypto` feature.
+const ENCRYPTED_MAGIC: &[u8] = b"pmEN";
+
+/// Magic of the chunks written by the `signature` module, matched here for
+/// the same reason.
+const SIGNATURE_MAGIC: &[u8] = b"pmSG";
+
+/// Text chunks larger than this are unusual.
+const MAX_TEXT_LEN: usize = 4096;
+
+/// Chunk payloads need this many bytes for their entropy to mean anything.
+const MIN_ENTROPY_LEN: usize = 256;
+
+/// Bits per byte above which a payload looks compressed or encrypted.
+const HIGH_ENTROPY: f64 = 7.2;
+
+/// Probability above which the chi-square attack reports embedding.
+const CHI_SQUARE_THRESHOLD: f64 = 0.95;
+
+/// Estimated embedding rate above which RS analysis reports embedding.
+const RS_THRESHOLD: f64 = 0.15;
+
+/// The kind of check behind a finding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Check {
+    /// A chunk type with the private bit set.
+    PrivateChunk,
+    /// A public chunk type no specification defines.
+    UnknownChunk,
+    /// A chunk holding data in a format written by this crate.
+    KnownPayload,
+    /// Chunks or other data stored after IEND, ignored by decoders.
+    AfterIend,
+    /// A text chunk larger than comments usually are.
+    LargeText,
+    /// A chunk payload looking compressed or encrypted.
+    HighEntropy,
+    /// An LSB header written by this crate.
+    LsbHeader,
+    /// Pairs of values equalised by LSB replacement.
+    ChiSquare,
+    /// LSB flipping estimated by RS analysis.
+    RsAnalysis,
+}
+
+impl Check {
+    /// Short identifier of the check, as used in JSON output.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::PrivateChunk => "private-chunk",
+            Self::UnknownChunk => "unknown-chunk",
+            Self::KnownPayload => "known-payload",
+            Self::AfterIend => "after-iend",
+            Self::LargeText => "large-text",
+            Self::HighEntropy => "high-entropy",
+            Self::LsbHeader => "lsb-header",
+            Self::ChiSquare => "chi-square",
+            Self::RsAnalysis => "rs-analysis",
+        }
+    }
+}
+
+impl Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Something suggesting hidden data.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub check: Check,
+    /// Index of the chunk concerned, if the finding is tied to one.
+    pub chunk_index: Option<usize>,
+    /// How strongly the finding suggests hidden data, from 0 to 1.
+    pub score: f64,
+    pub message: String,
+}
+
+impl Finding {
+    fn new(check: Check, chunk_index: Option<usize>, score: f64, message: String) -> Self {
+        Self {
+            check,
+            chunk_index,
+            score,
+            message,
+        }
+    }
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{:.2}] {}", self.score, self.check)?;
+        if let Some(index) = self.chunk_index {
+            write!(f, " in chunk {}", index)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// Statistics of the low bits of the pixels, taken over the channel that
+/// looks the most altered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PixelStats {
+    /// Probability that the LSB pairs were equalised by embedding.
+    pub chi_square: f64,
+    /// Fraction of the samples, from the start of the image, over which
+    /// that probability stays above the reporting threshold.
+    pub chi_square_extent: f64,
+    /// Fraction of the samples whose LSB RS analysis estimates to carry
+    /// data.
+    pub rs_estimate: f64,
+}
+
+/// The outcome of [`Png::analyze`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub findings: Vec<Finding>,
+    /// `None` when the pixels couldn't be decoded or aren't 8-bit samples.
+    pub pixels: Option<PixelStats>,
+}
+
+impl Report {
+    /// Combined score of the findings, from 0 to 1. Findings are treated as
+    /// independent evidence, so several weak ones add up.
+    pub fn score(&self) -> f64 {
+        1.0 - self
+            .findings
+            .iter()
+            .map(|finding| 1.0 - finding.score.clamp(0.0, 1.0))
+            .product::<f64>()
+    }
+
+    /// A word for the score.
+    pub fn verdict(&self) -> &'static str {
+        match self.score() {
+            score if score < 0.3 => "clean",
+            score if score < 0.7 => "suspicious",
+            _ => "likely hiding data",
+        }
+    }
+
+    /// The report as a JSON object.
+    pub fn to_json(&self) -> String {
+        let findings: Vec<String> = self
+            .findings
+            .iter()
+            .map(|finding| {
+                format!(
+                    "{{\"check\":\"{}\",\"chunk_index\":{},\"score\":{},\"message\":{}}}",
+                    finding.check,
+                    finding
+                        .chunk_index
+                        .map_or("null".to_string(), |index| index.to_string()),
+                    json_number(finding.score),
+                    json_string(&finding.message)
+                )
+            })
+            .collect();
+        let pixels = match &self.pixels {
+            Some(stats) => format!(
+                "{{\"chi_square\":{},\"chi_square_extent\":{},\"rs_estimate\":{}}}",
+                json_number(stats.chi_square),
+                json_number(stats.chi_square_extent),
+                json_number(stats.rs_estimate)
+            ),
+            None => "null".to_string(),
+        };
+
+        format!(
+            "{{\"score\":{},\"verdict\":\"{}\",\"findings\":[{}],\"pixels\":{}}}",
+            json_number(self.score()),
+            self.verdict(),
+            findings.join(","),
+            pixels
+        )
+    }
+}
+
+fn json_number(value: f64) -> String {
+    if value.is_finite() {
+        format!("{:.4}", value)
+    } else {
+        "null".to_string()
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+impl Png {
+    /// Looks for signs of hidden data in the chunks and pixels.
+    pub fn analyze(&self) -> Report {
+        let mut findings = Vec::new();
+        let iend = self
+            .chunks()
+            .iter()
+            .position(|chunk| chunk.chunk_type().bytes() == *b"IEND");
+
+        for (index, chunk) in self.chunks().iter().enumerate() {
+            let chunk_type = chunk.chunk_type();
+            let name = chunk_type.to_string();
+            let data = chunk.data();
+            let known = validate::is_standard_chunk(&name) || EXTENSION_CHUNKS.contains(&name.as_str());
+            let kind = if chunk_type.is_critical() { "critical" } else { "ancillary" };
+
+            if !chunk_type.is_public() {
+                findings.push(Finding::new(
+                    Check::PrivateChunk,
+                    Some(index),
+                    0.5,
+                    format!("private {} chunk {} holding {} bytes", kind, name, data.len()),
+                ));
+            } else if !known {
+                findings.push(Finding::new(
+                    Check::UnknownChunk,
+                    Some(index),
+                    0.3,
+                    format!("unknown {} chunk {} holding {} bytes", kind, name, data.len()),
+                ));
+            }
+
+            let format = if Payload::is_framed(data) {
+                Some("framed payload")
+            } else if fragment::is_fragment(data) {
+                Some("payload fragment")
+            } else if data.starts_with(ENCRYPTED_MAGIC) {
+                Some("encrypted payload")
+            } else if data.starts_with(SIGNATURE_MAGIC) {
+                Some("signature")
+            } else {
+                None
+            };
+            if let Some(format) = format {
+                findings.push(Finding::new(
+                    Check::KnownPayload,
+                    Some(index),
+                    1.0,
+                    format!("{} holds a pngme {}", name, format),
+                ));
+            }
+
+            if iend.is_some_and(|iend| index > iend) {
+                findings.push(Finding::new(
+                    Check::AfterIend,
+                    Some(index),
+                    0.8,
+                    format!("{} stored after IEND, where decoders don't look", name),
+                ));
+            }
+
+            if matches!(name.as_str(), "tEXt" | "zTXt" | "iTXt") && data.len() > MAX_TEXT_LEN {
+                findings.push(Finding::new(
+                    Check::LargeText,
+                    Some(index),
+                    0.3,
+                    format!("{} text chunk of {} bytes", name, data.len()),
+                ));
+            }
+
+            if data.len() >= MIN_ENTROPY_LEN && !DENSE_CHUNKS.contains(&name.as_str()) {
+                let bits = entropy(data);
+                if bits > HIGH_ENTROPY {
+                    findings.push(Finding::new(
+                        Check::HighEntropy,
+                        Some(index),
+                        0.6,
+                        format!(
+                            "{} data has {:.2} bits of entropy per byte, like compressed or encrypted data",
+                            name, bits
+                        ),
+                    ));
+                }
+            }
+        }
+
+        let trailing = self.trailing_data();
+        if !trailing.is_empty() {
+            let kind = match self.trailing_chunks() {
+                Ok(chunks) => format!("{} chunk(s)", chunks.len()),
+                Err(_) => payload::guess_mime_type(None, trailing).to_string(),
+            };
+            findings.push(Finding::new(
+                Check::AfterIend,
+                None,
+                0.8,
+                format!("{} bytes after IEND, looking like {}", trailing.len(), kind),
+            ));
+        }
+
+        let pixels = self.pixels().ok();
+        if pixels.as_ref().is_some_and(|pixels| lsb::is_embedded(pixels, None)) {
+            findings.push(Finding::new(
+                Check::LsbHeader,
+                None,
+                1.0,
+                "the pixels start with a pngme LSB header".to_string(),
+            ));
+        }
+
+        let stats = pixels.as_ref().and_then(pixel_stats);
+        if let Some(stats) = &stats {
+            if stats.chi_square_extent > 0.0 {
+                findings.push(Finding::new(
+                    Check::ChiSquare,
+                    None,
+                    0.7,
+                    format!(
+                        "LSB pairs are equalised over the first {:.0}% of the samples (p = {:.3})",
+                        stats.chi_square_extent * 100.0,
+                        stats.chi_square
+                    ),
+                ));
+            }
+            if stats.rs_estimate > RS_THRESHOLD {
+                findings.push(Finding::new(
+                    Check::RsAnalysis,
+                    None,
+                    (stats.rs_estimate * 1.5).min(0.9),
+                    format!(
+                        "RS analysis estimates {:.0}% of the samples carry data",
+                        stats.rs_estimate * 100.0
+                    ),
+                ));
+            }
+        }
+
+        Report {
+            findings,
+            pixels: stats,
+        }
+    }
+}
+
+/// Shannon entropy of `data` in bits per byte, from 0 to 8.
+pub fn entropy(data: &[u8]) -> f64 {
+    if data.is_empty() {
+        return 0.0;
+    }
+
+    let mut counts = [0usize; 256];
+    for byte in data {
+        counts[*byte as usize] += 1;
+    }
+    let len = data.len() as f64;
+    counts
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Chi-square and RS statistics of 8-bit, non palette pixels, keeping the
+/// channel that looks the most altered by each test.
+pub fn pixel_stats(pixels: &Pixels) -> Option<PixelStats> {
+    let ihdr = pixels.ihdr();
+    if ihdr.bit_depth != 8 || ihdr.color_type == ColorType::Indexed {
+        return None;
+    }
+
+    let (width, height) = (ihdr.width as usize, ihdr.height as usize);
+    let channels = ihdr.color_type.channels();
+    let mut stats = PixelStats {
+        chi_square: 0.0,
+        chi_square_extent: 0.0,
+        rs_estimate: 0.0,
+    };
+
+    for channel in 0..channels {
+        let samples: Vec<u8> = pixels
+            .data()
+            .iter()
+            .skip(channel)
+            .step_by(channels)
+            .copied()
+            .collect();
+
+        let (chi_square, extent) = chi_square_attack(&samples);
+        if (extent, chi_square) > (stats.chi_square_extent, stats.chi_square) {
+            stats.chi_square = chi_square;
+            stats.chi_square_extent = extent;
+        }
+        stats.rs_estimate = stats.rs_estimate.max(rs_estimate(&samples, width, height));
+    }
+
+    Some(stats)
+}
+
+/// Runs the chi-square attack on growing prefixes of `samples`, as
+/// sequential embedding starts at the beginning. Returns the highest
+/// probability of embedding and the largest prefix, as a fraction, over
+/// which it is above [`CHI_SQUARE_THRESHOLD`].
+fn chi_square_attack(samples: &[u8]) -> (f64, f64) {
+    const PREFIXES: [f64; 6] = [0.05, 0.1, 0.25, 0.5, 0.75, 1.0];
+    // Fewer samples than this don't tell much
+    const MIN_SAMPLES: usize = 256;
+
+    let mut best = (0.0, 0.0);
+    for fraction in PREFIXES {
+        let len = (samples.len() as f64 * fraction) as usize;
+        if len < MIN_SAMPLES {
+            continue;
+        }
+
+        let mut counts = [0usize; 256];
+        for sample in &samples[..len] {
+            counts[*sample as usize] += 1;
+        }
+
+        let (mut chi, mut categories) = (0.0, 0usize);
+        for pair in counts.chunks(2) {
+            let expected = (pair[0] + pair[1]) as f64 / 2.0;
+            // Rare pairs only add noise
+            if expected < 2.5 {
+                continue;
+            }
+            chi += (pair[0] as f64 - expected).powi(2) / expected;
+            categories += 1;
+        }
+        if categories < 2 {
+            continue;
+        }
+
+        let probability = gamma_q((categories - 1) as f64 / 2.0, chi / 2.0);
+        best.0 = f64::max(best.0, probability);
+        if probability > CHI_SQUARE_THRESHOLD {
+            best.1 = fraction;
+        }
+    }
+
+    best
+}
+
+/// Counts of regular and singular groups as fractions of all groups, for
+/// the flipping mask and its negation.
+struct RsCounts {
+    regular: f64,
+    singular: f64,
+    negative_regular: f64,
+    negative_singular: f64,
+}
+
+/// Estimates the fraction of samples whose LSB carries data, with RS
+/// analysis over groups of 4 horizontally adjacent samples.
+fn rs_estimate(samples: &[u8], width: usize, height: usize) -> f64 {
+    let Some(original) = rs_counts(samples, width, height, false) else {
+        return 0.0;
+    };
+    let flipped = rs_counts(samples, width, height, true).unwrap();
+
+    let d0 = original.regular - original.singular;
+    let d1 = flipped.regular - flipped.singular;
+    let n0 = original.negative_regular - original.negative_singular;
+    let n1 = flipped.negative_regular - flipped.negative_singular;
+
+    let a = 2.0 * (d1 + d0);
+    let b = n0 - n1 - d1 - 3.0 * d0;
+    let c = d0 - n0;
+
+    let x = if a.abs() < 1e-12 {
+        if b.abs() < 1e-12 {
+            return 0.0;
+        }
+        -c / b
+    } else {
+        let discriminant = b * b - 4.0 * a * c;
+        if discriminant < 0.0 {
+            return 0.0;
+        }
+        let roots = [
+            (-b + discriminant.sqrt()) / (2.0 * a),
+            (-b - discriminant.sqrt()) / (2.0 * a),
+        ];
+        if roots[0].abs() <= roots[1].abs() {
+            roots[0]
+        } else {
+            roots[1]
+        }
+    };
+
+    let estimate = x / (x - 0.5);
+    if estimate.is_finite() {
+        estimate.clamp(0.0, 1.0)
+    } else {
+        0.0
+    }
+}
+
+/// Classifies the groups of the image, with every LSB flipped first when
+/// `flip_all` is set. `None` when rows are too short to hold a group.
+fn rs_counts(samples: &[u8], width: usize, height: usize, flip_all: bool) -> Option<RsCounts> {
+    const MASK: [bool; 4] = [false, true, true, false];
+    let smoothness = |group: &[i32; 4]| -> i32 { group.windows(2).map(|pair| (pair[1] - pair[0]).abs()).sum() };
+
+    let mut counts = [0usize; 4];
+    let mut groups = 0usize;
+    for row in samples.chunks_exact(width).take(height) {
+        for group in row.chunks_exact(4) {
+            let group: [i32; 4] = std::array::from_fn(|i| {
+                let value = group[i] as i32;
+                if flip_all {
+                    value ^ 1
+                } else {
+                    value
+                }
+            });
+            let before = smoothness(&group);
+
+            // F1 swaps 2k and 2k + 1, F-1 swaps 2k - 1 and 2k
+            let positive = std::array::from_fn(|i| if MASK[i] { group[i] ^ 1 } else { group[i] });
+            let negative = std::array::from_fn(|i| {
+                if MASK[i] {
+                    ((group[i] + 1) ^ 1) - 1
+                } else {
+                    group[i]
+                }
+            });
+
+            for (offset, flipped) in [(0, positive), (2, negative)] {
+                match smoothness(&flipped).cmp(&before) {
+                    std::cmp::Ordering::Greater => counts[offset] += 1,
+                    std::cmp::Ordering::Less => counts[offset + 1] += 1,
+                    std::cmp::Ordering::Equal => {}
+                }
+            }
+            groups += 1;
+        }
+    }
+
+    if groups == 0 {
+        return None;
+    }
+    let fraction = |count: usize| count as f64 / groups as f64;
+    Some(RsCounts {
+        regular: fraction(counts[0]),
+        singular: fraction(counts[1]),
+        negative_regular: fraction(counts[2]),
+        negative_singular: fraction(counts[3]),
+    })
+}
+
+/// Natural logarithm of the gamma function, Lanczos approximation.
+fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 6] = [
+        76.18009172947146,
+        -86.50532032941677,
+        24.01409824083091,
+        -1.231739572450155,
+        0.1208650973866179e-2,
+        -0.5395239384953e-5,
+    ];
+
+    let tmp = x + 5.5;
+    let tmp = tmp - (x + 0.5) * tmp.ln();
+    let series = COEFFICIENTS
+        .iter()
+        .zip(1..)
+        .fold(1.000000000190015, |sum, (c, i)| sum + c / (x + i as f64));
+    -tmp + (2.5066282746310005 * series / x).ln()
+}
+
+/// Regularized upper incomplete gamma function Q(a, x), which gives the
+/// chi-square survival function as Q(df / 2, chi / 2).
+fn gamma_q(a: f64, x: f64) -> f64 {
+    const ITERATIONS: usize = 500;
+    const EPSILON: f64 = 1e-12;
+    const TINY: f64 = 1e-300;
+
+    if x <= 0.0 {
+        return 1.0;
+    }
+    let prefactor = (-x + a * x.ln() - ln_gamma(a)).exp();
+
+    if x < a + 1.0 {
+        // Series for the lower function P
+        let (mut term, mut sum, mut n) = (1.0 / a, 1.0 / a, a);
+        for _ in 0..ITERATIONS {
+            n += 1.0;
+            term *= x / n;
+            sum += term;
+            if term.abs() < sum.abs() * EPSILON {
+                break;
+            }
+        }
+        (1.0 - sum * prefactor).clamp(0.0, 1.0)
+    } else {
+        // Continued fraction for Q, modified Lentz's method
+        let mut b = x + 1.0 - a;
+        let mut c = 1.0 / TINY;
+        let mut d = 1.0 / b;
+        let mut h = d;
+        for i in 1..ITERATIONS {
+            let an = -(i as f64) * (i as f64 - a);
+            b += 2.0;
+            d = an * d + b;
+            if d.abs() < TINY {
+                d = TINY;
+            }
+            c = b + an / c;
+            if c.abs() < TINY {
+                c = TINY;
+            }
+            d = 1.0 / d;
+            let delta = d * c;
+            h *= delta;
+            if (delta - 1.0).abs() < EPSILON {
+                break;
+            }
+        }
+        (h * prefactor).clamp(0.0, 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use rand_chacha::rand_core::{RngCore, SeedableRng};
+    use rand_chacha::ChaCha20Rng;
+
+    use super::*;
+    use crate::chunk::Chunk;
+    use crate::chunk_type::ChunkType;
+    use crate::ihdr::{Ihdr, Interlace};
+    use crate::lsb::LsbOptions;
+    use crate::pixels::EncodeOptions;
+    use crate::png::tests::PNG_FILE;
+
+    fn random_bytes(len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        ChaCha20Rng::seed_from_u64(7).fill_bytes(&mut data);
+        data
+    }
+
+    /// A smooth greyscale image with a little noise, like a photo of the
+    /// sky.
+    fn smooth_pixels() -> Pixels {
+        let ihdr = Ihdr {
+            width: 128,
+            height: 128,
+            bit_depth: 8,
+            color_type: ColorType::Grayscale,
+            interlace: Interlace::None,
+        };
+        let noise = random_bytes(128 * 128);
+        let data = (0..128 * 128)
+            .map(|i| (40 + (i % 128 + i / 128) / 3 + noise[i] as usize % 3) as u8)
+            .collect();
+        Pixels::new(ihdr, data).unwrap()
+    }
+
+    fn file_pixels() -> Pixels {
+        Png::try_from(&PNG_FILE[..]).unwrap().pixels().unwrap()
+    }
+
+    fn chunk(chunk_type: &str, data: Vec<u8>) -> Chunk {
+        Chunk::new(ChunkType::from_str(chunk_type).unwrap(), data)
+    }
+
+    fn checks(report: &Report) -> Vec<(Check, Option<usize>)> {
+        report
+            .findings
+            .iter()
+            .map(|finding| (finding.check, finding.chunk_index))
+            .collect()
+    }
+
+    #[test]
+    fn test_entropy() {
+        assert_eq!(entropy(&[]), 0.0);
+        assert_eq!(entropy(&[7; 100]), 0.0);
+        assert!((entropy(&(0..=255).collect::<Vec<u8>>()) - 8.0).abs() < 1e-9);
+        assert!(entropy(b"just some plain english text, nothing to see here") < 5.0);
+    }
+
+    #[test]
+    fn test_gamma_q() {
+        // Chi-square survival function for 2 degrees of freedom is e^(-x/2)
+        for chi in [0.5, 2.0, 10.0] {
+            assert!((gamma_q(1.0, chi / 2.0) - (-chi / 2.0f64).exp()).abs() < 1e-9);
+        }
+        // 95th percentile of chi-square with 10 degrees of freedom
+        assert!((gamma_q(5.0, 18.307 / 2.0) - 0.05).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_chunk_findings() {
+        let png = Png::try_from(&PNG_FILE[..]).unwrap();
+        let mut chunks = png.chunks().to_vec();
+        chunks.insert(2, chunk("tEXt", [b"Comment\0".as_slice(), &[b'a'; 5000]].concat()));
+        chunks.insert(3, chunk("prVt", random_bytes(1000)));
+        chunks.insert(4, chunk("uNKn", b"public but unknown".to_vec()));
+        chunks.push(chunk("afTr", b"after the end".to_vec()));
+        let report = Png::from_chunks(chunks).analyze();
+
+        assert_eq!(
+            checks(&report),
+            [
+                (Check::LargeText, Some(2)),
+                (Check::PrivateChunk, Some(3)),
+                (Check::HighEntropy, Some(3)),
+                (Check::UnknownChunk, Some(4)),
+                (Check::PrivateChunk, Some(8)),
+                (Check::PrivateChunk, Some(10)),
+                (Check::AfterIend, Some(10)),
+            ]
+        );
+        assert_eq!(report.verdict(), "likely hiding data");
+    }
+
+    #[test]
+    fn test_trailing_data() {
+        let bytes = [&PNG_FILE[..], b"PK\x03\x04 appended archive"].concat();
+        let report = Png::try_from(bytes.as_ref()).unwrap().analyze();
+
+        let finding = report
+            .findings
+            .iter()
+            .find(|finding| finding.check == Check::AfterIend)
+            .unwrap();
+        assert_eq!(finding.chunk_index, None);
+        assert!(finding.message.contains("application/zip"), "{}", finding.message);
+    }
+
+    #[test]
+    fn test_known_payloads() {
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        for data in fragment::split(b"split message", 5).unwrap() {
+            png.insert_before("IEND", chunk("ruSt", data)).unwrap();
+        }
+
+        // A short encrypted message, and a signature over the messages
+        #[cfg(feature = "crypto")]
+        {
+            use crate::encryption::{self, Identity};
+            use crate::signature::{self, SigningKey};
+
+            let sealed = encryption::encrypt_to_recipients(b"hi", &[Identity::generate().recipient()]).unwrap();
+            png.insert_before("IEND", chunk("ruSt", sealed)).unwrap();
+            signature::sign(&mut png, &ChunkType::from_str("ruSt").unwrap(), &SigningKey::generate()).unwrap();
+        }
+        let expected = if cfg!(feature = "crypto") { 5 } else { 3 };
+
+        let report = png.analyze();
+        let known: Vec<_> = report
+            .findings
+            .iter()
+            .filter(|finding| finding.check == Check::KnownPayload)
+            .map(|finding| finding.message.as_str())
+            .collect();
+        assert_eq!(known.len(), expected, "{:?}", known);
+        assert!(report.score() > 0.99);
+    }
+
+    #[test]
+    fn test_clean_pixels() {
+        let stats = pixel_stats(&file_pixels()).unwrap();
+        assert_eq!(stats.chi_square_extent, 0.0);
+        assert!(stats.rs_estimate < RS_THRESHOLD, "{:?}", stats);
+        assert!(pixel_stats(&smooth_pixels()).unwrap().rs_estimate < 0.05);
+
+        let png = file_pixels().to_png(&EncodeOptions::default()).unwrap();
+        let report = png.analyze();
+        assert!(report.findings.is_empty(), "{:?}", report.findings);
+        assert_eq!(report.verdict(), "clean");
+    }
+
+    #[test]
+    fn test_sequential_embedding_is_detected() {
+        let mut pixels = file_pixels();
+        let options = LsbOptions::default();
+        let data = random_bytes(lsb::capacity(pixels.ihdr(), &options) / 2);
+        lsb::embed(&mut pixels, &data, &options, None).unwrap();
+
+        // Only the first half of the samples carry data
+        let stats = pixel_stats(&pixels).unwrap();
+        assert!(stats.chi_square > CHI_SQUARE_THRESHOLD, "{:?}", stats);
+        assert_eq!(stats.chi_square_extent, 0.5);
+        assert!(stats.rs_estimate > RS_THRESHOLD, "{:?}", stats);
+    }
+
+    #[test]
+    fn test_keyed_embedding_is_detected() {
+        let mut pixels = smooth_pixels();
+        let options = LsbOptions::default();
+        let data = random_bytes(lsb::capacity(pixels.ihdr(), &options));
+        lsb::embed(&mut pixels, &data, &options, Some(b"key")).unwrap();
+
+        let report = pixels.to_png(&EncodeOptions::default()).unwrap().analyze();
+        let found = checks(&report);
+        assert!(found.contains(&(Check::RsAnalysis, None)), "{:?}", report);
+        assert!(!found.contains(&(Check::LsbHeader, None)));
+        assert!(report.pixels.unwrap().rs_estimate > 0.7, "{:?}", report);
+    }
+
+    #[test]
+    fn test_lsb_header_is_recognised() {
+        let mut png = file_pixels().to_png(&EncodeOptions::default()).unwrap();
+        lsb::embed_in_png(&mut png, b"short", &LsbOptions::default(), None).unwrap();
+        assert!(checks(&png.analyze()).contains(&(Check::LsbHeader, None)));
+    }
+
+    #[test]
+    fn test_json() {
+        let report = Report {
+            findings: vec![Finding::new(
+                Check::PrivateChunk,
+                Some(3),
+                0.5,
+                "a \"quoted\" name\n".to_string(),
+            )],
+            pixels: None,
+        };
+        assert_eq!(
+            report.to_json(),
+            "{\"score\":0.5000,\"verdict\":\"suspicious\",\"findings\":[{\"check\":\"private-chunk\",\
+             \"chunk_index\":3,\"score\":0.5000,\"message\":\"a \\\"quoted\\\" name\\n\"}],\"pixels\":null}"
+        );
+    }
+}
@@ -1,4 +1,10 @@
-use clap::{Parser, Subcommand};
+use std::str::FromStr;
+
+use clap::{ArgEnum, Parser, Subcommand};
+
+use pngme::chunk_type::ChunkType;
+use pngme::encryption::Recipient;
+use pngme::signature::VerifyingKey;
 
 /// Pngme CLI
 #[derive(Debug, Parser)]
@@ -17,26 +23,493 @@ pub enum CliCommand {
     Encode {
         #[clap(required = true)]
         file_path: String,
-        #[clap(required = true)]
-        message: String,
+        #[clap(required_unless_present_any = &["file", "stdin"], conflicts_with_all = &["file", "stdin"])]
+        message: Option<String>,
         #[clap(required = false)]
         output_file: Option<String>,
+        /// Hide the contents of this file instead of a message
+        #[clap(long, conflicts_with = "stdin")]
+        file: Option<String>,
+        /// Hide data read from standard input instead of a message
+        #[clap(long)]
+        stdin: bool,
+        /// File to write the image to, same as output_file
+        #[clap(short, long, conflicts_with = "output-file")]
+        output: Option<String>,
+        /// Chunk type used to store the message
+        #[clap(long, default_value = DEFAULT_CHUNK_TYPE, parse(try_from_str = parse_chunk_type))]
+        chunk_type: ChunkType,
+        /// How to hide the message
+        #[clap(long, arg_enum, default_value = "chunk")]
+        method: Method,
+        /// Low bits of each channel used by the lsb method
+        #[clap(long, default_value = "1")]
+        bits: u8,
+        /// Channels used by the lsb method, bit i selecting channel i (e.g. 0b111 for RGB)
+        #[clap(long, default_value = "0xff", parse(try_from_str = parse_channel_mask))]
+        channel_mask: u8,
+        /// Spread the bits of the lsb method in an order only this key reveals
+        #[clap(long)]
+        lsb_key: Option<String>,
+        /// Where to put the chunk(s) holding the message
+        #[clap(long, arg_enum, default_value = "before-iend")]
+        position: Position,
+        /// Split the message into chunks carrying at most this many bytes
+        #[clap(long)]
+        fragment_size: Option<usize>,
+        /// Encrypt the message with a passphrase
+        #[clap(long)]
+        encrypt: bool,
+        /// Passphrase to encrypt with, prompted for when missing
+        #[clap(long, requires = "encrypt")]
+        passphrase: Option<String>,
+        /// Encrypt the message for this public key, can be repeated
+        #[clap(long = "recipient", conflicts_with = "encrypt", parse(try_from_str))]
+        recipients: Vec<Recipient>,
+        /// Edit file_path in place, only rewriting what follows the image data
+        #[clap(long, conflicts_with_all = &["output", "output-file"])]
+        in_place: bool,
+        /// Keep the original image as <file>.orig
+        #[clap(long, conflicts_with = "in-place")]
+        backup: bool,
     },
     /// Get message of chunk_type from png at file_path
     #[clap(arg_required_else_help = true)]
     Decode {
         #[clap(required = true)]
         file_path: String,
+        /// Chunk type the message is stored in
+        #[clap(long, default_value = DEFAULT_CHUNK_TYPE, parse(try_from_str = parse_any_chunk_type))]
+        chunk_type: ChunkType,
+        /// Passphrase of an encrypted message, prompted for when missing
+        #[clap(long)]
+        passphrase: Option<String>,
+        /// Secret key file for messages encrypted to recipients
+        #[clap(long)]
+        identity: Option<String>,
+        /// Key the message was spread with by the lsb method
+        #[clap(long)]
+        lsb_key: Option<String>,
+        /// File or directory to write the hidden data to, printed when missing
+        #[clap(short, long)]
+        output: Option<String>,
+        /// Read past damaged chunks instead of giving up, reporting the damage
+        #[clap(long)]
+        lenient: bool,
     },
     /// Remove message of chunk_type from png at file_path
     #[clap(arg_required_else_help = true)]
     Remove {
         #[clap(required = true)]
         file_path: String,
+        /// Chunk type of the message to remove
+        #[clap(long, default_value = DEFAULT_CHUNK_TYPE, parse(try_from_str = parse_chunk_type))]
+        chunk_type: ChunkType,
+        /// Edit file_path in place, only rewriting what follows the message
+        #[clap(long)]
+        in_place: bool,
+        /// Keep the original image as <file>.orig
+        #[clap(long, conflicts_with = "in-place")]
+        backup: bool,
     },
+    /// Print the chunks of the png at file_path
     #[clap(arg_required_else_help = true)]
     Print {
         #[clap(required = true)]
         file_path: String,
+        /// Only print chunks of this type
+        #[clap(long, parse(try_from_str = parse_any_chunk_type))]
+        chunk_type: Option<ChunkType>,
+    },
+    /// Show the image properties and chunk summary of the png at file_path
+    #[clap(arg_required_else_help = true)]
+    Info {
+        #[clap(required = true)]
+        file_path: String,
+    },
+    /// Show how much data the png at file_path can hide with each method
+    #[clap(arg_required_else_help = true)]
+    Capacity {
+        #[clap(required = true)]
+        file_path: String,
+        /// Account for hiding this file, and tell whether it fits
+        #[clap(long)]
+        file: Option<String>,
+        /// Account for encrypting with a passphrase
+        #[clap(long)]
+        encrypt: bool,
+        /// Account for encrypting to this many recipients
+        #[clap(long, conflicts_with = "encrypt")]
+        recipients: Option<usize>,
     },
-}
\ No newline at end of file
+    /// List, extract, strip or write the data after IEND of the png at file_path
+    #[clap(arg_required_else_help = true)]
+    Trailing {
+        #[clap(required = true)]
+        file_path: String,
+        /// Write the trailing data to this file
+        #[clap(long, conflicts_with_all = &["strip", "write"])]
+        extract: Option<String>,
+        /// Remove the trailing data
+        #[clap(long, conflicts_with = "write")]
+        strip: bool,
+        /// Replace the trailing data with the contents of this file
+        #[clap(long)]
+        write: Option<String>,
+        /// File to write the image to when stripping or writing, file_path when missing
+        #[clap(short, long)]
+        output: Option<String>,
+        /// Keep the original image as <file>.orig
+        #[clap(long)]
+        backup: bool,
+    },
+    /// Look for signs of data hidden in the png at file_path
+    #[clap(arg_required_else_help = true)]
+    Analyze {
+        #[clap(required = true)]
+        file_path: String,
+        /// Print the report as JSON
+        #[clap(long)]
+        json: bool,
+    },
+    /// Check the png at file_path against the chunk rules of the PNG spec
+    #[clap(arg_required_else_help = true)]
+    Validate {
+        #[clap(required = true)]
+        file_path: String,
+    },
+    /// Generate a key pair for encrypting messages to recipients
+    Keygen {
+        /// File to write the secret key to, printed when missing
+        #[clap(long)]
+        output: Option<String>,
+        /// Generate an Ed25519 key pair for signing instead
+        #[clap(long)]
+        signing: bool,
+    },
+    /// Sign the message of chunk_type together with the image carrying it
+    #[clap(arg_required_else_help = true)]
+    Sign {
+        #[clap(required = true)]
+        file_path: String,
+        #[clap(required = false)]
+        output_file: Option<String>,
+        /// Signing key file, as written by `keygen --signing`
+        #[clap(long, required = true)]
+        key: String,
+        /// Chunk type of the message to sign
+        #[clap(long, default_value = DEFAULT_CHUNK_TYPE, parse(try_from_str = parse_chunk_type))]
+        chunk_type: ChunkType,
+        /// Keep the original image as <file>.orig
+        #[clap(long)]
+        backup: bool,
+    },
+    /// Check the signature of the message of chunk_type
+    #[clap(arg_required_else_help = true)]
+    Verify {
+        #[clap(required = true)]
+        file_path: String,
+        /// Chunk type of the signed message
+        #[clap(long, default_value = DEFAULT_CHUNK_TYPE, parse(try_from_str = parse_any_chunk_type))]
+        chunk_type: ChunkType,
+        /// Require the message to be signed by this public key
+        #[clap(long, parse(try_from_str))]
+        signer: Option<VerifyingKey>,
+    },
+}
+
+/// Where encode hides the message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum Method {
+    /// In an ancillary chunk of its own
+    Chunk,
+    /// In the least significant bits of the pixels
+    Lsb,
+}
+
+/// Where encode puts the chunks it adds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum Position {
+    /// Right before IEND, after the image data
+    BeforeIend,
+    /// Right before the first IDAT
+    BeforeIdat,
+    /// After every other chunk, including IEND
+    End,
+}
+
+const DEFAULT_CHUNK_TYPE: &str = "ruSt";
+
+/// Parses a chunk type given on the command line, rejecting types that are
+/// not valid or that would clash with the critical chunks of the image.
+fn parse_chunk_type(s: &str) -> Result<ChunkType, String> {
+    let chunk_type = ChunkType::from_str(s).map_err(|why| why.to_string())?;
+
+    if !chunk_type.is_valid() {
+        return Err(format!(
+            "'{}' is not a valid chunk type (the third letter must be uppercase)",
+            s
+        ));
+    }
+
+    if chunk_type.is_critical() {
+        return Err(format!(
+            "'{}' is a critical chunk type (the first letter must be lowercase)",
+            s
+        ));
+    }
+
+    Ok(chunk_type)
+}
+
+/// Parses a chunk type to look up, which can be any chunk type: reading a
+/// chunk can't clash with the image.
+fn parse_any_chunk_type(s: &str) -> Result<ChunkType, String> {
+    ChunkType::from_str(s).map_err(|why| why.to_string())
+}
+
+/// Parses a channel mask written in decimal, or in binary or hexadecimal
+/// with a `0b` or `0x` prefix.
+fn parse_channel_mask(s: &str) -> Result<u8, String> {
+    let parsed = if let Some(binary) = s.strip_prefix("0b") {
+        u8::from_str_radix(binary, 2)
+    } else if let Some(hex) = s.strip_prefix("0x") {
+        u8::from_str_radix(hex, 16)
+    } else {
+        s.parse()
+    };
+
+    parsed.map_err(|why| format!("'{}' is not a channel mask: {}", s, why))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cli_definition() {
+        use clap::CommandFactory;
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_parse_chunk_type() {
+        let chunk_type = parse_chunk_type("ruSt").unwrap();
+        assert_eq!(chunk_type.to_string(), "ruSt");
+    }
+
+    #[test]
+    fn test_parse_chunk_type_rejects_reserved_bit() {
+        assert!(parse_chunk_type("rust").is_err());
+    }
+
+    #[test]
+    fn test_parse_chunk_type_rejects_critical() {
+        assert!(parse_chunk_type("IDAT").is_err());
+        assert!(parse_chunk_type("RuSt").is_err());
+    }
+
+    #[test]
+    fn test_parse_chunk_type_rejects_malformed() {
+        assert!(parse_chunk_type("ru5t").is_err());
+        assert!(parse_chunk_type("ruStx").is_err());
+    }
+
+    #[test]
+    fn test_cli_chunk_type_option() {
+        let cli = Cli::try_parse_from(["pngme", "decode", "a.png", "--chunk-type", "teSt"]).unwrap();
+        match cli.command {
+            CliCommand::Decode { chunk_type, .. } => assert_eq!(chunk_type.to_string(), "teSt"),
+            _ => panic!("expected decode"),
+        }
+
+        let cli = Cli::try_parse_from(["pngme", "remove", "a.png"]).unwrap();
+        match cli.command {
+            CliCommand::Remove { chunk_type, .. } => assert_eq!(chunk_type.to_string(), "ruSt"),
+            _ => panic!("expected remove"),
+        }
+
+        assert!(Cli::try_parse_from(["pngme", "encode", "a.png", "hi", "--chunk-type", "IDAT"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_lookups_take_any_chunk_type() {
+        for command in ["print", "decode", "verify"] {
+            for chunk_type in ["IEND", "IHDR", "RuSt"] {
+                assert!(Cli::try_parse_from(["pngme", command, "a.png", "--chunk-type", chunk_type]).is_ok());
+            }
+            assert!(Cli::try_parse_from(["pngme", command, "a.png", "--chunk-type", "ru5t"]).is_err());
+        }
+        // Commands writing chunks still refuse critical types
+        assert!(Cli::try_parse_from(["pngme", "remove", "a.png", "--chunk-type", "IEND"]).is_err());
+        assert!(Cli::try_parse_from(["pngme", "sign", "a.png", "--key", "k", "--chunk-type", "IEND"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_recipients() {
+        let recipient = pngme::encryption::Identity::generate().recipient().to_string();
+        let cli = Cli::try_parse_from([
+            "pngme", "encode", "a.png", "hi", "--recipient", &recipient, "--recipient", &recipient,
+        ])
+        .unwrap();
+        match cli.command {
+            CliCommand::Encode { recipients, .. } => assert_eq!(recipients.len(), 2),
+            _ => panic!("expected encode"),
+        }
+
+        assert!(Cli::try_parse_from(["pngme", "encode", "a.png", "hi", "--recipient", "pngme-pk-00"]).is_err());
+        assert!(Cli::try_parse_from(["pngme", "encode", "a.png", "hi", "--encrypt", "--recipient", &recipient]).is_err());
+    }
+
+    #[test]
+    fn test_cli_sign_requires_key() {
+        assert!(Cli::try_parse_from(["pngme", "sign", "a.png"]).is_err());
+        assert!(Cli::try_parse_from(["pngme", "sign", "a.png", "--key", "s.key"]).is_ok());
+        assert!(Cli::try_parse_from(["pngme", "verify", "a.png", "--signer", "pngme-pk-00"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_encode_input() {
+        let cli = Cli::try_parse_from(["pngme", "encode", "a.png", "--file", "s.zip", "-o", "b.png"]).unwrap();
+        match cli.command {
+            CliCommand::Encode {
+                message, file, output, ..
+            } => {
+                assert_eq!(message, None);
+                assert_eq!(file.as_deref(), Some("s.zip"));
+                assert_eq!(output.as_deref(), Some("b.png"));
+            }
+            _ => panic!("expected encode"),
+        }
+
+        assert!(Cli::try_parse_from(["pngme", "encode", "a.png", "--stdin"]).is_ok());
+        assert!(Cli::try_parse_from(["pngme", "encode", "a.png"]).is_err());
+        assert!(Cli::try_parse_from(["pngme", "encode", "a.png", "hi", "--stdin"]).is_err());
+        assert!(Cli::try_parse_from(["pngme", "encode", "a.png", "--file", "s.zip", "--stdin"]).is_err());
+        assert!(Cli::try_parse_from(["pngme", "encode", "a.png", "hi", "b.png", "-o", "c.png"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_position() {
+        let cli = Cli::try_parse_from(["pngme", "encode", "a.png", "hi"]).unwrap();
+        match cli.command {
+            CliCommand::Encode { position, .. } => assert_eq!(position, Position::BeforeIend),
+            _ => panic!("expected encode"),
+        }
+
+        let cli = Cli::try_parse_from(["pngme", "encode", "a.png", "hi", "--position", "before-idat"]).unwrap();
+        match cli.command {
+            CliCommand::Encode { position, .. } => assert_eq!(position, Position::BeforeIdat),
+            _ => panic!("expected encode"),
+        }
+
+        assert!(Cli::try_parse_from(["pngme", "encode", "a.png", "hi", "--position", "start"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_channel_mask() {
+        assert_eq!(parse_channel_mask("7"), Ok(7));
+        assert_eq!(parse_channel_mask("0b1011"), Ok(0b1011));
+        assert_eq!(parse_channel_mask("0xff"), Ok(0xff));
+        assert!(parse_channel_mask("0b2").is_err());
+        assert!(parse_channel_mask("256").is_err());
+    }
+
+    #[test]
+    fn test_cli_lsb_method() {
+        let cli = Cli::try_parse_from([
+            "pngme", "encode", "a.png", "hi", "--method", "lsb", "--bits", "2", "--channel-mask", "0b111",
+            "--lsb-key", "secret",
+        ])
+        .unwrap();
+        match cli.command {
+            CliCommand::Encode {
+                method,
+                bits,
+                channel_mask,
+                lsb_key,
+                ..
+            } => {
+                assert_eq!(method, Method::Lsb);
+                assert_eq!(bits, 2);
+                assert_eq!(channel_mask, 0b111);
+                assert_eq!(lsb_key.as_deref(), Some("secret"));
+            }
+            _ => panic!("expected encode"),
+        }
+    }
+
+    #[test]
+    fn test_cli_passphrase_requires_encrypt() {
+        assert!(Cli::try_parse_from(["pngme", "encode", "a.png", "hi", "--passphrase", "pw"]).is_err());
+
+        let cli = Cli::try_parse_from(["pngme", "encode", "a.png", "hi", "--encrypt", "--passphrase", "pw"]).unwrap();
+        match cli.command {
+            CliCommand::Encode { encrypt, passphrase, .. } => {
+                assert!(encrypt);
+                assert_eq!(passphrase.as_deref(), Some("pw"));
+            }
+            _ => panic!("expected encode"),
+        }
+    }
+
+    #[test]
+    fn test_cli_capacity() {
+        let cli = Cli::try_parse_from(["pngme", "capacity", "a.png", "--recipients", "2"]).unwrap();
+        match cli.command {
+            CliCommand::Capacity {
+                encrypt, recipients, ..
+            } => {
+                assert!(!encrypt);
+                assert_eq!(recipients, Some(2));
+            }
+            _ => panic!("expected capacity"),
+        }
+
+        assert!(Cli::try_parse_from(["pngme", "capacity", "a.png", "--encrypt", "--recipients", "1"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_analyze_json() {
+        let cli = Cli::try_parse_from(["pngme", "analyze", "a.png", "--json"]).unwrap();
+        assert!(matches!(cli.command, CliCommand::Analyze { json: true, .. }));
+    }
+
+    #[test]
+    fn test_cli_trailing_actions_conflict() {
+        assert!(Cli::try_parse_from(["pngme", "trailing", "a.png", "--strip"]).is_ok());
+        assert!(Cli::try_parse_from(["pngme", "trailing", "a.png", "--strip", "--write", "b.zip"]).is_err());
+        assert!(Cli::try_parse_from(["pngme", "trailing", "a.png", "--extract", "b.zip", "--strip"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_decode_lenient() {
+        let cli = Cli::try_parse_from(["pngme", "decode", "a.png", "--lenient"]).unwrap();
+        assert!(matches!(cli.command, CliCommand::Decode { lenient: true, .. }));
+        let cli = Cli::try_parse_from(["pngme", "decode", "a.png"]).unwrap();
+        assert!(matches!(cli.command, CliCommand::Decode { lenient: false, .. }));
+    }
+
+    #[test]
+    fn test_cli_in_place() {
+        let cli = Cli::try_parse_from(["pngme", "encode", "a.png", "hi", "--in-place"]).unwrap();
+        assert!(matches!(cli.command, CliCommand::Encode { in_place: true, .. }));
+        let cli = Cli::try_parse_from(["pngme", "remove", "a.png", "--in-place"]).unwrap();
+        assert!(matches!(cli.command, CliCommand::Remove { in_place: true, .. }));
+
+        assert!(Cli::try_parse_from(["pngme", "encode", "a.png", "hi", "b.png", "--in-place"]).is_err());
+        assert!(Cli::try_parse_from(["pngme", "encode", "a.png", "hi", "-o", "b.png", "--in-place"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_backup() {
+        let cli = Cli::try_parse_from(["pngme", "encode", "a.png", "hi", "--backup"]).unwrap();
+        assert!(matches!(cli.command, CliCommand::Encode { backup: true, .. }));
+        assert!(Cli::try_parse_from(["pngme", "sign", "a.png", "--key", "s.key", "--backup"]).is_ok());
+        assert!(Cli::try_parse_from(["pngme", "trailing", "a.png", "--strip", "--backup"]).is_ok());
+
+        // There is no copy of the whole file when editing in place
+        assert!(Cli::try_parse_from(["pngme", "encode", "a.png", "hi", "--in-place", "--backup"]).is_err());
+        assert!(Cli::try_parse_from(["pngme", "remove", "a.png", "--in-place", "--backup"]).is_err());
+    }
+}
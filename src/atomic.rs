@@ -0,0 +1,244 @@
+//! Replacing files without ever leaving them half written.
+
+use std::ffi::OsString;
+use std::fs::{self, File, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use pngme::in_place::sync_parent;
+
+/// How many names are tried for the temporary file before giving up.
+const TEMP_ATTEMPTS: u32 = 100;
+
+/// Writes the file at `path` through `write`, without touching the file it
+/// replaces until the new one is fully on disk.
+///
+/// `write` fills a temporary file in the same directory, which is synced
+/// and renamed over `path`. The permissions and modification time of the
+/// file replaced are kept. With `backup`, the file replaced is kept as
+/// `path.orig`. If anything fails, `path` is left as it was.
+pub fn write_file<E>(path: &Path, backup: bool, write: impl FnOnce(&mut File) -> Result<(), E>) -> Result<(), E>
+where
+    E: From<io::Error>,
+{
+    // Renaming over a symlink would replace the link, not what it points to
+    let path = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => fs::canonicalize(path)?,
+        _ => path.to_path_buf(),
+    };
+    let original = match fs::metadata(&path) {
+        Ok(metadata) => Some(metadata),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    let (temp_path, mut temp) = create_temp(&path, original.as_ref())?;
+    let replaced = write(&mut temp).and_then(|()| {
+        sync(&temp, original.as_ref())?;
+        drop(temp);
+        if backup && original.is_some() {
+            back_up(&path)?;
+        }
+        fs::rename(&temp_path, &path)?;
+        sync_parent(&path)?;
+        Ok(())
+    });
+
+    if replaced.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    replaced
+}
+
+/// Where [`write_file`] keeps the file it replaces.
+pub fn backup_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".orig");
+    PathBuf::from(name)
+}
+
+/// Creates a new, hidden file next to `path`, readable by no more users
+/// than the `original` file it replaces.
+fn create_temp(path: &Path, original: Option<&Metadata>) -> io::Result<(PathBuf, File)> {
+    let mut options = File::options();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(original.map_or(0o666, |original| original.permissions().mode() & 0o777));
+    }
+    #[cfg(not(unix))]
+    let _ = original;
+
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    for attempt in 0..TEMP_ATTEMPTS {
+        let temp_path = path.with_file_name(format!(".{}.{}-{}.tmp", name, std::process::id(), attempt));
+        match options.open(&temp_path) {
+            Ok(file) => return Ok((temp_path, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("couldn't create a temporary file next to {}", path.display()),
+    ))
+}
+
+/// Gives the new file the permissions and modification time of the one it
+/// replaces, then flushes it to disk.
+fn sync(file: &File, original: Option<&Metadata>) -> io::Result<()> {
+    if let Some(original) = original {
+        file.set_permissions(original.permissions())?;
+        file.set_modified(original.modified()?)?;
+    }
+    file.sync_all()
+}
+
+/// Keeps the file at `path` as its backup, replacing any older backup.
+fn back_up(path: &Path) -> io::Result<()> {
+    let backup = backup_path(path);
+    match fs::remove_file(&backup) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+
+    // A hard link keeps the original as it is, metadata included, without
+    // copying it. Not every file system has them.
+    if fs::hard_link(path, &backup).is_err() {
+        fs::copy(path, &backup)?;
+        let modified = fs::metadata(path)?.modified()?;
+        File::options().write(true).open(&backup)?.set_modified(modified)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+
+    /// A directory of its own in the temporary directory, removed when
+    /// dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("pngme-{}-{}", std::process::id(), name));
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        fn entries(&self) -> Vec<String> {
+            let mut entries: Vec<String> = fs::read_dir(&self.0)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect();
+            entries.sort();
+            entries
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn write_bytes(path: &Path, backup: bool, bytes: &[u8]) -> io::Result<()> {
+        write_file(path, backup, |file| file.write_all(bytes))
+    }
+
+    #[test]
+    fn test_creates_and_replaces() {
+        let dir = TempDir::new("atomic-replace");
+        let path = dir.0.join("image.png");
+
+        write_bytes(&path, false, b"first").unwrap();
+        write_bytes(&path, false, b"second").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert_eq!(dir.entries(), ["image.png"]);
+    }
+
+    #[test]
+    fn test_failure_keeps_original() {
+        let dir = TempDir::new("atomic-failure");
+        let path = dir.0.join("image.png");
+        fs::write(&path, b"original").unwrap();
+
+        let result = write_file(&path, true, |file| {
+            file.write_all(b"half")?;
+            Err(io::Error::new(io::ErrorKind::StorageFull, "disk full"))
+        });
+        assert!(result.is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"original");
+        assert_eq!(dir.entries(), ["image.png"]);
+    }
+
+    #[test]
+    fn test_keeps_modification_time() {
+        let dir = TempDir::new("atomic-mtime");
+        let path = dir.0.join("image.png");
+        fs::write(&path, b"original").unwrap();
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        File::options().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+
+        write_bytes(&path, false, b"new").unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), modified);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("atomic-permissions");
+        let path = dir.0.join("image.png");
+        fs::write(&path, b"original").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+
+        write_bytes(&path, false, b"new").unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o640);
+
+        // Not even the half written file is readable by others
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        write_file(&path, false, |file| {
+            assert_eq!(file.metadata()?.permissions().mode() & 0o777, 0o600);
+            file.write_all(b"secret")
+        })
+        .unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_backup() {
+        let dir = TempDir::new("atomic-backup");
+        let path = dir.0.join("image.png");
+
+        // Nothing to back up yet
+        write_bytes(&path, true, b"first").unwrap();
+        assert_eq!(dir.entries(), ["image.png"]);
+
+        write_bytes(&path, true, b"second").unwrap();
+        write_bytes(&path, true, b"third").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"third");
+        assert_eq!(fs::read(backup_path(&path)).unwrap(), b"second");
+        assert_eq!(dir.entries(), ["image.png", "image.png.orig"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_replaces_symlink_target() {
+        let dir = TempDir::new("atomic-symlink");
+        let target = dir.0.join("image.png");
+        let link = dir.0.join("link.png");
+        fs::write(&target, b"original").unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        write_bytes(&link, false, b"new").unwrap();
+        assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read(&target).unwrap(), b"new");
+    }
+}
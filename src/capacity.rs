@@ -0,0 +1,172 @@
+//! How much data an image can carry with each way of hiding it.
+
+use crate::chunk::Chunk;
+use crate::fragment::{FRAGMENT_OVERHEAD, MAX_FRAGMENT_SIZE};
+use crate::lsb::{self, LsbOptions};
+use crate::png::Png;
+use crate::{Error, Location, Result};
+
+/// Largest file fragmented data is spread over, 4 GiB: past 32 bit
+/// offsets, many PNG readers give up.
+pub const MAX_FILE_LEN: u64 = 1 << 32;
+
+/// A way of hiding data in an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    /// In a single ancillary chunk.
+    Chunk,
+    /// Split across ancillary chunks carrying at most this many bytes each.
+    Fragments(usize),
+    /// In the low bits of the pixels.
+    Lsb(LsbOptions),
+}
+
+/// Bytes added to the data before it is hidden.
+///
+/// Payloads aren't compressed, so framing and encryption are all there is.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Overhead {
+    /// Added by [`Payload::to_bytes`](crate::payload::Payload::to_bytes).
+    pub framing: usize,
+    /// Added by encryption, see `encryption::PASSPHRASE_OVERHEAD` and
+    /// `encryption::recipients_overhead`.
+    pub encryption: usize,
+}
+
+impl Overhead {
+    /// Number of bytes added in all.
+    pub fn total(&self) -> usize {
+        self.framing + self.encryption
+    }
+}
+
+impl Png {
+    /// Largest number of bytes `method` can hide in this image. Headers the
+    /// method writes itself, such as fragment and LSB headers, are already
+    /// taken out.
+    ///
+    /// Fragments can be added until the file reaches [`MAX_FILE_LEN`].
+    ///
+    /// The LSB capacity is 0 when the image can't hold data in its pixels,
+    /// e.g. palette images.
+    pub fn capacity(&self, method: &Method) -> Result<usize> {
+        match method {
+            Method::Chunk => Ok(Chunk::MAX_LENGTH as usize),
+            Method::Fragments(size) => {
+                if *size == 0 || *size > MAX_FRAGMENT_SIZE {
+                    return Err(Error::InvalidPayload {
+                        location: Location::default(),
+                        reason: format!(
+                            "fragment size must be between 1 and {} bytes",
+                            MAX_FRAGMENT_SIZE
+                        ),
+                    });
+                }
+                // Every fragment takes a chunk and a fragment header
+                let room = MAX_FILE_LEN.saturating_sub(self.encoded_len());
+                let headers = (Chunk::METADATA_BYTES_LEN + FRAGMENT_OVERHEAD) as u64;
+                let per_fragment = headers + *size as u64;
+                let fragments = room / per_fragment;
+                let last = (room % per_fragment).saturating_sub(headers);
+                let capacity = fragments * *size as u64 + last;
+                Ok(usize::try_from(capacity).unwrap_or(usize::MAX))
+            }
+            Method::Lsb(options) => Ok(lsb::capacity(&self.ihdr()?, options)),
+        }
+    }
+
+    /// Largest payload `method` can hide in this image once `overhead` is
+    /// added to it.
+    pub fn payload_capacity(&self, method: &Method, overhead: &Overhead) -> Result<usize> {
+        Ok(self.capacity(method)?.saturating_sub(overhead.total()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ihdr::{ColorType, Ihdr, Interlace};
+    use crate::png::tests::PNG_FILE;
+
+    fn testing_png() -> Png {
+        Png::try_from(&PNG_FILE[..]).unwrap()
+    }
+
+    #[test]
+    fn test_chunk_capacity() {
+        let png = testing_png();
+        assert_eq!(png.capacity(&Method::Chunk).unwrap(), (1 << 31) - 1);
+
+        // 4067199 fragments of 1056 bytes with their headers, and 349 bytes
+        // left for a last fragment of 293 bytes
+        assert_eq!(MAX_FILE_LEN - PNG_FILE.len() as u64, 4067199 * 1056 + 349);
+        assert_eq!(
+            png.capacity(&Method::Fragments(1000)).unwrap(),
+            4067199 * 1000 + 293
+        );
+        assert!(png.capacity(&Method::Fragments(0)).is_err());
+        assert!(png.capacity(&Method::Fragments(MAX_FRAGMENT_SIZE + 1)).is_err());
+    }
+
+    #[test]
+    fn test_lsb_capacity() {
+        let png = testing_png();
+        // 50x50 RGBA, 96 pixels taken by the header
+        let pixels = 50 * 50 - lsb::HEADER_PIXELS;
+        for bits_per_channel in 1..=4 {
+            let options = LsbOptions {
+                bits_per_channel,
+                channel_mask: 0b0111,
+            };
+            assert_eq!(
+                png.capacity(&Method::Lsb(options)).unwrap(),
+                pixels * 3 * bits_per_channel as usize / 8
+            );
+        }
+    }
+
+    #[test]
+    fn test_lsb_capacity_is_filled_exactly() {
+        let mut png = testing_png();
+        let method = Method::Lsb(LsbOptions::default());
+        let capacity = png.capacity(&method).unwrap();
+
+        let data = vec![0x5a; capacity];
+        lsb::embed_in_png(&mut png, &data, &LsbOptions::default(), None).unwrap();
+        assert!(lsb::embed_in_png(&mut png, &[0; 1], &LsbOptions::default(), None).is_ok());
+        assert!(lsb::embed_in_png(&mut png, &vec![0; capacity + 1], &LsbOptions::default(), None).is_err());
+    }
+
+    #[test]
+    fn test_palette_image_has_no_lsb_capacity() {
+        let ihdr = Ihdr {
+            width: 100,
+            height: 100,
+            bit_depth: 8,
+            color_type: ColorType::Indexed,
+            interlace: Interlace::None,
+        };
+        let png = Png::from_chunks(vec![ihdr.to_chunk()]);
+        assert_eq!(png.capacity(&Method::Lsb(LsbOptions::default())).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_payload_capacity() {
+        let png = testing_png();
+        let method = Method::Lsb(LsbOptions::default());
+        let overhead = Overhead {
+            framing: 20,
+            encryption: 70,
+        };
+        assert_eq!(
+            png.payload_capacity(&method, &overhead).unwrap(),
+            png.capacity(&method).unwrap() - 90
+        );
+
+        let overhead = Overhead {
+            framing: 0,
+            encryption: 1 << 20,
+        };
+        assert_eq!(png.payload_capacity(&method, &overhead).unwrap(), 0);
+    }
+}
@@ -1,8 +1,11 @@
+//! A single length-prefixed, CRC-protected PNG chunk.
+
 use std::{fmt::Display};
-use crc::{Crc, CRC_32_ISO_HDLC};
+use crc::{Crc, Digest, CRC_32_ISO_HDLC};
 
-use crate::{chunk_type::{ChunkType}, Error, Result};
+use crate::{chunk_type::{ChunkType}, Error, Location, Result};
 
+/// A chunk as stored in a PNG file: length, type, data and CRC.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Chunk {
     length: u32,
@@ -12,38 +15,79 @@ pub struct Chunk {
 }
 
 impl Chunk {
+    /// The largest chunk length allowed by the PNG spec, 2^31 - 1.
+    pub const MAX_LENGTH: u32 = (1 << 31) - 1;
+
     const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
     const LENGTH_BYTES_LEN: usize = 4;
     const CHUNK_TYPE_BYTES_LEN: usize = 4;
     const CRC_LEN: usize = 4;
-    const METADATA_BYTES_LEN: usize = Self::LENGTH_BYTES_LEN + Self::CHUNK_TYPE_BYTES_LEN + Self::CRC_LEN;
-
+    pub(crate) const METADATA_BYTES_LEN: usize = Self::LENGTH_BYTES_LEN + Self::CHUNK_TYPE_BYTES_LEN + Self::CRC_LEN;
+    pub(crate) const DATA_OFFSET: usize = Self::LENGTH_BYTES_LEN + Self::CHUNK_TYPE_BYTES_LEN;
+
+    /// Creates a chunk holding `data`, computing its length and CRC.
+    ///
+    /// # Panics
+    ///
+    /// If `data` is longer than [`Chunk::MAX_LENGTH`], see
+    /// [`Chunk::try_new`].
     pub fn new(chunk_type: ChunkType, data: Vec<u8>) -> Self {
+        Self::try_new(chunk_type, data).expect("chunk data longer than Chunk::MAX_LENGTH")
+    }
+
+    /// Creates a chunk holding `data`, computing its length and CRC. Data
+    /// longer than [`Chunk::MAX_LENGTH`] is reported as
+    /// [`Error::InvalidChunkLength`].
+    pub fn try_new(chunk_type: ChunkType, data: Vec<u8>) -> Result<Self> {
+        let length = match u32::try_from(data.len()) {
+            Ok(length) if length <= Self::MAX_LENGTH => length,
+            _ => {
+                return Err(Error::InvalidChunkLength {
+                    location: Location::default(),
+                    length: data.len() as u64,
+                    available: Self::MAX_LENGTH as u64,
+                })
+            }
+        };
         let crc = Self::crc_checksum(&chunk_type, &data);
-        let length = data.len() as u32;
-        Self {length: length, chunk_type: chunk_type, data: data, crc: crc}
+        Ok(Self { length, chunk_type, data, crc })
     }
     
+    /// Length of the chunk data in bytes.
     pub fn length(&self) -> usize {
         self.length as usize
     }
 
+    /// Type of the chunk.
     pub fn chunk_type(&self) -> &ChunkType {
         &self.chunk_type
     }
 
+    /// The chunk data, without length, type or CRC.
     pub fn data(&self) -> &[u8] {
         &self.data
     }
 
+    /// CRC over the chunk type and data.
     pub fn crc(&self) -> u32 {
         self.crc
     }
 
+    /// The chunk data decoded as UTF-8.
     pub fn data_as_string(&self) -> Result<String> {
-        String::from_utf8(self.data.clone()).map_err(Into::into)
+        self.as_chunk_ref().data_as_str().map(String::from)
     }
 
+    /// A view of the chunk borrowing its data.
+    pub fn as_chunk_ref(&self) -> ChunkRef<'_> {
+        ChunkRef {
+            chunk_type: self.chunk_type.clone(),
+            data: &self.data,
+            crc: self.crc,
+        }
+    }
+
+    /// The chunk serialized as it appears in a PNG file.
     pub fn as_bytes(&self) -> Vec<u8> {
         self.length
             .to_be_bytes()
@@ -55,77 +99,162 @@ impl Chunk {
             .collect()
     }
 
-    pub fn crc_checksum(chunk_type: &ChunkType, data: &Vec<u8>) -> u32 {
-        let bytes: Vec<_> = chunk_type
-            .bytes()
-            .iter()
-            .chain(data.iter())
-            .copied()
-            .collect();
+    /// Computes the CRC of a chunk with the given type and data.
+    pub fn crc_checksum(chunk_type: &ChunkType, data: &[u8]) -> u32 {
+        let mut digest = Self::crc_digest(chunk_type);
+        digest.update(data);
+        digest.finalize()
+    }
 
-        Self::CRC32.checksum(&bytes)
+    /// A CRC to be fed the data of a chunk read in pieces, already fed
+    /// the chunk type.
+    pub(crate) fn crc_digest(chunk_type: &ChunkType) -> Digest<'static, u32> {
+        let crc: &'static Crc<u32> = &Self::CRC32;
+        let mut digest = crc.digest();
+        digest.update(&chunk_type.bytes());
+        digest
     }
 
+    /// Whether the stored CRC matches the type and data. Only chunks read
+    /// leniently can fail this.
+    pub fn has_valid_crc(&self) -> bool {
+        self.crc == Self::crc_checksum(&self.chunk_type, &self.data)
+    }
+
+    /// A chunk keeping the CRC it was stored with, right or wrong.
+    pub(crate) fn with_crc(chunk_type: ChunkType, data: Vec<u8>, crc: u32) -> Self {
+        let length = data.len() as u32;
+        Self { length, chunk_type, data, crc }
+    }
 }
 
 impl TryFrom<&[u8]> for Chunk {
     type Error = Error;
 
-    fn try_from(value: &[u8]) -> Result<Self>{
-        if value.len() < Self::METADATA_BYTES_LEN {
-            return Err(Box::from(ChunkError::InvalidChunkLength));
-        }
+    /// Parses a chunk from the start of `value`. Error locations are
+    /// relative to the start of the slice.
+    ///
+    /// Never panics: truncated input and lengths above
+    /// [`Chunk::MAX_LENGTH`] are reported as [`Error::InvalidChunkLength`].
+    fn try_from(value: &[u8]) -> Result<Self> {
+        ChunkRef::try_from(value).map(Chunk::from)
+    }
+}
 
-        let length: usize = u32::from_be_bytes(value[0..4].try_into().unwrap()) as usize;
-        if value.len() < length + Self::METADATA_BYTES_LEN {
-            return Err(Box::from(ChunkError::InvalidChunkLength));
-        }
+impl From<ChunkRef<'_>> for Chunk {
+    fn from(chunk: ChunkRef<'_>) -> Self {
+        Self::with_crc(chunk.chunk_type, chunk.data.to_vec(), chunk.crc)
+    }
+}
+
+/// A chunk borrowing its data from the buffer it was parsed from, for
+/// reading without copying. [`Chunk`] is the owned version, for editing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkRef<'a> {
+    chunk_type: ChunkType,
+    data: &'a [u8],
+    crc: u32,
+}
 
-        let chunk_type: [u8;4] = value[4..8].try_into()?;
-        let chunk_type: ChunkType = chunk_type.try_into().unwrap();
+impl<'a> ChunkRef<'a> {
+    /// Length of the chunk data in bytes.
+    pub fn length(&self) -> usize {
+        self.data.len()
+    }
 
-        let data: Vec<u8> = value[8..8 + length].to_vec();
-        let crc: u32 = u32::from_be_bytes(value[8+length..length+12].try_into().unwrap());
+    /// Type of the chunk.
+    pub fn chunk_type(&self) -> &ChunkType {
+        &self.chunk_type
+    }
 
-        let checksum = Self::crc_checksum(&chunk_type, &data);
-        if crc != checksum {
-            return Err(Box::from(ChunkError::InvalidCrc(crc, checksum)));
-        }
+    /// The chunk data, without length, type or CRC.
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
 
-        Ok(Self {
-            length: length as u32,
-            chunk_type: chunk_type,
-            data: data,
-            crc: crc,
+    /// CRC over the chunk type and data.
+    pub fn crc(&self) -> u32 {
+        self.crc
+    }
+
+    /// The chunk data as UTF-8 text.
+    pub fn data_as_str(&self) -> Result<&'a str> {
+        std::str::from_utf8(self.data).map_err(|source| Error::InvalidUtf8 {
+            location: Location::at((Chunk::DATA_OFFSET + source.valid_up_to()) as u64),
+            source,
         })
+    }
 
+    /// Whether the stored CRC matches the type and data.
+    pub fn has_valid_crc(&self) -> bool {
+        self.crc == Chunk::crc_checksum(&self.chunk_type, self.data)
     }
-}
 
-#[derive(Debug)]
-pub enum ChunkError {
-    InvalidChunkLength,
-    InvalidCrc(u32, u32),
+    /// Copies the chunk into an owned [`Chunk`].
+    pub fn to_chunk(&self) -> Chunk {
+        Chunk::from(self.clone())
+    }
+
+    /// Parses a chunk like [`ChunkRef::try_from`] does, but keeps it even
+    /// if its CRC doesn't match.
+    pub(crate) fn parse_unchecked(value: &'a [u8]) -> Result<Self> {
+        if value.len() < Chunk::METADATA_BYTES_LEN {
+            let length = value
+                .get(..Chunk::LENGTH_BYTES_LEN)
+                .map_or(0, |bytes| u32::from_be_bytes(bytes.try_into().unwrap()));
+            return Err(Error::InvalidChunkLength {
+                location: Location::default(),
+                length: length as u64,
+                available: 0,
+            });
+        }
+
+        let length: u32 = u32::from_be_bytes(value[0..4].try_into().unwrap());
+        let available = value.len() - Chunk::METADATA_BYTES_LEN;
+        if length > Chunk::MAX_LENGTH || length as u64 > available as u64 {
+            return Err(Error::InvalidChunkLength {
+                location: Location::default(),
+                length: length as u64,
+                available: available as u64,
+            });
+        }
+        let length = length as usize;
+
+        let chunk_type: [u8;4] = value[4..8].try_into().unwrap();
+        let chunk_type: ChunkType = ChunkType::try_from(chunk_type)
+            .map_err(|e| e.relocate(Chunk::LENGTH_BYTES_LEN as u64, None))?;
+
+        let data = &value[8..8 + length];
+        let crc: u32 = u32::from_be_bytes(value[8+length..length+12].try_into().unwrap());
+
+        Ok(Self { chunk_type, data, crc })
+    }
 }
 
-impl std::error::Error for ChunkError {}
+impl<'a> TryFrom<&'a [u8]> for ChunkRef<'a> {
+    type Error = Error;
 
-impl Display for ChunkError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self{
-            Self::InvalidChunkLength => {
-                write!(f, "Invalid chunk length")
-            }
-            Self::InvalidCrc(expected, actual) => {
-                write!(f, "Invalid crc {}, {}", expected, actual)
-            }
+    /// Parses a chunk from the start of `value` without copying its data,
+    /// failing like [`Chunk::try_from`].
+    fn try_from(value: &'a [u8]) -> Result<Self> {
+        let chunk = Self::parse_unchecked(value)?;
+
+        let actual = Chunk::crc_checksum(&chunk.chunk_type, chunk.data);
+        if chunk.crc != actual {
+            return Err(Error::InvalidCrc {
+                location: Location::at((Chunk::DATA_OFFSET + chunk.length()) as u64),
+                expected: chunk.crc,
+                actual,
+            });
         }
+
+        Ok(chunk)
     }
 }
 
 impl Display for Chunk {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let data = self.data_as_string().unwrap();
+        let data = String::from_utf8_lossy(&self.data);
         write!(
             f,
             "Chunk {{ length: {}, chunk_type: {:?}, data: {}, crc: {} }}",
@@ -167,6 +296,17 @@ mod tests {
         assert_eq!(chunk.crc(), 2882656334);
     }
 
+    #[test]
+    fn test_new_chunk_above_spec_maximum() {
+        let chunk_type = ChunkType::from_str("RuSt").unwrap();
+        let data = vec![0; Chunk::MAX_LENGTH as usize + 1];
+        let chunk = Chunk::try_new(chunk_type.clone(), data);
+        assert!(matches!(chunk, Err(Error::InvalidChunkLength { length, .. }) if length == 1 << 31));
+
+        let chunk = Chunk::try_new(chunk_type, vec![1, 2, 3]).unwrap();
+        assert_eq!(chunk.length(), 3);
+    }
+
     #[test]
     fn test_chunk_length() {
         let chunk = testing_chunk();
@@ -242,6 +382,78 @@ mod tests {
         assert!(chunk.is_err());
     }
 
+    #[test]
+    fn test_invalid_crc_error() {
+        let mut chunk_data = testing_chunk().as_bytes();
+        let last = chunk_data.len() - 1;
+        chunk_data[last] ^= 1;
+
+        match Chunk::try_from(chunk_data.as_ref()) {
+            Err(Error::InvalidCrc { location, expected, actual }) => {
+                assert_eq!(location, Location::at(50));
+                assert_eq!(expected, 2882656335);
+                assert_eq!(actual, 2882656334);
+            }
+            other => panic!("expected crc error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_truncated_chunk_error() {
+        let chunk_data = testing_chunk().as_bytes();
+
+        match Chunk::try_from(&chunk_data[..40]) {
+            Err(Error::InvalidChunkLength { length, available, .. }) => {
+                assert_eq!(length, 42);
+                assert_eq!(available, 28);
+            }
+            other => panic!("expected length error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_chunk_length_above_spec_maximum() {
+        let mut chunk_data = testing_chunk().as_bytes();
+        chunk_data[..4].copy_from_slice(&(Chunk::MAX_LENGTH + 1).to_be_bytes());
+
+        let chunk = Chunk::try_from(chunk_data.as_ref());
+        assert!(matches!(chunk, Err(Error::InvalidChunkLength { length, .. }) if length == 1 << 31));
+
+        chunk_data[..4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(Chunk::try_from(chunk_data.as_ref()).is_err());
+    }
+
+    #[test]
+    fn test_chunk_from_short_input() {
+        let chunk_data = testing_chunk().as_bytes();
+
+        for end in 0..chunk_data.len() {
+            assert!(Chunk::try_from(&chunk_data[..end]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_invalid_chunk_type_error() {
+        let mut chunk_data = testing_chunk().as_bytes();
+        chunk_data[6] = b'1';
+
+        match Chunk::try_from(chunk_data.as_ref()) {
+            Err(Error::InvalidChunkType { location, .. }) => assert_eq!(location, Location::at(4)),
+            other => panic!("expected chunk type error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_invalid_utf8_error() {
+        let chunk_type = ChunkType::from_str("RuSt").unwrap();
+        let chunk = Chunk::new(chunk_type, vec![b'o', b'k', 0xff]);
+
+        match chunk.data_as_string() {
+            Err(Error::InvalidUtf8 { location, .. }) => assert_eq!(location, Location::at(10)),
+            other => panic!("expected utf-8 error, got {:?}", other),
+        }
+    }
+
     #[test]
     pub fn test_chunk_trait_impls() {
         let data_length: u32 = 42;
@@ -262,4 +474,40 @@ mod tests {
         
         let _chunk_string = format!("{}", chunk);
     }
+
+    #[test]
+    fn test_display_binary_chunk() {
+        let chunk = Chunk::new(ChunkType::from_str("RuSt").unwrap(), vec![0xff, 0xfe]);
+        let _chunk_string = format!("{}", chunk);
+    }
+
+    #[test]
+    fn test_chunk_ref_borrows_input() {
+        let chunk_data = testing_chunk().as_bytes();
+        let chunk = ChunkRef::try_from(chunk_data.as_ref()).unwrap();
+
+        assert!(std::ptr::eq(chunk.data(), &chunk_data[8..50]));
+        assert_eq!(chunk.data_as_str().unwrap(), "This is where your secret message will be!");
+        assert_eq!(chunk.crc(), 2882656334);
+        assert_eq!(chunk.to_chunk(), testing_chunk());
+        assert_eq!(testing_chunk().as_chunk_ref(), chunk);
+    }
+
+    #[test]
+    fn test_chunk_ref_errors_match_chunk() {
+        let mut chunk_data = testing_chunk().as_bytes();
+        chunk_data[20] ^= 1;
+
+        for end in 0..=chunk_data.len() {
+            let owned = Chunk::try_from(&chunk_data[..end]).unwrap_err();
+            let borrowed = ChunkRef::try_from(&chunk_data[..end]).unwrap_err();
+            assert_eq!(owned.to_string(), borrowed.to_string());
+        }
+    }
+
+    #[test]
+    fn test_crc_checksum() {
+        let chunk_type = ChunkType::from_str("IEND").unwrap();
+        assert_eq!(Chunk::crc_checksum(&chunk_type, &[]), 0xae426082);
+    }
 }
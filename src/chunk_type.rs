@@ -1,39 +1,48 @@
+//! Four letter chunk type codes and their property bits.
+
 use std::{str::FromStr, fmt::Display};
 
-use crate::{Error, Result};
+use crate::{Error, Location, Result};
 
+/// The four byte type code of a chunk, such as `IHDR` or `ruSt`.
+///
+/// The case of each letter encodes a property of the chunk, see
+/// [the PNG spec](http://www.libpng.org/pub/png/spec/1.2/PNG-Structure.html#Chunk-naming-conventions).
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ChunkType {
     bytes : [u8;4],
 }
 
 impl ChunkType {
+    /// The raw bytes of the type code.
     pub fn bytes(&self) -> [u8;4] {
         self.bytes
     }
 
+    /// Whether every byte is an ASCII letter and the reserved bit is valid.
     pub fn is_valid(&self) -> bool {
         let is_ascii_alphabetical = self.bytes()
             .iter()
-            .copied()
-            .map(|b| (b as char)
-            .is_ascii_alphabetic())
-            .all(|b| b);
+            .all(|b| b.is_ascii_alphabetic());
         is_ascii_alphabetical && self.is_reserved_bit_valid()
     }
 
+    /// Whether decoders must understand the chunk to display the image.
     pub fn is_critical(&self) -> bool {
         (self.bytes()[0] as char).is_uppercase()
     }
 
+    /// Whether the type is part of the PNG spec or registered with it.
     pub fn is_public(&self) -> bool {
         (self.bytes()[1] as char).is_uppercase()
     }
 
+    /// Whether the reserved third letter is uppercase, as required.
     pub fn is_reserved_bit_valid(&self) -> bool {
         (self.bytes()[2] as char).is_uppercase()
     }
 
+    /// Whether editors that don't know the chunk may copy it into a modified image.
     pub fn is_safe_to_copy(&self) -> bool {
         (self.bytes()[3] as char).is_lowercase()
     }
@@ -43,6 +52,13 @@ impl TryFrom<[u8;4]> for ChunkType {
     type Error = Error;
 
     fn try_from(value: [u8;4]) -> Result<Self> {
+        if !value.iter().all(|b| b.is_ascii_alphabetic()) {
+            return Err(Error::InvalidChunkType {
+                location: Location::default(),
+                bytes: value.to_vec(),
+            });
+        }
+
         Ok(Self { bytes: value })
     }
 }
@@ -51,25 +67,12 @@ impl FromStr for ChunkType {
     type Err = Error;
 
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
-        let bytes: &[u8] = s.as_bytes();
+        let bytes: [u8; 4] = s.as_bytes().try_into().map_err(|_| Error::InvalidChunkType {
+            location: Location::default(),
+            bytes: s.as_bytes().to_vec(),
+        })?;
 
-        if bytes.len() != 4 {
-            return Err("invalid string length for chunk type".into());
-        }
-
-        let chunk_type: ChunkType = ChunkType { bytes: bytes.try_into().unwrap() };
-    
-        let is_valid = bytes
-            .iter()
-            .copied()
-            .map(|b| (b as char).is_ascii_alphabetic())
-            .all(|b| b);
-        if !is_valid {
-            return Err("invalid chunk type".into());
-        }
-
-
-        Ok(chunk_type)
+        Self::try_from(bytes)
     }
 }
 
@@ -165,6 +168,15 @@ mod tests {
         assert!(chunk.is_err());
     }
 
+    #[test]
+    pub fn test_invalid_chunk_type_errors() {
+        let error = ChunkType::try_from([82, 117, 49, 116]).unwrap_err();
+        assert!(matches!(error, Error::InvalidChunkType { ref bytes, .. } if bytes == b"Ru1t"));
+
+        let error = ChunkType::from_str("RuStY").unwrap_err();
+        assert!(matches!(error, Error::InvalidChunkType { ref bytes, .. } if bytes == b"RuStY"));
+    }
+
     #[test]
     pub fn test_chunk_type_string() {
         let chunk = ChunkType::from_str("RuSt").unwrap();
@@ -1,87 +1,923 @@
-use std::borrow::Borrow;
-use std::fs::File;
-use std::io::{Error, Read, Write};
-use std::path::Path;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Error, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 use colored::Colorize;
 
-use crate::args::CliCommand;
-use crate::chunk::{Chunk, self};
-use crate::chunk_type::ChunkType;
-use crate::png::Png;
-
-const DEFAULT_CHUNK_TYPE: &str = "ruSt";
+use crate::args::{CliCommand, Method, Position};
+use crate::atomic;
+use pngme::capacity::{self, Overhead};
+use pngme::chunk::Chunk;
+use pngme::chunk_type::ChunkType;
+use pngme::encryption::{self, Identity, KeySource};
+use pngme::fragment;
+use pngme::ihdr::{ColorType, Interlace};
+use pngme::in_place;
+use pngme::lsb::{self, LsbOptions};
+use pngme::payload::{self, Payload};
+use pngme::png::Png;
+use pngme::reader::ChunkReader;
+use pngme::signature::{self, SigningKey};
+use pngme::validate::{Diagnostic, Severity};
 
 fn get_png(file_path: &String) -> Result<Png, Box<dyn std::error::Error>> {
-    let path = Path::new(file_path);
-    let mut file = File::open(path)?;
-
-    let mut buf: Vec<u8> = Vec::new();
-    file.read_to_end(&mut buf).unwrap();
+    let buf = read_file(file_path)?;
     let png = Png::try_from(buf.as_ref())?;
 
     Ok(png)
 }
 
+/// Reads the file at `file_path` without writing to it. An in place edit
+/// cut short leaves the file half written: it is read as if finished, and
+/// left for the next command writing to the file to finish.
+fn read_file(file_path: &String) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let (buf, pending) = in_place::read_recovered(Path::new(file_path))?;
+    if pending {
+        eprintln!(
+            "{}: an in place edit of '{}' was cut short, reading it as if it had finished",
+            "warning".yellow().bold(),
+            file_path
+        );
+    }
+    Ok(buf)
+}
+
+/// Reads the png at `file_path` however damaged it is, reporting the damage
+/// on stderr.
+fn get_png_lenient(file_path: &String) -> Result<Png, Box<dyn std::error::Error>> {
+    let buf = read_file(file_path)?;
+    let (png, diagnostics) = Png::parse_lenient(&buf);
+
+    for diagnostic in &diagnostics {
+        eprintln!("{}", format_diagnostic(&png, diagnostic));
+    }
+
+    Ok(png)
+}
+
+fn format_diagnostic(png: &Png, diagnostic: &Diagnostic) -> String {
+    let severity = match diagnostic.severity {
+        Severity::Error => "error".red().bold(),
+        Severity::Warning => "warning".yellow().bold(),
+    };
+    match diagnostic.chunk_index {
+        Some(index) => format!(
+            "{} in chunk {} ({}): {}",
+            severity,
+            index,
+            png.chunks()[index].chunk_type(),
+            diagnostic.message
+        ),
+        None => format!("{}: {}", severity, diagnostic.message),
+    }
+}
+
+/// Replaces the file at `file_path` with `buf`, see [`atomic::write_file`].
 fn overwrite_file(file_path: &String, buf: &[u8]) -> Result<(), Error> {
-    let mut file = File::options()
-        .truncate(true)
-        .write(true)
-        .open(Path::new(file_path))?;
-    file.write_all(buf).unwrap();
+    atomic::write_file(Path::new(file_path), false, |file| file.write_all(buf))
+}
+
+/// Writes `png` to `file_path` chunk by chunk, without serializing it first,
+/// and only replaces the file once it is fully written. With `backup`, the
+/// file replaced is kept as `<file_path>.orig`.
+fn write_png(file_path: &String, png: &Png, backup: bool) -> Result<(), Box<dyn std::error::Error>> {
+    // An in place edit cut short would otherwise be finished on the new file
+    in_place::recover(Path::new(file_path))?;
+    atomic::write_file(Path::new(file_path), backup, |file| {
+        png.write_to(BufWriter::new(file))?;
+        Ok(())
+    })
+}
+
+/// Returns the passphrase given on the command line, or prompts for it
+/// without echoing. New passphrases are asked for twice.
+fn get_passphrase(
+    passphrase: Option<String>,
+    confirm: bool,
+) -> Result<String, Box<dyn std::error::Error>> {
+    if let Some(passphrase) = passphrase {
+        return Ok(passphrase);
+    }
+
+    let passphrase = rpassword::prompt_password("Passphrase: ")?;
+    if confirm && passphrase != rpassword::prompt_password("Confirm passphrase: ")? {
+        return Err("Passphrases don't match".into());
+    }
+
+    Ok(passphrase)
+}
+
+/// Opens an encrypted message with the passphrase or identity file given,
+/// or returns `data` as is when it isn't encrypted.
+fn decrypt_message(
+    data: &[u8],
+    passphrase: Option<String>,
+    identity: Option<String>,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if !encryption::is_encrypted(data) {
+        return Ok(data.to_vec());
+    }
+
+    match encryption::key_source(data) {
+        Some(KeySource::Recipients) => {
+            let identity = identity.ok_or("Message is encrypted to recipients, pass --identity")?;
+            let identity = Identity::from_str(&fs::read_to_string(identity)?)?;
+            Ok(encryption::decrypt_with_identity(data, &identity)?)
+        }
+        _ => {
+            let passphrase = get_passphrase(passphrase, false)?;
+            Ok(encryption::decrypt(data, passphrase.as_bytes())?)
+        }
+    }
+}
+
+/// Frames the contents of `file`, or of stdin when no file is given, with
+/// its name and MIME type.
+fn read_payload(file: Option<String>) -> Result<Payload, Box<dyn std::error::Error>> {
+    let (name, data) = match file {
+        Some(file) => {
+            let name = Path::new(&file)
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned());
+            (name, fs::read(&file)?)
+        }
+        None => {
+            let mut data = Vec::new();
+            io::stdin().read_to_end(&mut data)?;
+            (None, data)
+        }
+    };
+
+    let mime_type = payload::guess_mime_type(name.as_deref(), &data).to_string();
+    Ok(Payload {
+        name,
+        mime_type: Some(mime_type),
+        data,
+    })
+}
+
+/// Where to extract a payload to: `output` itself, or the payload's
+/// original name inside `output` when it is a directory.
+fn output_path(output: &str, payload: &Payload) -> PathBuf {
+    let output = Path::new(output);
+    let name = payload
+        .name
+        .as_deref()
+        .and_then(|name| Path::new(name).file_name());
+
+    match name {
+        Some(name) if output.is_dir() => output.join(name),
+        _ => output.to_path_buf(),
+    }
+}
+
+/// Stores `data` in chunks of `chunk_type`, split into fragments when asked
+/// to or when it doesn't fit in one chunk.
+fn hide_in_chunks(
+    png: &mut Png,
+    chunk_type: &ChunkType,
+    data: Vec<u8>,
+    position: Position,
+    fragment_size: Option<usize>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let trailing = trailing_png(png);
+    let hidden_after_iend = trailing.is_some_and(|trailing| trailing.chunk_by_type(&chunk_type.to_string()).is_some());
+    if png.chunk_by_type(&chunk_type.to_string()).is_some() || hidden_after_iend {
+        return Err(format!(
+            "A message of type '{}' is already hidden in the image, remove it first",
+            chunk_type
+        )
+        .into());
+    }
+
+    if position == Position::End && !png.trailing_data().is_empty() && trailing_png(png).is_none() {
+        return Err("The image has other data after IEND, choose another position".into());
+    }
+
+    for chunk in message_chunks(chunk_type, data, fragment_size)? {
+        match position {
+            Position::BeforeIend => png.insert_before("IEND", chunk)?,
+            Position::BeforeIdat => png.insert_before("IDAT", chunk)?,
+            Position::End => png.append_chunk(chunk),
+        }
+    }
+
+    Ok(())
+}
+
+/// The chunks holding `data`, split into fragments when asked to or when
+/// it doesn't fit in one chunk.
+fn message_chunks(
+    chunk_type: &ChunkType,
+    data: Vec<u8>,
+    fragment_size: Option<usize>,
+) -> Result<Vec<Chunk>, Box<dyn std::error::Error>> {
+    let too_large = data.len() > Chunk::MAX_LENGTH as usize;
+    let chunks = match fragment_size {
+        None if !too_large => vec![data],
+        _ => {
+            let fragment_size = fragment_size.unwrap_or(fragment::MAX_FRAGMENT_SIZE);
+            fragment::split(&data, fragment_size)?
+        }
+    };
+    Ok(chunks
+        .into_iter()
+        .map(|data| Chunk::try_new(chunk_type.clone(), data))
+        .collect::<Result<_, _>>()?)
+}
+
+/// Checks that encode can edit the file in place before any passphrase is
+/// asked for: only chunks after the image data can be added that way.
+fn check_in_place(
+    file_path: &String,
+    chunk_type: &ChunkType,
+    method: Method,
+    position: Position,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if method != Method::Chunk || position == Position::BeforeIdat {
+        return Err("Only chunks before IEND or at the end can be added in place".into());
+    }
+
+    let layout = in_place::layout(Path::new(file_path))?;
+    if layout
+        .chunks
+        .iter()
+        .chain(&layout.trailing_chunks)
+        .any(|header| header.chunk_type == *chunk_type)
+    {
+        return Err(format!(
+            "A message of type '{}' is already hidden in the image, remove it first",
+            chunk_type
+        )
+        .into());
+    }
 
     Ok(())
 }
 
+/// The chunks appended after IEND, e.g. by `encode --position end`, if the
+/// trailing data is made of chunks.
+fn trailing_png(png: &Png) -> Option<Png> {
+    match png.trailing_chunks() {
+        Ok(chunks) if !chunks.is_empty() => Some(Png::from_chunks(chunks)),
+        _ => None,
+    }
+}
+
+/// Finds data hidden in chunks of `chunk_type`, after IEND or not, or
+/// failing that in the pixels, spread with `lsb_key` or row by row. Returns
+/// `None` when there is neither.
+fn find_hidden_data(
+    png: &Png,
+    chunk_type: &ChunkType,
+    lsb_key: Option<&[u8]>,
+) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+    if png.chunk_by_type(&chunk_type.to_string()).is_some() {
+        return Ok(Some(fragment::extract(png, &chunk_type.to_string())?));
+    }
+    if let Some(trailing) = trailing_png(png) {
+        if trailing.chunk_by_type(&chunk_type.to_string()).is_some() {
+            return Ok(Some(fragment::extract(&trailing, &chunk_type.to_string())?));
+        }
+    }
+
+    let pixels = match png.pixels() {
+        Ok(pixels) => pixels,
+        Err(_) => return Ok(None),
+    };
+    for key in [lsb_key, None] {
+        if lsb::is_embedded(&pixels, key) {
+            return Ok(Some(lsb::extract(&pixels, key)?));
+        }
+    }
+    Ok(None)
+}
+
+/// Looks for a message hidden in the chunks of the png at `file_path`,
+/// reading it chunk by chunk and dropping the data of other chunks.
+fn stream_hidden_data(
+    file_path: &String,
+    chunk_type: &ChunkType,
+) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+    // A file half written by an in place edit is only read whole
+    if in_place::has_pending_edit(Path::new(file_path))? {
+        return Ok(None);
+    }
+    let file = BufReader::new(File::open(file_path)?);
+    let wanted = chunk_type.clone();
+    let chunks = ChunkReader::new(file)?
+        .keep_only(move |chunk_type| *chunk_type == wanted)
+        .collect::<pngme::Result<Vec<_>>>()?;
+    if chunks.is_empty() {
+        return Ok(None);
+    }
+    // Errors are left to the whole file, which locates them and bounds the
+    // number of fragments by its real size
+    Ok(fragment::extract(&Png::from_chunks(chunks), &chunk_type.to_string()).ok())
+}
+
+/// Writes a new secret key file that only the current user can read.
+fn write_key_file(file_path: &String, contents: &str) -> Result<(), Error> {
+    let mut options = File::options();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options.open(file_path)?.write_all(contents.as_bytes())
+}
+
 pub fn execute_command(command: CliCommand) -> Result<(), Box<dyn std::error::Error>> {
     use CliCommand::*;
 
     match command {
-        Decode { file_path } => {
-            let png = get_png(&file_path).unwrap();
-
-            if let Some(chunk) = png.chunk_by_type(DEFAULT_CHUNK_TYPE) {
-                println!("{}", chunk.data_as_string().unwrap());
+        Decode {
+            file_path,
+            chunk_type,
+            passphrase,
+            identity,
+            lsb_key,
+            output,
+            lenient,
+        } => {
+            // Messages in chunks are found without loading the image, only
+            // trailing data and pixels need the whole file
+            let streamed = if lenient {
+                None
             } else {
-                println!("{} No message hidden in {file_path}", "Error:".red().bold())
+                stream_hidden_data(&file_path, &chunk_type)?
+            };
+            let found = match streamed {
+                Some(data) => Some(data),
+                None => {
+                    let png = if lenient {
+                        get_png_lenient(&file_path)?
+                    } else {
+                        get_png(&file_path)?
+                    };
+                    let lsb_key = lsb_key.as_ref().map(String::as_bytes);
+                    find_hidden_data(&png, &chunk_type, lsb_key)?
+                }
+            };
+            let data = found.ok_or_else(|| format!("No message of type '{}' hidden in {}", chunk_type, file_path))?;
+            let data = decrypt_message(&data, passphrase, identity)?;
+            let payload = Payload::from_bytes(&data)?;
+
+            if let Some(name) = &payload.name {
+                eprintln!("Name: {}", name);
+            }
+            if let Some(mime_type) = &payload.mime_type {
+                eprintln!("Type: {}", mime_type);
+            }
+
+            match output {
+                Some(output) => {
+                    let path = output_path(&output, &payload);
+                    atomic::write_file(&path, false, |file| file.write_all(&payload.data))?;
+                    eprintln!(
+                        "{} Wrote {} bytes to '{}'",
+                        "SUCCESS:".bright_green().bold(),
+                        payload.data.len(),
+                        path.display().to_string().blue(),
+                    );
+                }
+                None => {
+                    let mut stdout = io::stdout().lock();
+                    stdout.write_all(&payload.data)?;
+                    if stdout.is_terminal() && !payload.data.ends_with(b"\n") {
+                        writeln!(stdout)?;
+                    }
+                }
             }
         }
         Encode {
             file_path,
             message,
             output_file,
+            file,
+            stdin: _,
+            output,
+            chunk_type,
+            position,
+            fragment_size,
+            method,
+            bits,
+            channel_mask,
+            lsb_key,
+            encrypt,
+            passphrase,
+            recipients,
+            in_place,
+            backup,
         } => {
-            let mut png =
-                get_png(&file_path).unwrap_or_else(|_| panic!("File {} not found", &file_path));
-        
-            let chunk_type = ChunkType::try_from(<[u8;4]>::try_from(DEFAULT_CHUNK_TYPE.as_bytes())?).unwrap();
-            print!("{}", chunk_type);
-            let new_chunk : Chunk = Chunk::new(chunk_type, message.into_bytes());
+            // Editing in place is about not reading the whole file
+            let png = if in_place {
+                check_in_place(&file_path, &chunk_type, method, position)?;
+                None
+            } else {
+                Some(get_png(&file_path)?)
+            };
+
+            // Messages are framed like files, so decode never has to tell
+            // them apart from headers by their first bytes
+            let mut data = match message {
+                Some(message) => Payload::new(message.into_bytes()).to_bytes()?,
+                None => read_payload(file)?.to_bytes()?,
+            };
+            if encrypt {
+                let passphrase = get_passphrase(passphrase, true)?;
+                data = encryption::encrypt(&data, passphrase.as_bytes())?;
+            } else if !recipients.is_empty() {
+                data = encryption::encrypt_to_recipients(&data, &recipients)?;
+            }
 
-            png.append_chunk(new_chunk);
-            let buf = png.as_bytes();
+            let mut png = match png {
+                Some(png) => png,
+                None => {
+                    let chunks = message_chunks(&chunk_type, data, fragment_size)?;
+                    match position {
+                        Position::End => in_place::append(Path::new(&file_path), &chunks)?,
+                        _ => in_place::insert_before_iend(Path::new(&file_path), &chunks)?,
+                    }
+                    println!(
+                        "{} Wrote message to '{}' in place",
+                        "SUCCESS:".bright_green().bold(),
+                        file_path.blue(),
+                    );
+                    return Ok(());
+                }
+            };
 
-            match output_file {
-                None => overwrite_file(&file_path, buf.as_ref())?,
-                Some(x) => overwrite_file(&x, buf.as_ref())?,
+            match method {
+                Method::Chunk => hide_in_chunks(&mut png, &chunk_type, data, position, fragment_size)?,
+                Method::Lsb => {
+                    let options = LsbOptions {
+                        bits_per_channel: bits,
+                        channel_mask,
+                    };
+                    let lsb_key = lsb_key.as_ref().map(String::as_bytes);
+                    lsb::embed_in_png(&mut png, &data, &options, lsb_key)?;
+                }
             }
+            let output_file = output_file.or(output).unwrap_or_else(|| file_path.clone());
+            write_png(&output_file, &png, backup)?;
 
             println!(
                 "{} Wrote message to '{}'",
                 "SUCCESS:".bright_green().bold(),
-                file_path.blue(),
+                output_file.blue(),
+            );
+        }
+        Remove {
+            file_path,
+            chunk_type,
+            in_place,
+            backup,
+        } => {
+            if in_place {
+                in_place::remove_from_tail(Path::new(&file_path), &chunk_type.to_string())?;
+                return Ok(());
+            }
+
+            let mut png = get_png(&file_path)?;
+            let chunk_type = chunk_type.to_string();
+            match trailing_png(&png) {
+                Some(mut trailing) if png.chunk_by_type(&chunk_type).is_none() => {
+                    trailing.remove_chunks(&chunk_type)?;
+                    let chunks = trailing.chunks().iter().flat_map(|chunk| chunk.as_bytes()).collect();
+                    png.set_trailing_data(chunks);
+                }
+                _ => {
+                    png.remove_chunks(&chunk_type)?;
+                }
+            }
+            write_png(&file_path, &png, backup)?;
+        }
+        Print {
+            file_path,
+            chunk_type,
+        } => {
+            let png = get_png(&file_path)?;
+            match chunk_type {
+                None => {
+                    match png.ihdr() {
+                        Ok(ihdr) => println!("Image: {}", ihdr),
+                        Err(why) => println!("Image: {}", why),
+                    }
+                    println!("{}", png)
+                }
+                Some(chunk_type) => png
+                    .chunks()
+                    .iter()
+                    .filter(|chunk| *chunk.chunk_type() == chunk_type)
+                    .for_each(|chunk| println!("{}", chunk)),
+            }
+        }
+        Info { file_path } => {
+            let png = get_png(&file_path)?;
+            let ihdr = png.ihdr()?;
+
+            println!("File: {} ({} bytes)", file_path, png.encoded_len());
+            println!("Dimensions: {}x{}", ihdr.width, ihdr.height);
+            println!("Bit depth: {}", ihdr.bit_depth);
+            println!("Colour type: {} ({})", ihdr.color_type, ihdr.color_type.code());
+            println!(
+                "Interlace: {}",
+                match ihdr.interlace {
+                    Interlace::None => "none",
+                    Interlace::Adam7 => "Adam7",
+                }
             );
+
+            // Chunk types in order of first appearance, with count and size
+            let mut summary: Vec<(String, usize, usize)> = Vec::new();
+            for chunk in png.chunks() {
+                let chunk_type = chunk.chunk_type().to_string();
+                match summary.iter_mut().find(|(t, _, _)| *t == chunk_type) {
+                    Some((_, count, size)) => {
+                        *count += 1;
+                        *size += chunk.length();
+                    }
+                    None => summary.push((chunk_type, 1, chunk.length())),
+                }
+            }
+            println!("Chunks: {}", png.chunks().len());
+            for (chunk_type, count, size) in summary {
+                println!("  {} x{:<4} {} bytes", chunk_type, count, size);
+            }
+            if !png.trailing_data().is_empty() {
+                println!("Trailing data: {} bytes", png.trailing_data().len());
+            }
+        }
+        Capacity {
+            file_path,
+            file,
+            encrypt,
+            recipients,
+        } => {
+            let png = get_png(&file_path)?;
+            let ihdr = png.ihdr()?;
+
+            let payload = file.map(|file| read_payload(Some(file))).transpose()?;
+            let overhead = Overhead {
+                framing: payload
+                    .as_ref()
+                    .map_or_else(|| Payload::new(Vec::new()).overhead(), Payload::overhead),
+                encryption: match recipients {
+                    Some(count) => encryption::recipients_overhead(count),
+                    None if encrypt => encryption::PASSPHRASE_OVERHEAD,
+                    None => 0,
+                },
+            };
+
+            println!("Image: {}", ihdr);
+            if let Some(payload) = &payload {
+                println!("File: {} bytes", payload.data.len());
+            }
+            println!(
+                "Overhead: {} bytes of framing, {} bytes of encryption",
+                overhead.framing, overhead.encryption
+            );
+
+            let mut methods = vec![
+                ("chunk".to_string(), capacity::Method::Chunk),
+                (
+                    format!("chunk, fragmented, {} GiB file", capacity::MAX_FILE_LEN >> 30),
+                    capacity::Method::Fragments(fragment::MAX_FRAGMENT_SIZE),
+                ),
+            ];
+            let channels = ihdr.color_type.channels();
+            let mut masks = vec![("all channels", 0xff)];
+            if matches!(ihdr.color_type, ColorType::GrayscaleAlpha | ColorType::TruecolorAlpha) {
+                masks.push(("colour only", (1 << (channels - 1)) - 1));
+            }
+            for bits in 1..=ihdr.bit_depth.min(4) {
+                for (name, channel_mask) in &masks {
+                    let options = LsbOptions {
+                        bits_per_channel: bits,
+                        channel_mask: *channel_mask,
+                    };
+                    methods.push((
+                        format!("lsb, {} bit(s), {}", bits, name),
+                        capacity::Method::Lsb(options),
+                    ));
+                }
+            }
+
+            for (name, method) in methods {
+                let capacity = png.payload_capacity(&method, &overhead)?;
+                let line = format!("  {:<32} {:>20} bytes", name, capacity);
+                match &payload {
+                    Some(payload) if payload.data.len() <= capacity => {
+                        println!("{} {}", line, "fits".green())
+                    }
+                    Some(_) => println!("{} {}", line, "too large".red()),
+                    None => println!("{}", line),
+                }
+            }
+        }
+        Trailing {
+            file_path,
+            extract,
+            strip,
+            write,
+            output,
+            backup,
+        } => {
+            let mut png = get_png(&file_path)?;
+            let output = output.unwrap_or_else(|| file_path.clone());
+
+            if let Some(extract) = extract {
+                if png.trailing_data().is_empty() {
+                    return Err(format!("No data after IEND in {}", file_path).into());
+                }
+                overwrite_file(&extract, png.trailing_data())?;
+                println!(
+                    "{} Wrote {} bytes to '{}'",
+                    "SUCCESS:".bright_green().bold(),
+                    png.trailing_data().len(),
+                    extract.blue()
+                );
+            } else if strip {
+                let removed = png.take_trailing_data();
+                write_png(&output, &png, backup)?;
+                println!(
+                    "{} Removed {} bytes after IEND, wrote '{}'",
+                    "SUCCESS:".bright_green().bold(),
+                    removed.len(),
+                    output.blue()
+                );
+            } else if let Some(write) = write {
+                if png.chunks().last().is_none_or(|chunk| chunk.chunk_type().to_string() != "IEND") {
+                    return Err("The image doesn't end with IEND, data after it couldn't be told apart".into());
+                }
+                png.set_trailing_data(fs::read(&write)?);
+                write_png(&output, &png, backup)?;
+                println!(
+                    "{} Wrote {} bytes after IEND to '{}'",
+                    "SUCCESS:".bright_green().bold(),
+                    png.trailing_data().len(),
+                    output.blue()
+                );
+            } else {
+                let trailing = png.trailing_data();
+                if trailing.is_empty() {
+                    println!("No data after IEND");
+                    return Ok(());
+                }
+
+                let offset = png.encoded_len() - trailing.len() as u64;
+                println!("Trailing data: {} bytes at offset {}", trailing.len(), offset);
+                match png.trailing_chunks() {
+                    Ok(chunks) => {
+                        println!("Chunks: {}", chunks.len());
+                        for chunk in chunks {
+                            println!("  {} {} bytes", chunk.chunk_type(), chunk.length());
+                        }
+                    }
+                    Err(_) => println!("Type: {}", payload::guess_mime_type(None, trailing)),
+                }
+            }
+        }
+        Analyze { file_path, json } => {
+            let png = get_png(&file_path)?;
+            let report = png.analyze();
+
+            if json {
+                println!("{}", report.to_json());
+                return Ok(());
+            }
+
+            let verdict = match report.score() {
+                score if score < 0.3 => report.verdict().green(),
+                score if score < 0.7 => report.verdict().yellow(),
+                _ => report.verdict().red(),
+            };
+            println!("Score: {:.2}, {}", report.score(), verdict.bold());
+            for finding in &report.findings {
+                println!("  {}", finding);
+            }
+            match &report.pixels {
+                Some(stats) => println!(
+                    "Pixels: chi-square p = {:.3} over {:.0}% of the samples, RS estimate {:.0}%",
+                    stats.chi_square,
+                    stats.chi_square_extent * 100.0,
+                    stats.rs_estimate * 100.0
+                ),
+                None => println!("Pixels: not analyzed, only 8-bit non-palette images are"),
+            }
+        }
+        Validate { file_path } => {
+            let png = get_png(&file_path)?;
+            let diagnostics = png.validate();
+
+            for diagnostic in &diagnostics {
+                println!("{}", format_diagnostic(&png, diagnostic));
+            }
+
+            let errors = diagnostics
+                .iter()
+                .filter(|diagnostic| diagnostic.severity == Severity::Error)
+                .count();
+            if errors > 0 {
+                return Err(format!("{} is not a valid PNG: {} error(s)", file_path, errors).into());
+            }
+            if diagnostics.is_empty() {
+                println!("{} {} follows the PNG chunk rules", "OK:".bright_green().bold(), file_path);
+            }
+        }
+        Keygen { output, signing } => {
+            let (public, key_file) = if signing {
+                let key = SigningKey::generate();
+                let public = key.verifying_key().to_string();
+                let key_file = format!("# verifying key: {}\n{}\n", public, key);
+                (public, key_file)
+            } else {
+                let identity = Identity::generate();
+                let public = identity.recipient().to_string();
+                let key_file = format!("# public key: {}\n{}\n", public, identity);
+                (public, key_file)
+            };
+
+            match output {
+                None => print!("{}", key_file),
+                Some(output) => {
+                    write_key_file(&output, &key_file)?;
+                    println!("Public key: {}", public);
+                }
+            }
         }
-        Remove { file_path } => {
+        Sign {
+            file_path,
+            output_file,
+            key,
+            chunk_type,
+            backup,
+        } => {
+            let key = SigningKey::from_str(&fs::read_to_string(key)?)?;
             let mut png = get_png(&file_path)?;
-            png.remove_chunk(DEFAULT_CHUNK_TYPE).unwrap();
-            overwrite_file(&file_path, png.as_bytes().as_ref()).unwrap();
+            signature::sign(&mut png, &chunk_type, &key)?;
+
+            let output_file = output_file.unwrap_or_else(|| file_path.clone());
+            write_png(&output_file, &png, backup)?;
+
+            println!(
+                "{} Signed message '{}' in '{}' as {}",
+                "SUCCESS:".bright_green().bold(),
+                chunk_type,
+                output_file.blue(),
+                key.verifying_key(),
+            );
         }
-        Print { file_path } => {
+        Verify {
+            file_path,
+            chunk_type,
+            signer,
+        } => {
             let png = get_png(&file_path)?;
-            println!("{}", png);
+            let verification = signature::verify(&png, &chunk_type)?;
+
+            let expected_signer = signer.is_none_or(|signer| signer == verification.signer);
+            println!(
+                "Signer: {}{}",
+                verification.signer,
+                if expected_signer { "" } else { " (not the expected signer)" }
+            );
+            println!(
+                "Signature: {}",
+                if verification.signature_valid { "valid".green() } else { "INVALID".red().bold() }
+            );
+            if verification.changed.is_empty() {
+                println!("Changed chunks: none");
+            } else {
+                let changed: Vec<String> =
+                    verification.changed.iter().map(ToString::to_string).collect();
+                println!("Changed chunks: {}", changed.join(", ").red().bold());
+            }
+
+            if !verification.is_valid() || !expected_signer {
+                return Err("Signature verification failed".into());
+            }
         }
     };
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::args::Cli;
+
+    /// A directory of its own in the temporary directory, holding a copy of
+    /// the test image, removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("pngme-{}-{}", std::process::id(), name));
+            fs::create_dir_all(&path).unwrap();
+            fs::write(path.join("image.png"), include_bytes!("../test.png")).unwrap();
+            Self(path)
+        }
+
+        fn path(&self, name: &str) -> String {
+            self.0.join(name).to_string_lossy().into_owned()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn run(args: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+        let cli = Cli::try_parse_from([&["pngme"], args].concat()).unwrap();
+        execute_command(cli.command)
+    }
+
+    #[test]
+    fn test_verify_fails_on_changed_image() {
+        let dir = TempDir::new("commands-verify");
+        let (image, key) = (dir.path("image.png"), dir.path("key"));
+        run(&["encode", &image, "hidden", "--chunk-type", "teSt"]).unwrap();
+        run(&["keygen", "--signing", "--output", &key]).unwrap();
+        run(&["sign", &image, "--key", &key, "--chunk-type", "teSt"]).unwrap();
+        run(&["verify", &image, "--chunk-type", "teSt"]).unwrap();
+
+        // Change a pixel byte, keeping the CRC right
+        let png = get_png(&image).unwrap();
+        let chunks = png
+            .chunks()
+            .iter()
+            .map(|chunk| match chunk.chunk_type().to_string().as_str() {
+                "IDAT" => {
+                    let mut data = chunk.data().to_vec();
+                    data[10] ^= 0x01;
+                    Chunk::new(chunk.chunk_type().clone(), data)
+                }
+                _ => chunk.clone(),
+            })
+            .collect();
+        write_png(&image, &Png::from_chunks(chunks), false).unwrap();
+
+        assert!(run(&["verify", &image, "--chunk-type", "teSt"]).is_err());
+    }
+
+    #[test]
+    fn test_validate_fails_on_errors_only() {
+        let dir = TempDir::new("commands-validate");
+        let image = dir.path("image.png");
+        // The test image has a chunk appended after IEND
+        let error = run(&["validate", &image]).unwrap_err();
+        assert!(error.to_string().contains("is not a valid PNG: 1 error(s)"));
+
+        // Data after IEND that isn't made of chunks is only worth a warning
+        let mut png = get_png(&image).unwrap();
+        png.set_trailing_data(b"appended archive".to_vec());
+        write_png(&image, &png, false).unwrap();
+        run(&["validate", &image]).unwrap();
+
+        png.remove_chunk("IEND").unwrap();
+        png.take_trailing_data();
+        write_png(&image, &png, false).unwrap();
+        let error = run(&["validate", &image]).unwrap_err();
+        assert!(error.to_string().contains("is not a valid PNG: 1 error(s)"));
+    }
+
+    #[test]
+    fn test_decode_fails_on_missing_message() {
+        let dir = TempDir::new("commands-missing");
+        let image = dir.path("image.png");
+        let error = run(&["decode", &image, "--chunk-type", "teSt"]).unwrap_err();
+        assert!(error.to_string().contains("No message of type 'teSt' hidden in"));
+    }
+
+    #[test]
+    fn test_decode_fails_on_wrong_passphrase() {
+        let dir = TempDir::new("commands-passphrase");
+        let image = dir.path("image.png");
+        run(&["encode", &image, "hidden", "--chunk-type", "teSt", "--encrypt", "--passphrase", "right"]).unwrap();
+
+        let output = dir.path("message");
+        run(&["decode", &image, "--chunk-type", "teSt", "--passphrase", "right", "-o", &output]).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), b"hidden");
+        let error = run(&["decode", &image, "--chunk-type", "teSt", "--passphrase", "wrong"]).unwrap_err();
+        assert!(error.to_string().contains("Decryption failed"));
+    }
+
+    #[test]
+    fn test_messages_starting_with_magic_round_trip() {
+        let dir = TempDir::new("commands-magic");
+        let (image, output) = (dir.path("image.png"), dir.path("message"));
+        for message in ["pmPL hello", "pmEN hello", "pmFR hello"] {
+            run(&["encode", &image, message, "--chunk-type", "teSt"]).unwrap();
+            run(&["decode", &image, "--chunk-type", "teSt", "-o", &output]).unwrap();
+            assert_eq!(fs::read(&output).unwrap(), message.as_bytes());
+            run(&["remove", &image, "--chunk-type", "teSt"]).unwrap();
+        }
+    }
+}
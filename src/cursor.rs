@@ -0,0 +1,85 @@
+//! Bounds-checked reading of the binary formats stored inside chunks.
+
+use crate::{Error, Location, Result};
+
+/// Reads big-endian fields from a byte slice, reporting truncation as
+/// [`Error::InvalidPayload`] at the offset where the read started.
+pub(crate) struct Cursor<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Cursor<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    /// Offset of the next byte to be read.
+    pub(crate) fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Everything that hasn't been read yet.
+    pub(crate) fn rest(&self) -> &'a [u8] {
+        &self.data[self.position..]
+    }
+
+    /// An [`Error::InvalidPayload`] located at the current position.
+    pub(crate) fn error(&self, reason: impl Into<String>) -> Error {
+        Error::InvalidPayload {
+            location: Location::at(self.position as u64),
+            reason: reason.into(),
+        }
+    }
+
+    pub(crate) fn read_bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .position
+            .checked_add(len)
+            .and_then(|end| self.data.get(self.position..end))
+            .ok_or_else(|| self.error(format!("expected {} more bytes", len)))?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    pub(crate) fn read_array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.read_bytes(N)?.try_into().unwrap())
+    }
+
+    pub(crate) fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.read_array::<1>()?[0])
+    }
+
+    pub(crate) fn read_u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.read_array()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reads_fields_in_order() {
+        let data = [1, 0, 0, 0, 2, 3, 4];
+        let mut cursor = Cursor::new(&data);
+
+        assert_eq!(cursor.read_u8().unwrap(), 1);
+        assert_eq!(cursor.read_u32().unwrap(), 2);
+        assert_eq!(cursor.position(), 5);
+        assert_eq!(cursor.rest(), &[3, 4]);
+    }
+
+    #[test]
+    fn test_truncated_read_reports_offset() {
+        let data = [1, 2, 3];
+        let mut cursor = Cursor::new(&data);
+        cursor.read_u8().unwrap();
+
+        match cursor.read_u32() {
+            Err(Error::InvalidPayload { location, .. }) => assert_eq!(location, Location::at(1)),
+            other => panic!("expected payload error, got {:?}", other),
+        }
+        assert!(cursor.read_bytes(usize::MAX).is_err());
+    }
+}
@@ -0,0 +1,613 @@
+//! Authenticated encryption of chunk data, keyed either by a passphrase or
+//! by the X25519 keys of one or more recipients.
+//!
+//! Data is sealed with XChaCha20-Poly1305. Sealed data starts with a
+//! versioned header naming the algorithms and holding their parameters:
+//!
+//! | Field                                  | Size      |
+//! |----------------------------------------|-----------|
+//! | Magic `pmEN`                           | 4         |
+//! | Version                                | 1         |
+//! | KDF id                                 | 1         |
+//! | KDF parameters                         | see below |
+//! | AEAD id (1 = XChaCha20-Poly1305)       | 1         |
+//! | Nonce                                  | 24        |
+//! | Ciphertext and tag                     | remaining |
+//!
+//! With a passphrase (KDF id 1) the key is derived with Argon2id and the
+//! parameters are the memory in KiB, iterations and parallelism as three
+//! u32s, then a one byte salt length and the salt.
+//!
+//! With recipients (KDF id 2) the data is sealed with a random key, and the
+//! parameters are a one byte recipient count followed, for each recipient,
+//! by an ephemeral X25519 public key (32 bytes) and the data key wrapped
+//! with ChaCha20-Poly1305 under a key derived from the X25519 shared secret
+//! with HKDF-SHA256 (48 bytes).
+//!
+//! The header is authenticated along with the ciphertext, so changing any
+//! parameter makes decryption fail just like a wrong key does.
+
+use std::fmt::{Debug, Display};
+use std::str::FromStr;
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+use crate::cursor::Cursor;
+use crate::{Error, Location, Result};
+
+const MAGIC: [u8; 4] = *b"pmEN";
+const VERSION: u8 = 1;
+const KDF_ARGON2ID: u8 = 1;
+const KDF_X25519: u8 = 2;
+const AEAD_XCHACHA20POLY1305: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+const WRAPPED_KEY_LEN: usize = KEY_LEN + TAG_LEN;
+const STANZA_LEN: usize = 32 + WRAPPED_KEY_LEN;
+const PREFIX_LEN: usize = MAGIC.len() + 1 + 1;
+const SUFFIX_LEN: usize = 1 + NONCE_LEN + TAG_LEN;
+const HKDF_INFO: &[u8] = b"pngme x25519 v1";
+
+const IDENTITY_PREFIX: &str = "PNGME-SECRET-KEY-";
+const RECIPIENT_PREFIX: &str = "pngme-pk-";
+
+// Sealed data comes from untrusted files, so the cost it may ask of the KDF
+// is bounded to keep a hostile image from exhausting memory or CPU.
+const MAX_MEMORY_KIB: u32 = 1024 * 1024;
+const MAX_ITERATIONS: u32 = 64;
+const MAX_PARALLELISM: u32 = 16;
+
+/// Cost parameters of the Argon2id key derivation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    /// Memory used, in KiB.
+    pub memory_kib: u32,
+    /// Number of passes over the memory.
+    pub iterations: u32,
+    /// Number of lanes.
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+/// How the key of sealed data is obtained.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeySource {
+    /// Derived from a passphrase, see [`decrypt`].
+    Passphrase,
+    /// Wrapped for one or more recipients, see [`decrypt_with_identity`].
+    Recipients,
+}
+
+/// Number of bytes [`encrypt`] adds to the plaintext.
+pub const PASSPHRASE_OVERHEAD: usize = PREFIX_LEN + 3 * 4 + 1 + SALT_LEN + SUFFIX_LEN;
+
+/// Number of bytes [`encrypt_to_recipients`] adds to the plaintext when
+/// sealing for `recipients` recipients.
+pub const fn recipients_overhead(recipients: usize) -> usize {
+    PREFIX_LEN + 1 + recipients * STANZA_LEN + SUFFIX_LEN
+}
+
+/// Whether `data` looks like the output of [`encrypt`] or
+/// [`encrypt_to_recipients`].
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(&MAGIC)
+}
+
+/// How the key of `data` is obtained, or `None` if `data` isn't sealed by a
+/// supported version.
+pub fn key_source(data: &[u8]) -> Option<KeySource> {
+    match read_prefix(&mut Cursor::new(data)).ok()? {
+        KDF_ARGON2ID => Some(KeySource::Passphrase),
+        KDF_X25519 => Some(KeySource::Recipients),
+        _ => None,
+    }
+}
+
+/// Seals `plaintext` with a key derived from `passphrase` using the default
+/// KDF parameters.
+pub fn encrypt(plaintext: &[u8], passphrase: &[u8]) -> Result<Vec<u8>> {
+    encrypt_with_params(plaintext, passphrase, KdfParams::default())
+}
+
+/// Seals `plaintext` with a key derived from `passphrase` using `params`.
+pub fn encrypt_with_params(
+    plaintext: &[u8],
+    passphrase: &[u8],
+    params: KdfParams,
+) -> Result<Vec<u8>> {
+    let mut salt = [0; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+
+    let mut header = Vec::with_capacity(PASSPHRASE_OVERHEAD + plaintext.len());
+    write_prefix(&mut header, KDF_ARGON2ID);
+    header.extend_from_slice(&params.memory_kib.to_be_bytes());
+    header.extend_from_slice(&params.iterations.to_be_bytes());
+    header.extend_from_slice(&params.parallelism.to_be_bytes());
+    header.push(SALT_LEN as u8);
+    header.extend_from_slice(&salt);
+
+    let key = derive_key(passphrase, &salt, params)?;
+    seal(header, &key, plaintext)
+}
+
+/// Seals `plaintext` so that any of `recipients` can open it with their
+/// [`Identity`].
+pub fn encrypt_to_recipients(plaintext: &[u8], recipients: &[Recipient]) -> Result<Vec<u8>> {
+    if recipients.is_empty() || recipients.len() > u8::MAX as usize {
+        return Err(Error::InvalidKey {
+            reason: format!("can't encrypt to {} recipients", recipients.len()),
+        });
+    }
+
+    let key = XChaCha20Poly1305::generate_key(&mut OsRng);
+
+    let mut header = Vec::with_capacity(recipients_overhead(recipients.len()) + plaintext.len());
+    write_prefix(&mut header, KDF_X25519);
+    header.push(recipients.len() as u8);
+
+    for recipient in recipients {
+        let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral_public = PublicKey::from(&ephemeral);
+        let shared = ephemeral.diffie_hellman(&recipient.0);
+        if !shared.was_contributory() {
+            return Err(Error::InvalidKey {
+                reason: format!("{} is not a usable public key", recipient),
+            });
+        }
+
+        let wrapping_key = wrapping_key(shared.as_bytes(), &ephemeral_public, &recipient.0);
+        let wrapped = ChaCha20Poly1305::new(&wrapping_key)
+            .encrypt(&Nonce::default(), key.as_slice())
+            .expect("wrapping a 32 byte key can't fail");
+
+        header.extend_from_slice(ephemeral_public.as_bytes());
+        header.extend_from_slice(&wrapped);
+    }
+
+    seal(header, &key, plaintext)
+}
+
+/// Opens data sealed by [`encrypt`].
+///
+/// A malformed or unsupported header is an [`Error::InvalidPayload`]; a
+/// wrong passphrase or modified data is [`Error::DecryptionFailed`].
+pub fn decrypt(sealed: &[u8], passphrase: &[u8]) -> Result<Vec<u8>> {
+    let mut cursor = Cursor::new(sealed);
+
+    let kdf = read_prefix(&mut cursor)?;
+    if kdf != KDF_ARGON2ID {
+        return Err(Error::InvalidPayload {
+            location: Location::at(5),
+            reason: "data is not encrypted with a passphrase".to_string(),
+        });
+    }
+
+    let params = KdfParams {
+        memory_kib: cursor.read_u32()?,
+        iterations: cursor.read_u32()?,
+        parallelism: cursor.read_u32()?,
+    };
+    if params.memory_kib > MAX_MEMORY_KIB
+        || params.iterations > MAX_ITERATIONS
+        || params.parallelism > MAX_PARALLELISM
+    {
+        return Err(cursor.error(format!("key derivation cost {:?} is too high", params)));
+    }
+
+    let salt_len = cursor.read_u8()? as usize;
+    let salt = cursor.read_bytes(salt_len)?;
+    let nonce = read_cipher(&mut cursor)?;
+
+    let key = derive_key(passphrase, salt, params)?;
+    open(sealed, cursor, &nonce, &key)
+}
+
+/// Opens data sealed by [`encrypt_to_recipients`] for the recipient of
+/// `identity`.
+///
+/// A malformed or unsupported header is an [`Error::InvalidPayload`]; data
+/// not sealed for `identity`, or modified data, is
+/// [`Error::DecryptionFailed`].
+pub fn decrypt_with_identity(sealed: &[u8], identity: &Identity) -> Result<Vec<u8>> {
+    let mut cursor = Cursor::new(sealed);
+
+    let kdf = read_prefix(&mut cursor)?;
+    if kdf != KDF_X25519 {
+        return Err(Error::InvalidPayload {
+            location: Location::at(5),
+            reason: "data is not encrypted to recipients".to_string(),
+        });
+    }
+
+    let count = cursor.read_u8()? as usize;
+    let stanzas = cursor.read_bytes(count * STANZA_LEN)?;
+    let nonce = read_cipher(&mut cursor)?;
+
+    let public = PublicKey::from(&identity.0);
+    let key = stanzas
+        .chunks_exact(STANZA_LEN)
+        .find_map(|stanza| {
+            let ephemeral_public = PublicKey::from(<[u8; 32]>::try_from(&stanza[..32]).unwrap());
+            let shared = identity.0.diffie_hellman(&ephemeral_public);
+            if !shared.was_contributory() {
+                return None;
+            }
+
+            let wrapping_key = wrapping_key(shared.as_bytes(), &ephemeral_public, &public);
+            let key = ChaCha20Poly1305::new(&wrapping_key)
+                .decrypt(&Nonce::default(), &stanza[32..])
+                .ok()?;
+            Some(*Key::from_slice(&key))
+        })
+        .ok_or(Error::DecryptionFailed)?;
+
+    open(sealed, cursor, &nonce, &key)
+}
+
+fn write_prefix(header: &mut Vec<u8>, kdf: u8) {
+    header.extend_from_slice(&MAGIC);
+    header.push(VERSION);
+    header.push(kdf);
+}
+
+/// Reads the magic and version and returns the KDF id.
+fn read_prefix(cursor: &mut Cursor) -> Result<u8> {
+    if cursor.read_array::<4>()? != MAGIC {
+        return Err(Error::InvalidPayload {
+            location: Location::at(0),
+            reason: "data is not encrypted".to_string(),
+        });
+    }
+
+    let version = cursor.read_u8()?;
+    if version != VERSION {
+        return Err(cursor.error(format!("unsupported encryption version {}", version)));
+    }
+
+    let kdf = cursor.read_u8()?;
+    if kdf != KDF_ARGON2ID && kdf != KDF_X25519 {
+        return Err(cursor.error(format!("unsupported key derivation {}", kdf)));
+    }
+
+    Ok(kdf)
+}
+
+/// Reads the AEAD id and returns the nonce.
+fn read_cipher(cursor: &mut Cursor) -> Result<XNonce> {
+    let aead = cursor.read_u8()?;
+    if aead != AEAD_XCHACHA20POLY1305 {
+        return Err(cursor.error(format!("unsupported cipher {}", aead)));
+    }
+
+    Ok(XNonce::clone_from_slice(cursor.read_bytes(NONCE_LEN)?))
+}
+
+/// Appends the AEAD id and a fresh nonce to `header`, then the ciphertext
+/// of `plaintext` authenticated together with the whole header.
+fn seal(mut header: Vec<u8>, key: &Key, plaintext: &[u8]) -> Result<Vec<u8>> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    header.push(AEAD_XCHACHA20POLY1305);
+    header.extend_from_slice(&nonce);
+
+    let ciphertext = XChaCha20Poly1305::new(key)
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad: &header,
+            },
+        )
+        .map_err(|_| Error::InvalidPayload {
+            location: Location::default(),
+            reason: "payload is too large to encrypt".to_string(),
+        })?;
+    header.extend_from_slice(&ciphertext);
+
+    Ok(header)
+}
+
+/// Decrypts what follows the header read by `cursor`.
+fn open(sealed: &[u8], cursor: Cursor, nonce: &XNonce, key: &Key) -> Result<Vec<u8>> {
+    XChaCha20Poly1305::new(key)
+        .decrypt(
+            nonce,
+            Payload {
+                msg: cursor.rest(),
+                aad: &sealed[..cursor.position()],
+            },
+        )
+        .map_err(|_| Error::DecryptionFailed)
+}
+
+fn derive_key(passphrase: &[u8], salt: &[u8], params: KdfParams) -> Result<Key> {
+    let invalid = |e: argon2::Error| Error::InvalidPayload {
+        location: Location::default(),
+        reason: format!("invalid key derivation parameters: {}", e),
+    };
+
+    let params = Params::new(
+        params.memory_kib,
+        params.iterations,
+        params.parallelism,
+        Some(KEY_LEN),
+    )
+    .map_err(invalid)?;
+
+    let mut key = Key::default();
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase, salt, &mut key)
+        .map_err(invalid)?;
+
+    Ok(key)
+}
+
+fn wrapping_key(shared: &[u8; 32], ephemeral: &PublicKey, recipient: &PublicKey) -> Key {
+    let mut salt = [0; 64];
+    salt[..32].copy_from_slice(ephemeral.as_bytes());
+    salt[32..].copy_from_slice(recipient.as_bytes());
+
+    let mut key = Key::default();
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(HKDF_INFO, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+pub(crate) fn parse_key(s: &str, prefix: &str) -> Result<[u8; 32]> {
+    // Key files may carry comments, such as the matching public key
+    let line = s
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .unwrap_or_default();
+
+    let invalid = || Error::InvalidKey {
+        reason: format!("expected a key starting with {}", prefix),
+    };
+    let hex = line.strip_prefix(prefix).ok_or_else(invalid)?;
+    let mut key = [0; 32];
+    hex::decode_to_slice(hex, &mut key).map_err(|_| invalid())?;
+
+    Ok(key)
+}
+
+/// An X25519 secret key, able to open data sealed for its [`Recipient`].
+///
+/// Serialized as `PNGME-SECRET-KEY-` followed by the key in hex.
+pub struct Identity(StaticSecret);
+
+impl Identity {
+    /// Generates a new random identity.
+    pub fn generate() -> Self {
+        Self(StaticSecret::random_from_rng(OsRng))
+    }
+
+    /// The public key data can be sealed to for this identity.
+    pub fn recipient(&self) -> Recipient {
+        Recipient(PublicKey::from(&self.0))
+    }
+}
+
+impl FromStr for Identity {
+    type Err = Error;
+
+    /// Parses an identity, skipping blank lines and `#` comments so the
+    /// contents of a key file can be passed as is.
+    fn from_str(s: &str) -> Result<Self> {
+        parse_key(s, IDENTITY_PREFIX).map(|key| Self(StaticSecret::from(key)))
+    }
+}
+
+impl Display for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", IDENTITY_PREFIX, hex::encode_upper(self.0.as_bytes()))
+    }
+}
+
+impl Debug for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Identity").field(&self.recipient()).finish()
+    }
+}
+
+/// An X25519 public key that data can be sealed to.
+///
+/// Serialized as `pngme-pk-` followed by the key in hex.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Recipient(PublicKey);
+
+impl FromStr for Recipient {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        parse_key(s, RECIPIENT_PREFIX).map(|key| Self(PublicKey::from(key)))
+    }
+}
+
+impl Display for Recipient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", RECIPIENT_PREFIX, hex::encode(self.0.as_bytes()))
+    }
+}
+
+impl Debug for Recipient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Recipient({})", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Cheap parameters so the tests don't spend seconds in the KDF
+    const TEST_PARAMS: KdfParams = KdfParams {
+        memory_kib: 64,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    fn sealed_message() -> Vec<u8> {
+        encrypt_with_params(b"This is a secret message!", b"hunter2", TEST_PARAMS).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let sealed = sealed_message();
+        assert!(is_encrypted(&sealed));
+        assert_eq!(sealed.len(), PASSPHRASE_OVERHEAD + 25);
+        assert_eq!(key_source(&sealed), Some(KeySource::Passphrase));
+
+        let plaintext = decrypt(&sealed, b"hunter2").unwrap();
+        assert_eq!(plaintext, b"This is a secret message!");
+    }
+
+    #[test]
+    fn test_plaintext_is_not_stored() {
+        let sealed = sealed_message();
+        assert!(!sealed.windows(6).any(|w| w == b"secret"));
+    }
+
+    #[test]
+    fn test_wrong_passphrase() {
+        let sealed = sealed_message();
+        assert!(matches!(decrypt(&sealed, b"hunter3"), Err(Error::DecryptionFailed)));
+    }
+
+    #[test]
+    fn test_tampered_ciphertext() {
+        let mut sealed = sealed_message();
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        assert!(matches!(decrypt(&sealed, b"hunter2"), Err(Error::DecryptionFailed)));
+    }
+
+    #[test]
+    fn test_tampered_header() {
+        let mut sealed = sealed_message();
+        // First byte of the salt
+        sealed[19] ^= 1;
+        assert!(matches!(decrypt(&sealed, b"hunter2"), Err(Error::DecryptionFailed)));
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let mut sealed = sealed_message();
+        sealed[4] = 2;
+        match decrypt(&sealed, b"hunter2") {
+            Err(Error::InvalidPayload { location, .. }) => assert_eq!(location, Location::at(5)),
+            other => panic!("expected payload error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_hostile_kdf_cost() {
+        let mut sealed = sealed_message();
+        sealed[6..10].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(decrypt(&sealed, b"hunter2"), Err(Error::InvalidPayload { .. })));
+    }
+
+    #[test]
+    fn test_truncated_data() {
+        let sealed = sealed_message();
+        for end in 0..sealed.len() {
+            assert!(decrypt(&sealed[..end], b"hunter2").is_err());
+        }
+    }
+
+    #[test]
+    fn test_not_encrypted() {
+        assert!(!is_encrypted(b"plain message"));
+        assert!(matches!(decrypt(b"plain message", b"hunter2"), Err(Error::InvalidPayload { .. })));
+    }
+
+    #[test]
+    fn test_recipients_round_trip() {
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        let recipients = [alice.recipient(), bob.recipient()];
+
+        let sealed = encrypt_to_recipients(b"for your eyes only", &recipients).unwrap();
+        assert_eq!(sealed.len(), recipients_overhead(2) + 18);
+        assert_eq!(key_source(&sealed), Some(KeySource::Recipients));
+
+        assert_eq!(decrypt_with_identity(&sealed, &alice).unwrap(), b"for your eyes only");
+        assert_eq!(decrypt_with_identity(&sealed, &bob).unwrap(), b"for your eyes only");
+    }
+
+    #[test]
+    fn test_recipients_wrong_identity() {
+        let alice = Identity::generate();
+        let eve = Identity::generate();
+
+        let sealed = encrypt_to_recipients(b"for your eyes only", &[alice.recipient()]).unwrap();
+        assert!(matches!(decrypt_with_identity(&sealed, &eve), Err(Error::DecryptionFailed)));
+    }
+
+    #[test]
+    fn test_recipients_tampered_stanza() {
+        let alice = Identity::generate();
+        let mut sealed = encrypt_to_recipients(b"for your eyes only", &[alice.recipient()]).unwrap();
+        // Last byte of the wrapped key
+        sealed[PREFIX_LEN + STANZA_LEN] ^= 1;
+        assert!(matches!(decrypt_with_identity(&sealed, &alice), Err(Error::DecryptionFailed)));
+    }
+
+    #[test]
+    fn test_recipients_truncated_data() {
+        let alice = Identity::generate();
+        let sealed = encrypt_to_recipients(b"for your eyes only", &[alice.recipient()]).unwrap();
+        for end in 0..sealed.len() {
+            assert!(decrypt_with_identity(&sealed[..end], &alice).is_err());
+        }
+    }
+
+    #[test]
+    fn test_no_recipients() {
+        assert!(matches!(encrypt_to_recipients(b"message", &[]), Err(Error::InvalidKey { .. })));
+    }
+
+    #[test]
+    fn test_mismatched_key_source() {
+        let alice = Identity::generate();
+        let sealed = encrypt_to_recipients(b"message", &[alice.recipient()]).unwrap();
+        assert!(matches!(decrypt(&sealed, b"hunter2"), Err(Error::InvalidPayload { .. })));
+
+        let sealed = sealed_message();
+        assert!(matches!(decrypt_with_identity(&sealed, &alice), Err(Error::InvalidPayload { .. })));
+    }
+
+    #[test]
+    fn test_key_serialization() {
+        let identity = Identity::generate();
+        let recipient = identity.recipient();
+
+        let key_file = format!("# public key: {}\n{}\n", recipient, identity);
+        let parsed = Identity::from_str(&key_file).unwrap();
+        assert_eq!(parsed.recipient(), recipient);
+
+        let parsed = Recipient::from_str(&recipient.to_string()).unwrap();
+        assert_eq!(parsed, recipient);
+
+        assert!(Recipient::from_str("pngme-pk-1234").is_err());
+        assert!(Recipient::from_str(&identity.to_string()).is_err());
+        assert!(Identity::from_str("").is_err());
+    }
+}
@@ -0,0 +1,226 @@
+//! The error type shared by the whole crate.
+
+use std::fmt::Display;
+
+/// Where in the input an error was found.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Location {
+    /// Byte offset from the start of the input.
+    pub offset: u64,
+    /// Index of the chunk the error belongs to, if any.
+    pub chunk_index: Option<usize>,
+}
+
+impl Location {
+    /// A location at `offset` that doesn't belong to any chunk.
+    pub fn at(offset: u64) -> Self {
+        Self {
+            offset,
+            chunk_index: None,
+        }
+    }
+
+    /// A location at `offset` inside the chunk at `chunk_index`.
+    pub fn in_chunk(offset: u64, chunk_index: usize) -> Self {
+        Self {
+            offset,
+            chunk_index: Some(chunk_index),
+        }
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "byte {}", self.offset)?;
+        if let Some(index) = self.chunk_index {
+            write!(f, " (chunk {})", index)?;
+        }
+        Ok(())
+    }
+}
+
+/// Everything that can go wrong while reading, editing or writing a PNG.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The input doesn't start with the 8 byte PNG signature.
+    InvalidHeader { location: Location, found: Vec<u8> },
+    /// A chunk declares more data than the input holds.
+    InvalidChunkLength {
+        location: Location,
+        length: u64,
+        available: u64,
+    },
+    /// The CRC stored in a chunk doesn't match its type and data.
+    InvalidCrc {
+        location: Location,
+        expected: u32,
+        actual: u32,
+    },
+    /// A chunk type is not made of four ASCII letters.
+    InvalidChunkType { location: Location, bytes: Vec<u8> },
+    /// Chunks appear in an order the operation can't work with.
+    InvalidChunkOrder { location: Location, reason: String },
+    /// Chunk data was expected to be UTF-8 text but isn't.
+    InvalidUtf8 { location: Location, source: std::str::Utf8Error },
+    /// Data stored inside a chunk doesn't follow the expected format.
+    InvalidPayload { location: Location, reason: String },
+    /// Authenticated decryption failed: the key is wrong or the data was
+    /// tampered with.
+    DecryptionFailed,
+    /// A key couldn't be parsed or used.
+    InvalidKey { reason: String },
+    /// No chunk of the requested type exists.
+    ChunkNotFound { chunk_type: String },
+    /// Reading or writing the underlying file failed.
+    Io {
+        location: Location,
+        source: std::io::Error,
+    },
+}
+
+impl Error {
+    /// Where the error happened, for errors that point into the input.
+    pub fn location(&self) -> Option<Location> {
+        match self {
+            Self::InvalidHeader { location, .. }
+            | Self::InvalidChunkLength { location, .. }
+            | Self::InvalidCrc { location, .. }
+            | Self::InvalidChunkType { location, .. }
+            | Self::InvalidChunkOrder { location, .. }
+            | Self::InvalidUtf8 { location, .. }
+            | Self::InvalidPayload { location, .. }
+            | Self::Io { location, .. } => Some(*location),
+            Self::DecryptionFailed | Self::InvalidKey { .. } | Self::ChunkNotFound { .. } => None,
+        }
+    }
+
+    fn location_mut(&mut self) -> Option<&mut Location> {
+        match self {
+            Self::InvalidHeader { location, .. }
+            | Self::InvalidChunkLength { location, .. }
+            | Self::InvalidCrc { location, .. }
+            | Self::InvalidChunkType { location, .. }
+            | Self::InvalidChunkOrder { location, .. }
+            | Self::InvalidUtf8 { location, .. }
+            | Self::InvalidPayload { location, .. }
+            | Self::Io { location, .. } => Some(location),
+            Self::DecryptionFailed | Self::InvalidKey { .. } | Self::ChunkNotFound { .. } => None,
+        }
+    }
+
+    /// Moves an error found in a sub-slice to its position in the whole
+    /// input: `base` is added to the offset and the chunk index is set if
+    /// it wasn't already.
+    pub(crate) fn relocate(mut self, base: u64, chunk_index: Option<usize>) -> Self {
+        if let Some(location) = self.location_mut() {
+            location.offset += base;
+            location.chunk_index = location.chunk_index.or(chunk_index);
+        }
+        self
+    }
+
+    /// An I/O error met at `offset`.
+    pub(crate) fn io(offset: u64, source: std::io::Error) -> Self {
+        Self::Io {
+            location: Location::at(offset),
+            source,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidHeader { location, found } => {
+                write!(f, "Invalid PNG signature {:?} at {}", found, location)
+            }
+            Self::InvalidChunkLength {
+                location,
+                length,
+                available,
+            } => write!(
+                f,
+                "Invalid chunk length {} at {}, only {} bytes available",
+                length, location, available
+            ),
+            Self::InvalidCrc {
+                location,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Invalid crc at {}: stored {}, computed {}",
+                location, expected, actual
+            ),
+            Self::InvalidChunkType { location, bytes } => {
+                let chunk_type = String::from_utf8_lossy(bytes);
+                write!(f, "Invalid chunk type {:?} at {}", chunk_type, location)
+            }
+            Self::InvalidChunkOrder { location, reason } => {
+                write!(f, "Invalid chunk order at {}: {}", location, reason)
+            }
+            Self::InvalidUtf8 { location, source } => {
+                write!(f, "Invalid UTF-8 at {}: {}", location, source)
+            }
+            Self::InvalidPayload { location, reason } => {
+                write!(f, "Invalid payload at {}: {}", location, reason)
+            }
+            Self::DecryptionFailed => {
+                write!(f, "Decryption failed: wrong key or the data was tampered with")
+            }
+            Self::InvalidKey { reason } => write!(f, "Invalid key: {}", reason),
+            Self::ChunkNotFound { chunk_type } => {
+                write!(f, "Chunk type {} not found", chunk_type)
+            }
+            Self::Io { location, source } => write!(f, "I/O error at {}: {}", location, source),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::InvalidUtf8 { source, .. } => Some(source),
+            Self::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relocate() {
+        let error = Error::InvalidCrc {
+            location: Location::at(50),
+            expected: 1,
+            actual: 2,
+        };
+
+        let error = error.relocate(33, Some(2));
+        assert_eq!(error.location(), Some(Location::in_chunk(83, 2)));
+
+        let error = error.relocate(8, Some(7));
+        assert_eq!(error.location(), Some(Location::in_chunk(91, 2)));
+    }
+
+    #[test]
+    fn test_display_includes_location() {
+        let error = Error::InvalidChunkType {
+            location: Location::in_chunk(12, 1),
+            bytes: b"ru5t".to_vec(),
+        };
+        assert!(error.to_string().contains("byte 12 (chunk 1)"));
+    }
+
+    #[test]
+    fn test_chunk_not_found_has_no_location() {
+        let error = Error::ChunkNotFound {
+            chunk_type: "ruSt".to_string(),
+        };
+        assert_eq!(error.location(), None);
+    }
+}
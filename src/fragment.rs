@@ -0,0 +1,402 @@
+//! Splitting of hidden data across several chunks of the same type.
+//!
+//! Every fragment is stored in its own chunk and starts with a header:
+//!
+//! | Field                          | Size      |
+//! |--------------------------------|-----------|
+//! | Magic `pmFR`                   | 4         |
+//! | Sequence number, from 0        | 4         |
+//! | Total number of fragments      | 4         |
+//! | SHA-256 of the whole data      | 32        |
+//! | Fragment data                  | remaining |
+//!
+//! Fragments can be stored in any order; [`reassemble`] sorts them and
+//! checks that none are missing, duplicated or altered.
+
+use sha2::{Digest, Sha256};
+
+use crate::chunk::Chunk;
+use crate::cursor::Cursor;
+use crate::png::Png;
+use crate::{Error, Location, Result};
+
+const MAGIC: [u8; 4] = *b"pmFR";
+
+/// Number of bytes the header adds to every fragment.
+pub const FRAGMENT_OVERHEAD: usize = MAGIC.len() + 4 + 4 + 32;
+
+/// Largest amount of data a single fragment can carry.
+pub const MAX_FRAGMENT_SIZE: usize = Chunk::MAX_LENGTH as usize - FRAGMENT_OVERHEAD;
+
+/// How many missing fragments are named when reassembly fails.
+const MISSING_LISTED: usize = 8;
+
+/// Whether `data` starts with a fragment header.
+pub fn is_fragment(data: &[u8]) -> bool {
+    data.starts_with(&MAGIC)
+}
+
+/// Splits `data` into fragments carrying at most `fragment_size` bytes
+/// each. Every fragment is meant to be stored in a chunk of its own.
+pub fn split(data: &[u8], fragment_size: usize) -> Result<Vec<Vec<u8>>> {
+    if fragment_size == 0 || fragment_size > MAX_FRAGMENT_SIZE {
+        return Err(Error::InvalidPayload {
+            location: Location::default(),
+            reason: format!(
+                "fragment size must be between 1 and {} bytes",
+                MAX_FRAGMENT_SIZE
+            ),
+        });
+    }
+
+    let pieces: Vec<&[u8]> = if data.is_empty() {
+        vec![data]
+    } else {
+        data.chunks(fragment_size).collect()
+    };
+    let total = u32::try_from(pieces.len()).map_err(|_| Error::InvalidPayload {
+        location: Location::default(),
+        reason: "too many fragments".to_string(),
+    })?;
+    let digest = Sha256::digest(data);
+
+    let fragments = pieces
+        .into_iter()
+        .zip(0u32..)
+        .map(|(piece, sequence)| {
+            let mut fragment = Vec::with_capacity(FRAGMENT_OVERHEAD + piece.len());
+            fragment.extend_from_slice(&MAGIC);
+            fragment.extend_from_slice(&sequence.to_be_bytes());
+            fragment.extend_from_slice(&total.to_be_bytes());
+            fragment.extend_from_slice(&digest);
+            fragment.extend_from_slice(piece);
+            fragment
+        })
+        .collect();
+
+    Ok(fragments)
+}
+
+struct Fragment<'a> {
+    location: Location,
+    sequence: u32,
+    total: u32,
+    digest: [u8; 32],
+    data: &'a [u8],
+}
+
+impl<'a> Fragment<'a> {
+    /// Parses a fragment whose data starts at `location`.
+    fn parse(data: &'a [u8], location: Location) -> Result<Self> {
+        let mut cursor = Cursor::new(data);
+        let parsed = (|| {
+            if cursor.read_array::<4>()? != MAGIC {
+                return Err(Error::InvalidPayload {
+                    location: Location::at(0),
+                    reason: "not a fragment".to_string(),
+                });
+            }
+            let sequence = cursor.read_u32()?;
+            let total = cursor.read_u32()?;
+            let digest = cursor.read_array()?;
+
+            if sequence >= total {
+                return Err(Error::InvalidPayload {
+                    location: Location::at(4),
+                    reason: format!("fragment {} of only {}", sequence, total),
+                });
+            }
+
+            Ok(Self {
+                location,
+                sequence,
+                total,
+                digest,
+                data: cursor.rest(),
+            })
+        })();
+
+        parsed.map_err(|e| e.relocate(location.offset, location.chunk_index))
+    }
+
+    fn error(&self, reason: String) -> Error {
+        Error::InvalidPayload {
+            location: self.location,
+            reason,
+        }
+    }
+}
+
+/// Puts fragments produced by [`split`] back together, in whatever order
+/// they are given. Errors are located inside the fragment at fault, using
+/// its index in `fragments` as the chunk index.
+pub fn reassemble(fragments: &[&[u8]]) -> Result<Vec<u8>> {
+    let fragments = fragments
+        .iter()
+        .enumerate()
+        .map(|(index, data)| Fragment::parse(data, Location::in_chunk(0, index)))
+        .collect::<Result<Vec<_>>>()?;
+
+    // Without a file, nothing bounds the total but its own size
+    join(fragments, u32::MAX)
+}
+
+/// Sorts and checks `fragments`, rejecting a total above `max_total`.
+fn join(mut fragments: Vec<Fragment>, max_total: u32) -> Result<Vec<u8>> {
+    let first = match fragments.first() {
+        Some(first) => first,
+        None => {
+            return Err(Error::InvalidPayload {
+                location: Location::default(),
+                reason: "no fragments".to_string(),
+            })
+        }
+    };
+    let (total, digest) = (first.total, first.digest);
+
+    if let Some(other) = fragments
+        .iter()
+        .find(|fragment| fragment.total != total || fragment.digest != digest)
+    {
+        return Err(other.error("fragment belongs to a different payload".to_string()));
+    }
+    if total > max_total {
+        return Err(first.error(format!(
+            "{} fragments can't fit in the file, at most {} can",
+            total, max_total
+        )));
+    }
+
+    fragments.sort_by_key(|fragment| fragment.sequence);
+    if let Some(pair) = fragments
+        .windows(2)
+        .find(|pair| pair[0].sequence == pair[1].sequence)
+    {
+        return Err(pair[1].error(format!("fragment {} appears more than once", pair[1].sequence)));
+    }
+
+    if fragments.len() != total as usize {
+        // Only the gaps below the last fragment present are listed: the
+        // total comes from the file and may be anything
+        let missing = total as usize - fragments.len();
+        let listed: Vec<String> = fragments
+            .iter()
+            .scan(0, |next, fragment| {
+                let gap = *next..fragment.sequence;
+                *next = fragment.sequence + 1;
+                Some(gap)
+            })
+            .flatten()
+            .take(MISSING_LISTED)
+            .map(|sequence| sequence.to_string())
+            .collect();
+
+        let mut reason = format!("missing {} fragment(s) of {}", missing, total);
+        if !listed.is_empty() {
+            reason += &format!(": {}", listed.join(", "));
+            if missing > listed.len() {
+                reason += ", ...";
+            }
+        }
+        return Err(fragments[0].error(reason));
+    }
+
+    let data: Vec<u8> = fragments
+        .iter()
+        .flat_map(|fragment| fragment.data)
+        .copied()
+        .collect();
+    if Sha256::digest(&data)[..] != digest[..] {
+        return Err(fragments[0].error("reassembled data doesn't match its digest".to_string()));
+    }
+
+    Ok(data)
+}
+
+/// Reads the data hidden in chunks of `chunk_type`, reassembling it when it
+/// was split into fragments.
+///
+/// Unfragmented data is read from the first chunk of the type, as earlier
+/// versions did.
+pub fn extract(png: &Png, chunk_type: &str) -> Result<Vec<u8>> {
+    let first = png.chunk_by_type(chunk_type).ok_or_else(|| Error::ChunkNotFound {
+        chunk_type: chunk_type.to_string(),
+    })?;
+    if !is_fragment(first.data()) {
+        return Ok(first.data().to_vec());
+    }
+
+    let mut offset = Png::STANDARD_HEADER.len() as u64;
+    let mut fragments = Vec::new();
+    for (index, chunk) in png.chunks().iter().enumerate() {
+        let data_offset = offset + Chunk::DATA_OFFSET as u64;
+        offset += (chunk.length() + Chunk::METADATA_BYTES_LEN) as u64;
+        if chunk.chunk_type().to_string() == chunk_type {
+            fragments.push(Fragment::parse(chunk.data(), Location::in_chunk(data_offset, index))?);
+        }
+    }
+
+    // Every fragment takes a chunk of its own, header included
+    let max_total = png.encoded_len() / (Chunk::METADATA_BYTES_LEN + FRAGMENT_OVERHEAD) as u64;
+    join(fragments, u32::try_from(max_total).unwrap_or(u32::MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::chunk_type::ChunkType;
+
+    fn testing_data() -> Vec<u8> {
+        (0..=255).cycle().take(1000).collect()
+    }
+
+    fn fragment_refs(fragments: &[Vec<u8>]) -> Vec<&[u8]> {
+        fragments.iter().map(Vec::as_slice).collect()
+    }
+
+    #[test]
+    fn test_split_and_reassemble() {
+        let data = testing_data();
+        let fragments = split(&data, 300).unwrap();
+
+        assert_eq!(fragments.len(), 4);
+        assert!(fragments.iter().all(|fragment| is_fragment(fragment)));
+        assert_eq!(fragments[3].len(), FRAGMENT_OVERHEAD + 100);
+        assert_eq!(reassemble(&fragment_refs(&fragments)).unwrap(), data);
+    }
+
+    #[test]
+    fn test_reassemble_out_of_order() {
+        let data = testing_data();
+        let mut fragments = split(&data, 128).unwrap();
+        fragments.reverse();
+        fragments.swap(1, 5);
+
+        assert_eq!(reassemble(&fragment_refs(&fragments)).unwrap(), data);
+    }
+
+    #[test]
+    fn test_split_empty_data() {
+        let fragments = split(&[], 10).unwrap();
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(reassemble(&fragment_refs(&fragments)).unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_split_rejects_zero_size() {
+        assert!(split(b"data", 0).is_err());
+    }
+
+    #[test]
+    fn test_missing_fragment() {
+        let mut fragments = split(&testing_data(), 100).unwrap();
+        fragments.remove(3);
+        fragments.remove(6);
+
+        let error = reassemble(&fragment_refs(&fragments)).unwrap_err();
+        assert!(error.to_string().contains("missing 2 fragment(s) of 10: 3, 7"));
+
+        // Missing fragments past the last one present are only counted
+        fragments.truncate(4);
+        let error = reassemble(&fragment_refs(&fragments)).unwrap_err();
+        assert!(error.to_string().contains("missing 6 fragment(s) of 10: 3, ..."));
+    }
+
+    #[test]
+    fn test_huge_total() {
+        let mut fragment = split(b"data", 10).unwrap().remove(0);
+        fragment[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+
+        let error = reassemble(&[&fragment]).unwrap_err();
+        assert!(error.to_string().contains(&format!("missing {} fragment(s)", u32::MAX - 1)));
+
+        let chunk_type = ChunkType::from_str("ruSt").unwrap();
+        let png = Png::from_chunks(vec![Chunk::new(chunk_type, fragment)]);
+        let error = extract(&png, "ruSt").unwrap_err();
+        assert!(error.to_string().contains("can't fit in the file"));
+    }
+
+    #[test]
+    fn test_duplicate_fragment() {
+        let mut fragments = split(&testing_data(), 100).unwrap();
+        fragments.push(fragments[2].clone());
+
+        match reassemble(&fragment_refs(&fragments)) {
+            Err(Error::InvalidPayload { location, reason }) => {
+                assert!(reason.contains("fragment 2 appears more than once"));
+                assert!(matches!(location.chunk_index, Some(2) | Some(10)));
+            }
+            other => panic!("expected payload error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_altered_fragment() {
+        let mut fragments = split(&testing_data(), 100).unwrap();
+        let last = fragments[4].len() - 1;
+        fragments[4][last] ^= 1;
+
+        let error = reassemble(&fragment_refs(&fragments)).unwrap_err();
+        assert!(error.to_string().contains("doesn't match its digest"));
+    }
+
+    #[test]
+    fn test_fragments_of_different_payloads() {
+        let mut fragments = split(&testing_data(), 500).unwrap();
+        fragments[1] = split(b"something else", 500).unwrap().remove(0);
+
+        match reassemble(&fragment_refs(&fragments)) {
+            Err(Error::InvalidPayload { location, .. }) => {
+                assert_eq!(location, Location::in_chunk(0, 1))
+            }
+            other => panic!("expected payload error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_truncated_fragment() {
+        let fragments = split(&testing_data(), 500).unwrap();
+        let truncated = &fragments[1][..20];
+
+        match reassemble(&[&fragments[0], truncated]) {
+            Err(Error::InvalidPayload { location, .. }) => {
+                assert_eq!(location, Location::in_chunk(12, 1))
+            }
+            other => panic!("expected payload error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_extract_from_png() {
+        let chunk_type = ChunkType::from_str("ruSt").unwrap();
+        let data = testing_data();
+
+        let mut png = Png::from_chunks(vec![Chunk::new(ChunkType::from_str("teSt").unwrap(), vec![1])]);
+        for fragment in split(&data, 333).unwrap().into_iter().rev() {
+            png.append_chunk(Chunk::new(chunk_type.clone(), fragment));
+        }
+
+        assert_eq!(extract(&png, "ruSt").unwrap(), data);
+        assert_eq!(extract(&png, "teSt").unwrap(), vec![1]);
+        assert!(matches!(extract(&png, "miSs"), Err(Error::ChunkNotFound { .. })));
+    }
+
+    #[test]
+    fn test_extract_locates_errors_in_png() {
+        let chunk_type = ChunkType::from_str("ruSt").unwrap();
+        let mut png = Png::from_chunks(Vec::new());
+        for mut fragment in split(&testing_data(), 600).unwrap() {
+            fragment.truncate(30);
+            png.append_chunk(Chunk::new(chunk_type.clone(), fragment));
+        }
+
+        match extract(&png, "ruSt") {
+            Err(Error::InvalidPayload { location, .. }) => {
+                assert_eq!(location, Location::in_chunk(8 + 8 + 12, 0))
+            }
+            other => panic!("expected payload error, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,332 @@
+//! The image header: dimensions, pixel format and interlacing.
+
+use std::fmt::Display;
+use std::str::FromStr;
+
+use crate::chunk::Chunk;
+use crate::chunk_type::ChunkType;
+use crate::{Error, Location, Result};
+
+/// How pixels are stored, as given by the colour type field of IHDR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorType {
+    Grayscale,
+    Truecolor,
+    Indexed,
+    GrayscaleAlpha,
+    TruecolorAlpha,
+}
+
+impl ColorType {
+    /// The value stored in IHDR.
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::Grayscale => 0,
+            Self::Truecolor => 2,
+            Self::Indexed => 3,
+            Self::GrayscaleAlpha => 4,
+            Self::TruecolorAlpha => 6,
+        }
+    }
+
+    /// Number of samples per pixel.
+    pub fn channels(&self) -> usize {
+        match self {
+            Self::Grayscale | Self::Indexed => 1,
+            Self::GrayscaleAlpha => 2,
+            Self::Truecolor => 3,
+            Self::TruecolorAlpha => 4,
+        }
+    }
+
+    /// Bit depths the PNG spec allows for this colour type.
+    pub fn allowed_bit_depths(&self) -> &'static [u8] {
+        match self {
+            Self::Grayscale => &[1, 2, 4, 8, 16],
+            Self::Indexed => &[1, 2, 4, 8],
+            Self::Truecolor | Self::GrayscaleAlpha | Self::TruecolorAlpha => &[8, 16],
+        }
+    }
+}
+
+impl TryFrom<u8> for ColorType {
+    type Error = u8;
+
+    fn try_from(code: u8) -> std::result::Result<Self, u8> {
+        match code {
+            0 => Ok(Self::Grayscale),
+            2 => Ok(Self::Truecolor),
+            3 => Ok(Self::Indexed),
+            4 => Ok(Self::GrayscaleAlpha),
+            6 => Ok(Self::TruecolorAlpha),
+            other => Err(other),
+        }
+    }
+}
+
+impl Display for ColorType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Grayscale => "greyscale",
+            Self::Truecolor => "truecolour",
+            Self::Indexed => "indexed-colour",
+            Self::GrayscaleAlpha => "greyscale with alpha",
+            Self::TruecolorAlpha => "truecolour with alpha",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Order in which the pixels are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interlace {
+    None,
+    Adam7,
+}
+
+/// The decoded IHDR chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ihdr {
+    pub width: u32,
+    pub height: u32,
+    pub bit_depth: u8,
+    pub color_type: ColorType,
+    pub interlace: Interlace,
+}
+
+impl Ihdr {
+    /// Length of the IHDR data.
+    pub const LENGTH: usize = 13;
+
+    /// Largest width or height allowed by the PNG spec, 2^31 - 1.
+    pub const MAX_DIMENSION: u32 = (1 << 31) - 1;
+
+    /// Number of bits used by one pixel.
+    pub fn bits_per_pixel(&self) -> usize {
+        self.color_type.channels() * self.bit_depth as usize
+    }
+
+    /// Number of bytes in a row of `width` pixels, rounded up.
+    pub fn row_bytes(&self, width: u32) -> usize {
+        (width as usize * self.bits_per_pixel()).div_ceil(8)
+    }
+
+    /// Checks the fields against the limits and bit depth / colour type
+    /// combinations of the PNG spec. Errors are located in the IHDR chunk.
+    pub fn check(&self) -> Result<()> {
+        let invalid = |field_offset: usize, reason: String| Error::InvalidPayload {
+            location: Location::at((Chunk::DATA_OFFSET + field_offset) as u64),
+            reason,
+        };
+
+        for (offset, name, value) in [(0, "width", self.width), (4, "height", self.height)] {
+            if value == 0 || value > Self::MAX_DIMENSION {
+                return Err(invalid(offset, format!("invalid image {} {}", name, value)));
+            }
+        }
+
+        if !self.color_type.allowed_bit_depths().contains(&self.bit_depth) {
+            return Err(invalid(
+                8,
+                format!(
+                    "bit depth {} is not allowed for {} images",
+                    self.bit_depth, self.color_type
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// The IHDR chunk describing this header.
+    pub fn to_chunk(&self) -> Chunk {
+        let mut data = Vec::with_capacity(Self::LENGTH);
+        data.extend_from_slice(&self.width.to_be_bytes());
+        data.extend_from_slice(&self.height.to_be_bytes());
+        data.push(self.bit_depth);
+        data.push(self.color_type.code());
+        // Compression and filter methods, 0 is the only one defined
+        data.extend_from_slice(&[0, 0]);
+        data.push(match self.interlace {
+            Interlace::None => 0,
+            Interlace::Adam7 => 1,
+        });
+
+        Chunk::new(ChunkType::from_str("IHDR").unwrap(), data)
+    }
+}
+
+impl TryFrom<&Chunk> for Ihdr {
+    type Error = Error;
+
+    /// Parses and checks an IHDR chunk. Errors are located relative to the
+    /// start of the chunk.
+    fn try_from(chunk: &Chunk) -> Result<Self> {
+        let invalid = |field_offset: usize, reason: String| Error::InvalidPayload {
+            location: Location::at((Chunk::DATA_OFFSET + field_offset) as u64),
+            reason,
+        };
+
+        if chunk.chunk_type().bytes() != *b"IHDR" {
+            return Err(Error::InvalidPayload {
+                location: Location::at(4),
+                reason: format!("expected an IHDR chunk, found {}", chunk.chunk_type()),
+            });
+        }
+
+        let data = chunk.data();
+        if data.len() != Self::LENGTH {
+            return Err(invalid(
+                0,
+                format!("IHDR holds {} bytes instead of {}", data.len(), Self::LENGTH),
+            ));
+        }
+
+        let color_type = ColorType::try_from(data[9])
+            .map_err(|code| invalid(9, format!("unknown colour type {}", code)))?;
+        if data[10] != 0 {
+            return Err(invalid(10, format!("unknown compression method {}", data[10])));
+        }
+        if data[11] != 0 {
+            return Err(invalid(11, format!("unknown filter method {}", data[11])));
+        }
+        let interlace = match data[12] {
+            0 => Interlace::None,
+            1 => Interlace::Adam7,
+            other => return Err(invalid(12, format!("unknown interlace method {}", other))),
+        };
+
+        let ihdr = Self {
+            width: u32::from_be_bytes(data[0..4].try_into().unwrap()),
+            height: u32::from_be_bytes(data[4..8].try_into().unwrap()),
+            bit_depth: data[8],
+            color_type,
+            interlace,
+        };
+        ihdr.check()?;
+
+        Ok(ihdr)
+    }
+}
+
+impl Display for Ihdr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}x{}, {}-bit {}, {}",
+            self.width,
+            self.height,
+            self.bit_depth,
+            self.color_type,
+            match self.interlace {
+                Interlace::None => "not interlaced",
+                Interlace::Adam7 => "Adam7 interlaced",
+            }
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png::tests::PNG_FILE;
+    use crate::png::Png;
+
+    fn testing_ihdr() -> Ihdr {
+        Ihdr {
+            width: 640,
+            height: 480,
+            bit_depth: 8,
+            color_type: ColorType::Indexed,
+            interlace: Interlace::Adam7,
+        }
+    }
+
+    fn with_data_bytes(changes: &[(usize, u8)]) -> Chunk {
+        let mut data = testing_ihdr().to_chunk().data().to_vec();
+        for (index, value) in changes {
+            data[*index] = *value;
+        }
+        Chunk::new(ChunkType::from_str("IHDR").unwrap(), data)
+    }
+
+    fn error_offset(chunk: &Chunk) -> u64 {
+        match Ihdr::try_from(chunk) {
+            Err(Error::InvalidPayload { location, .. }) => location.offset,
+            other => panic!("expected payload error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let ihdr = testing_ihdr();
+        let chunk = ihdr.to_chunk();
+        assert_eq!(chunk.length(), Ihdr::LENGTH);
+        assert_eq!(Ihdr::try_from(&chunk).unwrap(), ihdr);
+    }
+
+    #[test]
+    fn test_image_file_header() {
+        let png = Png::try_from(&PNG_FILE[..]).unwrap();
+        let ihdr = png.ihdr().unwrap();
+
+        assert_eq!((ihdr.width, ihdr.height), (50, 50));
+        assert_eq!(ihdr.bit_depth, 8);
+        assert_eq!(ihdr.color_type, ColorType::TruecolorAlpha);
+        assert_eq!(ihdr.interlace, Interlace::None);
+        assert_eq!(ihdr.bits_per_pixel(), 32);
+        assert_eq!(ihdr.to_string(), "50x50, 8-bit truecolour with alpha, not interlaced");
+    }
+
+    #[test]
+    fn test_row_bytes() {
+        let mut ihdr = testing_ihdr();
+        ihdr.color_type = ColorType::Grayscale;
+        ihdr.bit_depth = 1;
+        assert_eq!(ihdr.row_bytes(9), 2);
+
+        ihdr.color_type = ColorType::Truecolor;
+        ihdr.bit_depth = 16;
+        assert_eq!(ihdr.row_bytes(3), 18);
+    }
+
+    #[test]
+    fn test_bit_depth_combinations() {
+        let colour_types = [0, 2, 3, 4, 6];
+        let mut allowed = 0;
+        for code in colour_types {
+            for depth in [1, 2, 4, 8, 16] {
+                let chunk = with_data_bytes(&[(8, depth), (9, code)]);
+                if Ihdr::try_from(&chunk).is_ok() {
+                    allowed += 1;
+                } else {
+                    assert_eq!(error_offset(&chunk), 16);
+                }
+            }
+        }
+        // 5 greyscale, 4 indexed and 2 for each of the other three
+        assert_eq!(allowed, 15);
+    }
+
+    #[test]
+    fn test_invalid_fields() {
+        assert_eq!(error_offset(&with_data_bytes(&[(0, 0x80)])), 8);
+        assert_eq!(error_offset(&with_data_bytes(&[(9, 5)])), 17);
+        assert_eq!(error_offset(&with_data_bytes(&[(10, 1)])), 18);
+        assert_eq!(error_offset(&with_data_bytes(&[(11, 1)])), 19);
+        assert_eq!(error_offset(&with_data_bytes(&[(12, 2)])), 20);
+
+        let mut ihdr = testing_ihdr();
+        ihdr.height = 0;
+        assert_eq!(error_offset(&ihdr.to_chunk()), 12);
+    }
+
+    #[test]
+    fn test_wrong_chunk() {
+        let chunk = Chunk::new(ChunkType::from_str("IDAT").unwrap(), vec![0; 13]);
+        assert_eq!(error_offset(&chunk), 4);
+
+        let chunk = Chunk::new(ChunkType::from_str("IHDR").unwrap(), vec![0; 12]);
+        assert_eq!(error_offset(&chunk), 8);
+    }
+}
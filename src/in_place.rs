@@ -0,0 +1,634 @@
+//! Editing the end of large files in place, without rewriting them.
+//!
+//! Hidden chunks go right before IEND or after it, so adding or removing
+//! them only changes the last bytes of a file. The functions here find
+//! those bytes by seeking from chunk header to chunk header, without
+//! reading the image data, and rewrite only them.
+//!
+//! Every edit is written to a journal next to the file before the file is
+//! touched. An edit cut short by a crash is finished by [`recover`], which
+//! every function here calls first. Readers that mustn't write use
+//! [`read_recovered`] instead.
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::chunk::{Chunk, ChunkRef};
+use crate::chunk_type::ChunkType;
+use crate::png::Png;
+use crate::reader::read_full;
+use crate::{Error, Location, Result};
+
+const JOURNAL_MAGIC: [u8; 8] = *b"pngmeJNL";
+const JOURNAL_SUFFIX: &str = ".pngme-journal";
+
+/// Where a chunk sits in a file, as read from its header alone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkHeader {
+    /// Offset of the length field.
+    pub offset: u64,
+    pub length: u32,
+    pub chunk_type: ChunkType,
+}
+
+impl ChunkHeader {
+    /// Offset of the byte right after the chunk's CRC.
+    pub fn end(&self) -> u64 {
+        self.offset + self.length as u64 + Chunk::METADATA_BYTES_LEN as u64
+    }
+
+    fn is(&self, chunk_type: &str) -> bool {
+        chunk_type.as_bytes() == self.chunk_type.bytes()
+    }
+}
+
+/// The chunks of a file, found by seeking from header to header. Neither
+/// the data nor the CRC of the chunks is read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    /// Chunks up to and including IEND.
+    pub chunks: Vec<ChunkHeader>,
+    /// Chunks after IEND, empty unless the data there is made of chunks.
+    pub trailing_chunks: Vec<ChunkHeader>,
+    pub file_len: u64,
+}
+
+impl Layout {
+    /// Reads the chunk headers of the PNG in `reader`. Errors are located
+    /// like those of [`Png::try_from`].
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<Self> {
+        let file_len = reader.seek(SeekFrom::End(0)).map_err(|source| Error::io(0, source))?;
+        reader.seek(SeekFrom::Start(0)).map_err(|source| Error::io(0, source))?;
+
+        let mut signature = [0; 8];
+        let read = read_full(reader, &mut signature).map_err(|source| Error::io(0, source))?;
+        if signature[..read] != Png::STANDARD_HEADER {
+            return Err(Error::InvalidHeader {
+                location: Location::at(0),
+                found: signature[..read].to_vec(),
+            });
+        }
+
+        let mut offset = signature.len() as u64;
+        let mut chunks: Vec<ChunkHeader> = Vec::new();
+        while offset < file_len {
+            let header = read_header(reader, offset, file_len)
+                .map_err(|e| e.relocate(offset, Some(chunks.len())))?;
+            offset = header.end();
+            let is_iend = header.is("IEND");
+            chunks.push(header);
+            if is_iend {
+                break;
+            }
+        }
+
+        let mut trailing_chunks = Vec::new();
+        while offset < file_len {
+            match read_header(reader, offset, file_len) {
+                Ok(header) => {
+                    offset = header.end();
+                    trailing_chunks.push(header);
+                }
+                Err(_) => {
+                    trailing_chunks.clear();
+                    break;
+                }
+            }
+        }
+
+        Ok(Self {
+            chunks,
+            trailing_chunks,
+            file_len,
+        })
+    }
+
+    /// Offset of the first byte after the last chunk.
+    pub fn trailing_start(&self) -> u64 {
+        self.chunks
+            .last()
+            .map_or(Png::STANDARD_HEADER.len() as u64, ChunkHeader::end)
+    }
+
+    /// Every chunk, those after IEND included, with its index in the file.
+    fn all_chunks(&self) -> impl Iterator<Item = (usize, &ChunkHeader)> {
+        self.chunks.iter().chain(&self.trailing_chunks).enumerate()
+    }
+
+    fn iend(&self) -> Result<&ChunkHeader> {
+        self.chunks
+            .last()
+            .filter(|header| header.is("IEND"))
+            .ok_or_else(|| Error::InvalidChunkOrder {
+                location: Location::at(self.trailing_start()),
+                reason: "the file doesn't end with IEND".to_string(),
+            })
+    }
+}
+
+/// Reads the header of the chunk at `offset`. Error locations are relative
+/// to the start of the chunk.
+fn read_header<R: Read + Seek>(reader: &mut R, offset: u64, file_len: u64) -> Result<ChunkHeader> {
+    let mut header = [0; Chunk::DATA_OFFSET];
+    reader
+        .seek(SeekFrom::Start(offset))
+        .map_err(|source| Error::io(0, source))?;
+    let read = read_full(reader, &mut header).map_err(|source| Error::io(0, source))?;
+
+    let length = match header.get(..4) {
+        Some(bytes) if read >= 4 => u32::from_be_bytes(bytes.try_into().unwrap()),
+        _ => 0,
+    };
+    let available = (file_len - offset).saturating_sub(Chunk::METADATA_BYTES_LEN as u64);
+    if read < header.len() || length > Chunk::MAX_LENGTH || length as u64 > available {
+        return Err(Error::InvalidChunkLength {
+            location: Location::default(),
+            length: length as u64,
+            available: if read < header.len() { 0 } else { available },
+        });
+    }
+
+    let chunk_type = ChunkType::try_from(<[u8; 4]>::try_from(&header[4..]).unwrap())
+        .map_err(|e| e.relocate(4, None))?;
+    Ok(ChunkHeader {
+        offset,
+        length,
+        chunk_type,
+    })
+}
+
+/// Reads the chunk headers of the PNG at `path`, after finishing any edit
+/// a crash cut short.
+pub fn layout(path: &Path) -> Result<Layout> {
+    recover(path)?;
+    let mut file = File::open(path).map_err(|source| Error::io(0, source))?;
+    Layout::read(&mut file)
+}
+
+/// Inserts `chunks` right before IEND, moving only IEND and the data after
+/// it.
+pub fn insert_before_iend(path: &Path, chunks: &[Chunk]) -> Result<()> {
+    recover(path)?;
+    let mut file = open(path)?;
+    let layout = Layout::read(&mut file)?;
+    let iend = layout.iend()?;
+
+    let old_tail = read_range(&mut file, iend.offset, layout.file_len)?;
+    ChunkRef::try_from(old_tail.as_slice())
+        .map_err(|e| e.relocate(iend.offset, Some(layout.chunks.len() - 1)))?;
+
+    let tail: Vec<u8> = chunks
+        .iter()
+        .flat_map(Chunk::as_bytes)
+        .chain(old_tail)
+        .collect();
+    replace_tail(path, &mut file, iend.offset, tail)
+}
+
+/// Appends `chunks` after everything else, IEND included. Fails if the
+/// file has data after IEND that isn't made of chunks, as the new chunks
+/// couldn't be told apart from it.
+pub fn append(path: &Path, chunks: &[Chunk]) -> Result<()> {
+    recover(path)?;
+    let mut file = open(path)?;
+    let layout = Layout::read(&mut file)?;
+    layout.iend()?;
+
+    if layout.file_len > layout.trailing_start() && layout.trailing_chunks.is_empty() {
+        return Err(Error::InvalidChunkOrder {
+            location: Location::at(layout.trailing_start()),
+            reason: "the data after IEND isn't made of chunks".to_string(),
+        });
+    }
+
+    let tail: Vec<u8> = chunks.iter().flat_map(Chunk::as_bytes).collect();
+    replace_tail(path, &mut file, layout.file_len, tail)
+}
+
+/// Removes the chunks of `chunk_type` that come after the image data,
+/// before or after IEND, and returns them. Only the chunks after the first
+/// one removed are moved.
+///
+/// Fails if a chunk of that type comes before the last IDAT, as removing it
+/// would mean moving the image data.
+pub fn remove_from_tail(path: &Path, chunk_type: &str) -> Result<Vec<Chunk>> {
+    recover(path)?;
+    let mut file = open(path)?;
+    let layout = Layout::read(&mut file)?;
+
+    let start = layout
+        .chunks
+        .iter()
+        .rfind(|header| header.is("IDAT"))
+        .ok_or_else(|| Error::ChunkNotFound {
+            chunk_type: "IDAT".to_string(),
+        })?
+        .end();
+    if let Some((index, header)) = layout
+        .all_chunks()
+        .find(|(_, header)| header.offset < start && header.is(chunk_type))
+    {
+        return Err(Error::InvalidChunkOrder {
+            location: Location::in_chunk(header.offset, index),
+            reason: format!("{} comes before the image data and can't be removed in place", chunk_type),
+        });
+    }
+
+    let old_tail = read_range(&mut file, start, layout.file_len)?;
+    let mut removed = Vec::new();
+    let mut first_removed = None;
+    let mut tail = Vec::new();
+    for (index, header) in layout.all_chunks().filter(|(_, header)| header.offset >= start) {
+        let bytes = &old_tail[(header.offset - start) as usize..(header.end() - start) as usize];
+        let chunk = ChunkRef::try_from(bytes).map_err(|e| e.relocate(header.offset, Some(index)))?;
+
+        if header.is(chunk_type) {
+            first_removed.get_or_insert(header.offset);
+            removed.push(chunk.to_chunk());
+        } else if first_removed.is_some() {
+            tail.extend_from_slice(bytes);
+        }
+    }
+    let first_removed = first_removed.ok_or_else(|| Error::ChunkNotFound {
+        chunk_type: chunk_type.to_string(),
+    })?;
+    if layout.trailing_chunks.is_empty() {
+        tail.extend_from_slice(&old_tail[(layout.trailing_start() - start) as usize..]);
+    }
+
+    replace_tail(path, &mut file, first_removed, tail)?;
+    Ok(removed)
+}
+
+/// Finishes the edit of the file at `path` a crash cut short, if any.
+/// Returns whether there was one to finish.
+///
+/// A journal that was itself cut short is dropped: the file wasn't touched
+/// yet.
+pub fn recover(path: &Path) -> Result<bool> {
+    let journal_path = journal_path(path);
+    let journal = match read_journal(path)? {
+        None => return Ok(false),
+        Some(journal) => journal,
+    };
+
+    if let Some(journal) = &journal {
+        journal.apply(&mut open(path)?)?;
+    }
+    fs::remove_file(&journal_path)
+        .and_then(|_| sync_parent(path))
+        .map_err(|source| Error::io(0, source))?;
+    Ok(journal.is_some())
+}
+
+/// Whether the file at `path` has an edit a crash cut short, which
+/// [`recover`] would finish.
+pub fn has_pending_edit(path: &Path) -> Result<bool> {
+    Ok(matches!(read_journal(path)?, Some(Some(_))))
+}
+
+/// Reads the file at `path` as [`recover`] would leave it, without writing
+/// to it. Also returns whether an edit cut short was finished in what is
+/// read.
+pub fn read_recovered(path: &Path) -> Result<(Vec<u8>, bool)> {
+    let mut bytes = fs::read(path).map_err(|source| Error::io(0, source))?;
+    match read_journal(path)? {
+        Some(Some(journal)) => {
+            bytes.resize(journal.start as usize, 0);
+            bytes.extend_from_slice(&journal.tail);
+            Ok((bytes, true))
+        }
+        _ => Ok((bytes, false)),
+    }
+}
+
+/// Reads the journal of the file at `path`: `None` if there is none,
+/// `Some(None)` if it was itself cut short.
+fn read_journal(path: &Path) -> Result<Option<Option<Journal>>> {
+    match fs::read(journal_path(path)) {
+        Ok(bytes) => Ok(Some(Journal::from_bytes(&bytes))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(source) => Err(Error::io(0, source)),
+    }
+}
+
+/// An edit replacing everything from `start` to the end of a file with
+/// `tail`. Applying it twice gives the same file.
+struct Journal {
+    start: u64,
+    tail: Vec<u8>,
+}
+
+impl Journal {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = JOURNAL_MAGIC.to_vec();
+        bytes.extend_from_slice(&self.start.to_be_bytes());
+        bytes.extend_from_slice(&(self.tail.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(&self.tail);
+        let checksum = Sha256::digest(&bytes);
+        bytes.extend_from_slice(&checksum);
+        bytes
+    }
+
+    /// Parses a journal, or returns `None` if it is incomplete.
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (contents, checksum) = bytes.split_at_checked(bytes.len().checked_sub(32)?)?;
+        if Sha256::digest(contents).as_slice() != checksum {
+            return None;
+        }
+
+        let rest = contents.strip_prefix(&JOURNAL_MAGIC)?;
+        let start = u64::from_be_bytes(rest.get(..8)?.try_into().unwrap());
+        let len = u64::from_be_bytes(rest.get(8..16)?.try_into().unwrap());
+        let tail = rest.get(16..)?;
+        if tail.len() as u64 != len {
+            return None;
+        }
+        Some(Self {
+            start,
+            tail: tail.to_vec(),
+        })
+    }
+
+    fn apply(&self, file: &mut File) -> Result<()> {
+        let end = self.start + self.tail.len() as u64;
+        let io_error = |source| Error::io(self.start, source);
+
+        file.seek(SeekFrom::Start(self.start)).map_err(io_error)?;
+        file.write_all(&self.tail).map_err(io_error)?;
+        file.set_len(end).map_err(io_error)?;
+        file.sync_all().map_err(io_error)
+    }
+}
+
+/// Replaces everything from `start` to the end of the file with `tail`,
+/// going through the journal.
+fn replace_tail(path: &Path, file: &mut File, start: u64, tail: Vec<u8>) -> Result<()> {
+    let journal = Journal { start, tail };
+    let journal_path = journal_path(path);
+    let write_journal = || -> io::Result<()> {
+        let mut journal_file = File::options().write(true).create_new(true).open(&journal_path)?;
+        journal_file.write_all(&journal.to_bytes())?;
+        journal_file.sync_all()
+    };
+    write_journal()
+        .and_then(|_| sync_parent(path))
+        .map_err(|source| Error::io(0, source))?;
+
+    journal.apply(file)?;
+
+    fs::remove_file(&journal_path)
+        .and_then(|_| sync_parent(path))
+        .map_err(|source| Error::io(0, source))
+}
+
+fn journal_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(JOURNAL_SUFFIX);
+    PathBuf::from(name)
+}
+
+fn open(path: &Path) -> Result<File> {
+    File::options()
+        .read(true)
+        .write(true)
+        .open(path)
+        .map_err(|source| Error::io(0, source))
+}
+
+fn read_range(file: &mut File, start: u64, end: u64) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    file.seek(SeekFrom::Start(start))
+        .and_then(|_| file.take(end - start).read_to_end(&mut bytes))
+        .map_err(|source| Error::io(start, source))?;
+    Ok(bytes)
+}
+
+/// Makes the creation, removal or renaming of a file next to `path`
+/// durable.
+pub fn sync_parent(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        File::open(parent)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::png::tests::PNG_FILE;
+
+    /// A file in the temporary directory, removed with its journal when
+    /// dropped.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, contents: &[u8]) -> Self {
+            let path = std::env::temp_dir().join(format!("pngme-{}-{}.png", std::process::id(), name));
+            fs::write(&path, contents).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+            let _ = fs::remove_file(journal_path(&self.0));
+        }
+    }
+
+    fn chunk(chunk_type: &str, data: &str) -> Chunk {
+        Chunk::new(ChunkType::from_str(chunk_type).unwrap(), data.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_layout() {
+        let file = TempFile::new("layout", &[&PNG_FILE[..], &chunk("ruSt", "after").as_bytes()].concat());
+        let layout = layout(&file.0).unwrap();
+        let png = Png::try_from(&PNG_FILE[..]).unwrap();
+
+        assert_eq!(layout.chunks.len(), png.chunks().len());
+        for (index, header) in layout.chunks.iter().enumerate() {
+            assert_eq!(header.offset, png.chunk_data_offset(index) - Chunk::DATA_OFFSET as u64);
+            assert_eq!(&header.chunk_type, png.chunks()[index].chunk_type());
+        }
+        assert_eq!(layout.trailing_start(), PNG_FILE.len() as u64);
+        assert_eq!(layout.trailing_chunks.len(), 1);
+        assert_eq!(layout.trailing_chunks[0].end(), layout.file_len);
+    }
+
+    #[test]
+    fn test_layout_errors_match_png() {
+        let mut bytes = PNG_FILE.to_vec();
+        // The type of gAMA
+        bytes[50] = b'1';
+        let file = TempFile::new("layout-errors", &bytes);
+
+        let error = layout(&file.0).unwrap_err();
+        assert_eq!(error.to_string(), Png::try_from(bytes.as_slice()).err().unwrap().to_string());
+    }
+
+    #[test]
+    fn test_insert_before_iend() {
+        let file = TempFile::new("insert", &[&PNG_FILE[..], b"trailing"].concat());
+        let chunks = [chunk("ruSt", "one"), chunk("ruSt", "two")];
+        insert_before_iend(&file.0, &chunks).unwrap();
+
+        let mut expected = Png::try_from(&PNG_FILE[..]).unwrap();
+        for chunk in chunks {
+            expected.insert_before("IEND", chunk).unwrap();
+        }
+        expected.set_trailing_data(b"trailing".to_vec());
+        assert_eq!(fs::read(&file.0).unwrap(), expected.as_bytes());
+        assert!(!journal_path(&file.0).exists());
+    }
+
+    #[test]
+    fn test_append() {
+        let file = TempFile::new("append", &PNG_FILE);
+        append(&file.0, &[chunk("ruSt", "one")]).unwrap();
+        append(&file.0, &[chunk("ruSt", "two")]).unwrap();
+
+        let png = Png::try_from(fs::read(&file.0).unwrap().as_slice()).unwrap();
+        let trailing = png.trailing_chunks().unwrap();
+        assert_eq!(trailing, [chunk("ruSt", "one"), chunk("ruSt", "two")]);
+
+        let file = TempFile::new("append-raw", &[&PNG_FILE[..], b"PK\x03\x04"].concat());
+        assert!(matches!(
+            append(&file.0, &[chunk("ruSt", "one")]),
+            Err(Error::InvalidChunkOrder { .. })
+        ));
+    }
+
+    #[test]
+    fn test_remove_from_tail() {
+        let original = [&PNG_FILE[..], b"trailing"].concat();
+        let file = TempFile::new("remove", &original);
+        insert_before_iend(&file.0, &[chunk("ruSt", "one"), chunk("teSt", "kept"), chunk("ruSt", "two")]).unwrap();
+
+        let removed = remove_from_tail(&file.0, "ruSt").unwrap();
+        assert_eq!(removed, [chunk("ruSt", "one"), chunk("ruSt", "two")]);
+        let png = Png::try_from(fs::read(&file.0).unwrap().as_slice()).unwrap();
+        assert_eq!(png.chunks()[6], chunk("teSt", "kept"));
+        assert_eq!(png.trailing_data(), b"trailing");
+
+        remove_from_tail(&file.0, "teSt").unwrap();
+        assert_eq!(fs::read(&file.0).unwrap(), original);
+
+        assert!(matches!(remove_from_tail(&file.0, "ruSt"), Err(Error::ChunkNotFound { .. })));
+        // Before IDAT
+        assert!(matches!(remove_from_tail(&file.0, "gAMA"), Err(Error::InvalidChunkOrder { .. })));
+    }
+
+    #[test]
+    fn test_remove_trailing_chunks() {
+        let file = TempFile::new("remove-trailing", &PNG_FILE);
+        append(&file.0, &[chunk("ruSt", "after"), chunk("teSt", "kept")]).unwrap();
+
+        assert_eq!(remove_from_tail(&file.0, "ruSt").unwrap(), [chunk("ruSt", "after")]);
+        let png = Png::try_from(fs::read(&file.0).unwrap().as_slice()).unwrap();
+        assert_eq!(png.trailing_chunks().unwrap(), [chunk("teSt", "kept")]);
+    }
+
+    #[test]
+    fn test_recover_finishes_edit() {
+        let file = TempFile::new("recover", &PNG_FILE);
+        let iend = PNG_FILE.len() as u64 - 12;
+        let tail = [&chunk("ruSt", "hidden").as_bytes()[..], &PNG_FILE[iend as usize..]].concat();
+        let journal = Journal { start: iend, tail };
+
+        // A crash halfway through writing the file
+        fs::write(journal_path(&file.0), journal.to_bytes()).unwrap();
+        let mut bytes = PNG_FILE.to_vec();
+        bytes.truncate(iend as usize);
+        bytes.extend_from_slice(&journal.tail[..10]);
+        fs::write(&file.0, &bytes).unwrap();
+
+        // Read as if finished, without touching the file
+        assert!(has_pending_edit(&file.0).unwrap());
+        let (recovered, pending) = read_recovered(&file.0).unwrap();
+        assert!(pending);
+        assert_eq!(fs::read(&file.0).unwrap(), bytes);
+
+        assert!(recover(&file.0).unwrap());
+        assert_eq!(fs::read(&file.0).unwrap(), recovered);
+        let png = Png::try_from(recovered.as_slice()).unwrap();
+        assert_eq!(png.chunks()[6], chunk("ruSt", "hidden"));
+        assert!(!journal_path(&file.0).exists());
+        assert!(!recover(&file.0).unwrap());
+    }
+
+    #[test]
+    fn test_recover_drops_incomplete_journal() {
+        let file = TempFile::new("recover-incomplete", &PNG_FILE);
+        let journal = Journal {
+            start: 0,
+            tail: b"garbage".to_vec(),
+        };
+        let bytes = journal.to_bytes();
+        fs::write(journal_path(&file.0), &bytes[..bytes.len() - 1]).unwrap();
+
+        assert!(!has_pending_edit(&file.0).unwrap());
+        assert_eq!(read_recovered(&file.0).unwrap(), (PNG_FILE.to_vec(), false));
+        assert!(!recover(&file.0).unwrap());
+        assert_eq!(fs::read(&file.0).unwrap(), PNG_FILE);
+        assert!(!journal_path(&file.0).exists());
+    }
+
+    /// Writes a sparse file of over 4 GiB: IHDR, two IDAT chunks of the
+    /// largest length allowed and IEND. The IDAT data is never written, so
+    /// it takes no room on disk.
+    fn huge_file(name: &str) -> TempFile {
+        let png = Png::try_from(&PNG_FILE[..]).unwrap();
+        let file = TempFile::new(name, &[&PNG_FILE[..8], &png.chunks()[0].as_bytes()].concat());
+
+        let mut writer = File::options().write(true).open(&file.0).unwrap();
+        writer.seek(SeekFrom::End(0)).unwrap();
+        for _ in 0..2 {
+            writer.write_all(&Chunk::MAX_LENGTH.to_be_bytes()).unwrap();
+            writer.write_all(b"IDAT").unwrap();
+            writer.seek(SeekFrom::Current(Chunk::MAX_LENGTH as i64)).unwrap();
+            writer.write_all(&[0; 4]).unwrap();
+        }
+        writer.write_all(&png.chunks()[6].as_bytes()).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_huge_file() {
+        let file = huge_file("huge");
+        let len = fs::metadata(&file.0).unwrap().len();
+        assert!(len > 4 << 30);
+
+        let message = chunk("ruSt", "in a huge file");
+        insert_before_iend(&file.0, std::slice::from_ref(&message)).unwrap();
+        let layout = layout(&file.0).unwrap();
+        assert_eq!(layout.file_len, len + message.as_bytes().len() as u64);
+        assert_eq!(layout.chunks.len(), 5);
+        let header = &layout.chunks[3];
+        let mut reader = File::open(&file.0).unwrap();
+        assert_eq!(read_range(&mut reader, header.offset, header.end()).unwrap(), message.as_bytes());
+
+        assert_eq!(remove_from_tail(&file.0, "ruSt").unwrap(), std::slice::from_ref(&message));
+        assert_eq!(fs::metadata(&file.0).unwrap().len(), len);
+
+        append(&file.0, std::slice::from_ref(&message)).unwrap();
+        assert_eq!(remove_from_tail(&file.0, "ruSt").unwrap(), [message]);
+        let mut reader = File::open(&file.0).unwrap();
+        let tail = read_range(&mut reader, len - 12, len).unwrap();
+        assert_eq!(tail, PNG_FILE[PNG_FILE.len() - 12..]);
+    }
+}
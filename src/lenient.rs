@@ -0,0 +1,347 @@
+//! Reading damaged files as far as possible.
+
+use crate::chunk::{Chunk, ChunkRef};
+use crate::chunk_type::ChunkType;
+use crate::png::Png;
+use crate::validate::{self, Diagnostic};
+
+/// How many times over the input CRCs are computed at most while
+/// recovering, so that crafted damage can't make recovery quadratic.
+const CRC_PASSES: usize = 4;
+
+/// Bounds the CRC work done while recovering a file.
+struct CrcBudget {
+    left: usize,
+    spent: bool,
+}
+
+impl CrcBudget {
+    fn new(bytes: &[u8]) -> Self {
+        Self {
+            left: bytes.len().saturating_mul(CRC_PASSES),
+            spent: false,
+        }
+    }
+
+    /// Whether the CRC of `chunk` matches. Always false once the budget is
+    /// spent.
+    fn check(&mut self, chunk: &ChunkRef) -> bool {
+        match self.left.checked_sub(chunk.length() + Chunk::METADATA_BYTES_LEN) {
+            Some(left) => {
+                self.left = left;
+                chunk.has_valid_crc()
+            }
+            None => {
+                self.spent = true;
+                false
+            }
+        }
+    }
+}
+
+/// Whether a chunk that can be trusted starts at `offset`: its type is made
+/// of letters, its length fits in the input and either it is a chunk of the
+/// PNG spec or its CRC matches. The CRC is only computed last.
+fn plausible_chunk_at(bytes: &[u8], offset: usize, budget: &mut CrcBudget) -> bool {
+    match ChunkRef::parse_unchecked(&bytes[offset..]) {
+        Ok(chunk) => validate::is_standard_chunk(&chunk.chunk_type().to_string()) || budget.check(&chunk),
+        Err(_) => false,
+    }
+}
+
+/// Offset of the first plausible chunk at or after `from`.
+fn next_chunk_at(bytes: &[u8], from: usize, budget: &mut CrcBudget) -> Option<usize> {
+    let last = bytes.len().checked_sub(Chunk::METADATA_BYTES_LEN)?;
+    (from..=last).find(|offset| plausible_chunk_at(bytes, *offset, budget))
+}
+
+/// What is read at an offset of a damaged file.
+enum Step {
+    /// A chunk, and the offset of what follows it.
+    Chunk(Chunk, usize),
+    /// Unreadable bytes, up to the offset of the next plausible chunk.
+    Skip(usize),
+    /// Unreadable bytes up to the end.
+    Trailing,
+}
+
+/// Reads the chunk at `pointer`, or finds where to resume reading.
+fn step_at(
+    bytes: &[u8],
+    pointer: usize,
+    index: usize,
+    budget: &mut CrcBudget,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Step {
+    if let Ok(chunk) = ChunkRef::parse_unchecked(&bytes[pointer..]) {
+        let end = pointer + chunk.length() + Chunk::METADATA_BYTES_LEN;
+        let valid = budget.check(&chunk);
+        // A wrong CRC with a sound length means damaged data rather than a
+        // damaged header
+        if valid || end == bytes.len() || plausible_chunk_at(bytes, end, budget) {
+            if !valid {
+                diagnostics.push(Diagnostic::error(
+                    Some(index),
+                    format!(
+                        "CRC mismatch in {} at byte {}, data kept as it is",
+                        chunk.chunk_type(),
+                        end - 4
+                    ),
+                ));
+            }
+            return Step::Chunk(chunk.to_chunk(), end);
+        }
+    }
+
+    let chunk_type = bytes
+        .get(pointer + 4..pointer + 8)
+        .and_then(|bytes| ChunkType::try_from(<[u8; 4]>::try_from(bytes).unwrap()).ok());
+
+    match (next_chunk_at(bytes, pointer + 1, budget), chunk_type) {
+        (Some(next), Some(chunk_type)) if next >= pointer + Chunk::METADATA_BYTES_LEN => {
+            let data = bytes[pointer + Chunk::DATA_OFFSET..next - 4].to_vec();
+            let crc = u32::from_be_bytes(bytes[next - 4..next].try_into().unwrap());
+            let chunk = Chunk::with_crc(chunk_type, data, crc);
+            diagnostics.push(Diagnostic::error(
+                Some(index),
+                format!(
+                    "{} at byte {} has a damaged length, its data runs to the next chunk at byte {}{}",
+                    chunk.chunk_type(),
+                    pointer,
+                    next,
+                    if chunk.has_valid_crc() { "" } else { " and doesn't match its CRC" }
+                ),
+            ));
+            Step::Chunk(chunk, next)
+        }
+        (Some(next), _) => {
+            diagnostics.push(Diagnostic::error(
+                None,
+                format!("skipped {} unreadable bytes at byte {}", next - pointer, pointer),
+            ));
+            Step::Skip(next)
+        }
+        (None, _) => {
+            diagnostics.push(Diagnostic::error(
+                None,
+                format!(
+                    "{} unreadable bytes at byte {} kept as trailing data",
+                    bytes.len() - pointer,
+                    pointer
+                ),
+            ));
+            Step::Trailing
+        }
+    }
+}
+
+impl Png {
+    /// Reads a PNG, recovering what it can from a damaged file instead of
+    /// failing at the first problem, and reports every problem met.
+    ///
+    /// Chunks whose CRC doesn't match are kept as they are. When a chunk
+    /// header can't be read, reading resumes at the next plausible chunk:
+    /// the damaged chunk is kept with the data up to there if its type is
+    /// readable, otherwise its bytes are skipped. Unreadable bytes at the
+    /// end are kept as trailing data, as is everything after the point
+    /// where looking for chunks took too long.
+    pub fn parse_lenient(bytes: &[u8]) -> (Png, Vec<Diagnostic>) {
+        let mut diagnostics = Vec::new();
+        if bytes.get(..8) != Some(&Self::STANDARD_HEADER[..]) {
+            diagnostics.push(Diagnostic::error(None, "invalid PNG signature, read as if it were valid"));
+        }
+
+        let mut budget = CrcBudget::new(bytes);
+        let mut pointer = bytes.len().min(8);
+        let mut chunks: Vec<Chunk> = Vec::new();
+        let mut trailing_data = Vec::new();
+
+        while pointer < bytes.len() {
+            let reported = diagnostics.len();
+            let step = step_at(bytes, pointer, chunks.len(), &mut budget, &mut diagnostics);
+            if budget.spent {
+                // What was found with the budget running out can't be trusted
+                diagnostics.truncate(reported);
+                diagnostics.push(Diagnostic::error(
+                    None,
+                    format!(
+                        "gave up recovering at byte {}, too damaged, {} bytes kept as trailing data",
+                        pointer,
+                        bytes.len() - pointer
+                    ),
+                ));
+                trailing_data = bytes[pointer..].to_vec();
+                break;
+            }
+
+            let chunk = match step {
+                Step::Chunk(chunk, next) => {
+                    pointer = next;
+                    chunk
+                }
+                Step::Skip(next) => {
+                    pointer = next;
+                    continue;
+                }
+                Step::Trailing => {
+                    trailing_data = bytes[pointer..].to_vec();
+                    break;
+                }
+            };
+
+            let is_iend = chunk.chunk_type().bytes() == *b"IEND";
+            chunks.push(chunk);
+            if is_iend {
+                trailing_data = bytes[pointer..].to_vec();
+                break;
+            }
+        }
+
+        let mut png = Png::from_chunks(chunks);
+        png.set_trailing_data(trailing_data);
+        (png, diagnostics)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png::tests::PNG_FILE;
+
+    /// Start of the chunks of [`PNG_FILE`]: IHDR, sRGB, gAMA, pHYs, IDAT,
+    /// RuSt and IEND.
+    const CHUNK_OFFSETS: [usize; 7] = [8, 33, 46, 62, 83, 4776, 4791];
+
+    fn chunk_types(png: &Png) -> Vec<String> {
+        png.chunks().iter().map(|chunk| chunk.chunk_type().to_string()).collect()
+    }
+
+    fn original_types() -> Vec<String> {
+        chunk_types(&Png::try_from(&PNG_FILE[..]).unwrap())
+    }
+
+    #[test]
+    fn test_chunk_offsets() {
+        let png = Png::try_from(&PNG_FILE[..]).unwrap();
+        for (index, offset) in CHUNK_OFFSETS.iter().enumerate() {
+            assert_eq!(png.chunk_data_offset(index) as usize, offset + Chunk::DATA_OFFSET);
+        }
+    }
+
+    #[test]
+    fn test_intact_file() {
+        let (png, diagnostics) = Png::parse_lenient(&PNG_FILE);
+        assert!(diagnostics.is_empty());
+        assert_eq!(png.as_bytes(), PNG_FILE);
+    }
+
+    #[test]
+    fn test_damaged_data_is_kept() {
+        let mut bytes = PNG_FILE.to_vec();
+        // A bit of the RuSt data
+        bytes[CHUNK_OFFSETS[5] + 9] ^= 0x04;
+        assert!(Png::try_from(bytes.as_ref()).is_err());
+
+        let (png, diagnostics) = Png::parse_lenient(&bytes);
+        assert_eq!(chunk_types(&png), original_types());
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].chunk_index, Some(5));
+        assert!(diagnostics[0].message.contains("CRC mismatch in RuSt"));
+        assert!(!png.chunks()[5].has_valid_crc());
+        // The raw bytes are kept
+        assert_eq!(png.as_bytes(), bytes);
+    }
+
+    #[test]
+    fn test_damaged_length_is_recovered() {
+        let original = Png::try_from(&PNG_FILE[..]).unwrap();
+        for length_byte in [2, 3] {
+            let mut bytes = PNG_FILE.to_vec();
+            // The length of IDAT, too small and too large
+            bytes[CHUNK_OFFSETS[4] + length_byte] ^= 0x10;
+
+            let (png, diagnostics) = Png::parse_lenient(&bytes);
+            assert_eq!(chunk_types(&png), original_types());
+            assert_eq!(png.chunks()[4], original.chunks()[4]);
+            assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+            assert!(diagnostics[0].message.contains("damaged length"));
+            assert_eq!(png.pixels().unwrap(), original.pixels().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_garbage_is_skipped() {
+        let garbage = [0xde, 0xad, 0xbe, 0xef, 0x00, 0x01];
+        let bytes = [&PNG_FILE[..CHUNK_OFFSETS[2]], &garbage, &PNG_FILE[CHUNK_OFFSETS[2]..]].concat();
+
+        let (png, diagnostics) = Png::parse_lenient(&bytes);
+        assert_eq!(chunk_types(&png), original_types());
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(
+            diagnostics[0].message,
+            format!("skipped 6 unreadable bytes at byte {}", CHUNK_OFFSETS[2])
+        );
+        assert_eq!(png.as_bytes(), PNG_FILE);
+    }
+
+    #[test]
+    fn test_damaged_signature() {
+        let mut bytes = PNG_FILE.to_vec();
+        bytes[1] = b'J';
+
+        let (png, diagnostics) = Png::parse_lenient(&bytes);
+        assert_eq!(chunk_types(&png), original_types());
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].message.contains("signature"));
+    }
+
+    #[test]
+    fn test_truncated_file() {
+        let bytes = &PNG_FILE[..CHUNK_OFFSETS[4] + 100];
+        let (png, diagnostics) = Png::parse_lenient(bytes);
+
+        assert_eq!(chunk_types(&png), ["IHDR", "sRGB", "gAMA", "pHYs"]);
+        assert_eq!(png.trailing_data().len(), 100);
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].message.contains("kept as trailing data"));
+    }
+
+    #[test]
+    fn test_trailing_data_after_iend() {
+        let bytes = [&PNG_FILE[..], b"appended"].concat();
+        let (png, diagnostics) = Png::parse_lenient(&bytes);
+        assert!(diagnostics.is_empty());
+        assert_eq!(png.trailing_data(), b"appended");
+    }
+
+    #[test]
+    fn test_recovery_work_is_bounded() {
+        // Overlapping fake headers, each claiming all but the last byte of
+        // the input
+        let len = 1 << 16;
+        let mut bytes = PNG_FILE[..8].to_vec();
+        while bytes.len() + Chunk::METADATA_BYTES_LEN < len {
+            let length = (len - bytes.len() - Chunk::METADATA_BYTES_LEN - 1) as u32;
+            bytes.extend_from_slice(&length.to_be_bytes());
+            bytes.extend_from_slice(b"abCd");
+        }
+        bytes.resize(len, 0);
+
+        let (png, diagnostics) = Png::parse_lenient(&bytes);
+        assert!(png.chunks().is_empty());
+        assert!(diagnostics.last().unwrap().message.starts_with("gave up recovering at byte"));
+        assert_eq!(png.as_bytes(), bytes);
+    }
+
+    #[test]
+    fn test_any_single_damage_keeps_most_chunks() {
+        for position in 8..PNG_FILE.len() {
+            let mut bytes = PNG_FILE.to_vec();
+            bytes[position] ^= 0xff;
+
+            let (png, diagnostics) = Png::parse_lenient(&bytes);
+            assert!(!diagnostics.is_empty(), "damage at {} went unnoticed", position);
+            assert!(png.chunks().len() >= 6, "damage at {} lost chunks", position);
+        }
+    }
+}
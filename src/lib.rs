@@ -0,0 +1,50 @@
+//! Read, edit and write the chunks of PNG files.
+//!
+//! A [`Png`](png::Png) is a signature followed by a list of
+//! [`Chunk`](chunk::Chunk)s, each tagged with a four letter
+//! [`ChunkType`](chunk_type::ChunkType). Hiding a message in an image is a
+//! matter of appending an ancillary chunk to it:
+//!
+//! ```
+//! use std::str::FromStr;
+//!
+//! use pngme::chunk::Chunk;
+//! use pngme::chunk_type::ChunkType;
+//! use pngme::png::Png;
+//!
+//! let mut png = Png::from_chunks(Vec::new());
+//! let chunk_type = ChunkType::from_str("ruSt")?;
+//! png.append_chunk(Chunk::new(chunk_type, b"hidden message".to_vec()));
+//!
+//! let png = Png::try_from(png.as_bytes().as_ref())?;
+//! let chunk = png.chunk_by_type("ruSt").unwrap();
+//! assert_eq!(chunk.data_as_string()?, "hidden message");
+//! # Ok::<(), pngme::Error>(())
+//! ```
+
+pub mod analyze;
+pub mod capacity;
+pub mod chunk;
+pub mod chunk_type;
+mod cursor;
+#[cfg(feature = "crypto")]
+pub mod encryption;
+mod error;
+pub mod fragment;
+pub mod ihdr;
+pub mod in_place;
+pub mod lenient;
+pub mod lsb;
+pub mod payload;
+pub mod pixels;
+pub mod png;
+pub mod reader;
+#[cfg(feature = "crypto")]
+pub mod signature;
+pub mod validate;
+pub mod writer;
+
+pub use error::{Error, Location};
+
+/// Result type used throughout the crate.
+pub type Result<T> = std::result::Result<T, Error>;
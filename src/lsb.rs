@@ -0,0 +1,573 @@
+//! Hiding data in the least significant bits of the pixels.
+//!
+//! Unlike ancillary chunks, the data survives tools that strip metadata, as
+//! long as the image is stored losslessly. A header is written first in the
+//! lowest bit of the first channel of the first [`HEADER_PIXELS`] pixels:
+//!
+//! | Field                          | Size |
+//! |--------------------------------|------|
+//! | Magic `pmLB`                   | 4    |
+//! | Version                        | 1    |
+//! | Bits used per channel          | 1    |
+//! | Channel mask                   | 1    |
+//! | Flags                          | 1    |
+//! | Length of the data             | 4    |
+//!
+//! The data follows in the pixels after those, using the low bits of every
+//! channel selected by the mask, most significant bit first.
+//!
+//! Without a key the pixels are used row by row. With a key they are used in
+//! an order derived from it, so the header and the data are spread over the
+//! whole image and can only be found by someone holding the key. The key
+//! only hides where the bits are; encrypt the data to keep it confidential.
+
+use std::collections::HashMap;
+
+use rand_chacha::rand_core::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use sha2::{Digest, Sha256};
+
+use crate::cursor::Cursor;
+use crate::ihdr::{ColorType, Ihdr};
+use crate::pixels::{EncodeOptions, Pixels};
+use crate::png::Png;
+use crate::{Error, Location, Result};
+
+const MAGIC: [u8; 4] = *b"pmLB";
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 12;
+
+/// Flag set when the pixel order is derived from a key.
+const FLAG_KEYED: u8 = 1;
+
+/// Prefix of the hashed key, so the seed is specific to this use.
+const SEED_CONTEXT: &[u8] = b"pngme lsb v1";
+
+/// Number of pixels holding the header, one bit each.
+pub const HEADER_PIXELS: usize = HEADER_LEN * 8;
+
+/// Which bits of the pixels carry the data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LsbOptions {
+    /// Number of low bits used in every selected channel.
+    pub bits_per_channel: u8,
+    /// Bit `i` selects channel `i`, so `0b0111` is the colour of an RGBA
+    /// image. Bits past the channels of the image are ignored.
+    pub channel_mask: u8,
+}
+
+impl Default for LsbOptions {
+    /// One bit of every channel.
+    fn default() -> Self {
+        Self {
+            bits_per_channel: 1,
+            channel_mask: 0xff,
+        }
+    }
+}
+
+fn invalid(reason: String) -> Error {
+    Error::InvalidPayload {
+        location: Location::default(),
+        reason,
+    }
+}
+
+impl LsbOptions {
+    /// The channel mask restricted to the channels of `ihdr`.
+    fn mask_for(&self, ihdr: &Ihdr) -> u8 {
+        self.channel_mask & ((1u16 << ihdr.color_type.channels()) - 1) as u8
+    }
+
+    /// Checks that the options can be used on images described by `ihdr`.
+    fn check(&self, ihdr: &Ihdr) -> Result<()> {
+        if ihdr.color_type == ColorType::Indexed {
+            return Err(invalid(
+                "can't hide data in the pixels of a palette image, it would change their colours"
+                    .to_string(),
+            ));
+        }
+
+        if (ihdr.width as usize * ihdr.height as usize) < HEADER_PIXELS {
+            return Err(invalid(format!(
+                "the image needs at least {} pixels to hold the header",
+                HEADER_PIXELS
+            )));
+        }
+
+        let max_bits = ihdr.bit_depth.min(8);
+        if self.bits_per_channel == 0 || self.bits_per_channel > max_bits {
+            return Err(invalid(format!(
+                "bits per channel must be between 1 and {} for {}-bit images",
+                max_bits, ihdr.bit_depth
+            )));
+        }
+
+        if self.mask_for(ihdr) == 0 {
+            return Err(invalid(format!(
+                "channel mask {:#b} selects none of the {} channels",
+                self.channel_mask,
+                ihdr.color_type.channels()
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Number of bytes of data an image described by `ihdr` can hold, not
+/// counting the header.
+pub fn capacity(ihdr: &Ihdr, options: &LsbOptions) -> usize {
+    if options.check(ihdr).is_err() {
+        return 0;
+    }
+
+    let pixels = ihdr.width as usize * ihdr.height as usize;
+    let channels = options.mask_for(ihdr).count_ones() as usize;
+    pixels.saturating_sub(HEADER_PIXELS) * channels * options.bits_per_channel as usize / 8
+}
+
+/// Pixel indices in a random order derived from a key, a Fisher-Yates
+/// shuffle done one step per item so only the pixels used are drawn.
+///
+/// Only the entries the swaps moved are stored, so drawing a few pixels of
+/// a large image takes little memory.
+struct Shuffle {
+    len: usize,
+    /// Entries moved by the swaps so far, every other index holds itself.
+    swapped: HashMap<usize, usize>,
+    next: usize,
+    rng: ChaCha20Rng,
+}
+
+impl Shuffle {
+    fn new(len: usize, key: &[u8]) -> Self {
+        let seed = Sha256::new().chain_update(SEED_CONTEXT).chain_update(key).finalize();
+        Self {
+            len,
+            swapped: HashMap::new(),
+            next: 0,
+            rng: ChaCha20Rng::from_seed(seed.into()),
+        }
+    }
+
+    /// A uniformly distributed number below `bound`, rejecting the values
+    /// that would make the modulo biased.
+    fn below(&mut self, bound: u64) -> u64 {
+        let threshold = bound.wrapping_neg() % bound;
+        loop {
+            let value = self.rng.next_u64();
+            if value >= threshold {
+                return value % bound;
+            }
+        }
+    }
+}
+
+impl Iterator for Shuffle {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        let remaining = self.len - self.next;
+        if remaining == 0 {
+            return None;
+        }
+        let pick = self.next + self.below(remaining as u64) as usize;
+
+        // Swap the entries at `next` and `pick`, the one at `next` is never
+        // looked at again
+        let current = self.swapped.remove(&self.next).unwrap_or(self.next);
+        let picked = if pick == self.next {
+            current
+        } else {
+            self.swapped.insert(pick, current).unwrap_or(pick)
+        };
+        self.next += 1;
+        Some(picked)
+    }
+}
+
+/// The (x, y) of every pixel in the order they are used: the first
+/// [`HEADER_PIXELS`] hold the header and the rest the data.
+fn pixel_order<'a>(ihdr: &Ihdr, key: Option<&[u8]>) -> Box<dyn Iterator<Item = (u32, u32)> + 'a> {
+    let width = ihdr.width as usize;
+    let pixels = width * ihdr.height as usize;
+    let position = move |pixel: usize| ((pixel % width) as u32, (pixel / width) as u32);
+
+    match key {
+        Some(key) => Box::new(Shuffle::new(pixels, key).map(position)),
+        None => Box::new((0..pixels).map(position)),
+    }
+}
+
+/// The (x, y, channel) of every sample carrying data, in order.
+fn data_samples<'a>(
+    ihdr: &Ihdr,
+    mask: u8,
+    key: Option<&[u8]>,
+) -> impl Iterator<Item = (u32, u32, usize)> + 'a {
+    let channels = ihdr.color_type.channels();
+
+    pixel_order(ihdr, key).skip(HEADER_PIXELS).flat_map(move |(x, y)| {
+        (0..channels)
+            .filter(move |channel| mask & (1 << channel) != 0)
+            .map(move |channel| (x, y, channel))
+    })
+}
+
+/// The bits of `bytes`, most significant first.
+fn bits(bytes: &[u8]) -> impl Iterator<Item = u16> + '_ {
+    bytes
+        .iter()
+        .flat_map(|byte| (0..8).rev().map(move |bit| ((byte >> bit) & 1) as u16))
+}
+
+/// Hides `data` in the pixels, using the pixels in an order derived from
+/// `key` when one is given.
+///
+/// Fails for palette images, invalid options, or data larger than
+/// [`capacity`].
+pub fn embed(pixels: &mut Pixels, data: &[u8], options: &LsbOptions, key: Option<&[u8]>) -> Result<()> {
+    let ihdr = *pixels.ihdr();
+    options.check(&ihdr)?;
+
+    let available = capacity(&ihdr, options);
+    let length = u32::try_from(data.len()).ok().filter(|len| *len as usize <= available);
+    let length = length.ok_or_else(|| {
+        invalid(format!(
+            "{} bytes don't fit in the {} bytes the pixels can hold",
+            data.len(),
+            available
+        ))
+    })?;
+
+    let mask = options.mask_for(&ihdr);
+    let flags = if key.is_some() { FLAG_KEYED } else { 0 };
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(&MAGIC);
+    header.extend_from_slice(&[VERSION, options.bits_per_channel, mask, flags]);
+    header.extend_from_slice(&length.to_be_bytes());
+
+    for ((x, y), bit) in pixel_order(&ihdr, key).zip(bits(&header)) {
+        let sample = pixels.sample(x, y, 0);
+        pixels.set_sample(x, y, 0, (sample & !1) | bit);
+    }
+
+    let width = options.bits_per_channel as usize;
+    let low_bits = (1u16 << width) - 1;
+    let mut data_bits = bits(data).peekable();
+    for (x, y, channel) in data_samples(&ihdr, mask, key) {
+        if data_bits.peek().is_none() {
+            break;
+        }
+        // Missing bits at the very end are zeros
+        let value = (0..width).fold(0, |value, _| (value << 1) | data_bits.next().unwrap_or(0));
+        let sample = pixels.sample(x, y, channel);
+        pixels.set_sample(x, y, channel, (sample & !low_bits) | value);
+    }
+
+    Ok(())
+}
+
+/// Reads the header, if the pixels start with one in the order given by
+/// `key`.
+fn read_header(pixels: &Pixels, key: Option<&[u8]>) -> Option<[u8; HEADER_LEN]> {
+    let ihdr = pixels.ihdr();
+    if ihdr.color_type == ColorType::Indexed
+        || (ihdr.width as usize * ihdr.height as usize) < HEADER_PIXELS
+    {
+        return None;
+    }
+
+    let mut header = [0; HEADER_LEN];
+    for (i, (x, y)) in pixel_order(ihdr, key).take(HEADER_PIXELS).enumerate() {
+        header[i / 8] |= ((pixels.sample(x, y, 0) & 1) as u8) << (7 - i % 8);
+    }
+
+    header.starts_with(&MAGIC).then_some(header)
+}
+
+/// Whether the pixels hold a header written by [`embed`] with the same
+/// `key`.
+pub fn is_embedded(pixels: &Pixels, key: Option<&[u8]>) -> bool {
+    read_header(pixels, key).is_some()
+}
+
+/// Reads data hidden by [`embed`] with the same `key`, using the options
+/// stored with it.
+pub fn extract(pixels: &Pixels, key: Option<&[u8]>) -> Result<Vec<u8>> {
+    let header = read_header(pixels, key).ok_or_else(|| invalid("no data hidden in the pixels".to_string()))?;
+
+    let mut cursor = Cursor::new(&header);
+    cursor.read_array::<4>()?;
+    let version = cursor.read_u8()?;
+    if version != VERSION {
+        return Err(cursor.error(format!("unsupported LSB version {}", version)));
+    }
+    let options = LsbOptions {
+        bits_per_channel: cursor.read_u8()?,
+        channel_mask: cursor.read_u8()?,
+    };
+    let flags = cursor.read_u8()?;
+    if flags & !FLAG_KEYED != 0 || (flags & FLAG_KEYED != 0) != key.is_some() {
+        return Err(cursor.error(format!("unsupported LSB flags {:#04x}", flags)));
+    }
+    let length = cursor.read_u32()? as usize;
+
+    let ihdr = pixels.ihdr();
+    options.check(ihdr)?;
+    if length > capacity(ihdr, &options) {
+        return Err(invalid(format!(
+            "header claims {} bytes, more than the pixels can hold",
+            length
+        )));
+    }
+
+    let width = options.bits_per_channel as usize;
+    let low_bits = (1u16 << width) - 1;
+    let mut data = Vec::with_capacity(length);
+    let (mut byte, mut filled) = (0u16, 0);
+    for (x, y, channel) in data_samples(ihdr, options.mask_for(ihdr), key) {
+        if data.len() == length {
+            break;
+        }
+        byte = (byte << width) | (pixels.sample(x, y, channel) & low_bits);
+        filled += width;
+        while filled >= 8 && data.len() < length {
+            filled -= 8;
+            data.push((byte >> filled) as u8);
+        }
+        byte &= (1 << filled) - 1;
+    }
+
+    Ok(data)
+}
+
+/// Hides `data` in the pixels of `png` and re-encodes its image data
+/// losslessly.
+pub fn embed_in_png(png: &mut Png, data: &[u8], options: &LsbOptions, key: Option<&[u8]>) -> Result<()> {
+    let mut pixels = png.pixels()?;
+    embed(&mut pixels, data, options, key)?;
+    png.set_pixels(&pixels, &EncodeOptions::default())
+}
+
+/// Reads data hidden in the pixels of `png` by [`embed_in_png`].
+pub fn extract_from_png(png: &Png, key: Option<&[u8]>) -> Result<Vec<u8>> {
+    extract(&png.pixels()?, key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ihdr::Interlace;
+    use crate::png::tests::PNG_FILE;
+
+    fn testing_pixels(color_type: ColorType, bit_depth: u8) -> Pixels {
+        let ihdr = Ihdr {
+            width: 40,
+            height: 30,
+            bit_depth,
+            color_type,
+            interlace: Interlace::None,
+        };
+        let len = ihdr.row_bytes(ihdr.width) * ihdr.height as usize;
+        let data = (0..len).map(|i| (i * 7 % 251) as u8).collect();
+        Pixels::new(ihdr, data).unwrap()
+    }
+
+    fn message(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 + 7) as u8).collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for (color_type, depth) in [
+            (ColorType::Grayscale, 1),
+            (ColorType::Grayscale, 4),
+            (ColorType::Truecolor, 8),
+            (ColorType::GrayscaleAlpha, 16),
+            (ColorType::TruecolorAlpha, 8),
+        ] {
+            for bits_per_channel in [1, depth.min(3)] {
+                let options = LsbOptions {
+                    bits_per_channel,
+                    channel_mask: 0b101,
+                };
+                let mut pixels = testing_pixels(color_type, depth);
+                let data = message(capacity(pixels.ihdr(), &options));
+
+                embed(&mut pixels, &data, &options, None).unwrap();
+                assert!(is_embedded(&pixels, None));
+                assert_eq!(extract(&pixels, None).unwrap(), data, "{:?} {}", color_type, depth);
+            }
+        }
+    }
+
+    #[test]
+    fn test_only_low_bits_of_selected_channels_change() {
+        let original = testing_pixels(ColorType::TruecolorAlpha, 8);
+        let mut pixels = original.clone();
+        let options = LsbOptions {
+            bits_per_channel: 2,
+            channel_mask: 0b0111,
+        };
+        embed(&mut pixels, &message(500), &options, None).unwrap();
+
+        for (i, (before, after)) in original.data().iter().zip(pixels.data()).enumerate() {
+            let (pixel, channel) = (i / 4, i % 4);
+            if pixel < HEADER_PIXELS {
+                assert_eq!(before & !1, after & !1);
+                if channel != 0 {
+                    assert_eq!(before, after);
+                }
+            } else if channel == 3 {
+                assert_eq!(before, after);
+            } else {
+                assert_eq!(before & !0b11, after & !0b11);
+            }
+        }
+    }
+
+    #[test]
+    fn test_capacity() {
+        let ihdr = *testing_pixels(ColorType::TruecolorAlpha, 8).ihdr();
+        let pixels = 40 * 30 - HEADER_PIXELS;
+
+        assert_eq!(capacity(&ihdr, &LsbOptions::default()), pixels * 4 / 8);
+        let options = LsbOptions {
+            bits_per_channel: 3,
+            channel_mask: 0b0111,
+        };
+        assert_eq!(capacity(&ihdr, &options), pixels * 3 * 3 / 8);
+    }
+
+    #[test]
+    fn test_too_much_data() {
+        let mut pixels = testing_pixels(ColorType::Truecolor, 8);
+        let capacity = capacity(pixels.ihdr(), &LsbOptions::default());
+        assert!(embed(&mut pixels, &message(capacity + 1), &LsbOptions::default(), None).is_err());
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        let mut pixels = testing_pixels(ColorType::Grayscale, 4);
+        for options in [
+            LsbOptions { bits_per_channel: 0, channel_mask: 1 },
+            LsbOptions { bits_per_channel: 5, channel_mask: 1 },
+            LsbOptions { bits_per_channel: 1, channel_mask: 0b10 },
+        ] {
+            assert!(embed(&mut pixels, b"data", &options, None).is_err());
+        }
+    }
+
+    #[test]
+    fn test_tiny_images_are_refused() {
+        let ihdr = Ihdr {
+            width: 9,
+            height: 10,
+            bit_depth: 8,
+            color_type: ColorType::Grayscale,
+            interlace: Interlace::None,
+        };
+        let mut pixels = Pixels::new(ihdr, vec![0; 90]).unwrap();
+        assert_eq!(capacity(&ihdr, &LsbOptions::default()), 0);
+        assert!(embed(&mut pixels, b"", &LsbOptions::default(), None).is_err());
+        assert!(!is_embedded(&pixels, None));
+    }
+
+    #[test]
+    fn test_palette_images_are_refused() {
+        let mut pixels = testing_pixels(ColorType::Indexed, 8);
+        assert!(embed(&mut pixels, b"data", &LsbOptions::default(), None).is_err());
+        assert!(!is_embedded(&pixels, None));
+    }
+
+    #[test]
+    fn test_plain_image_has_no_data() {
+        let png = Png::try_from(&PNG_FILE[..]).unwrap();
+        assert!(!is_embedded(&png.pixels().unwrap(), None));
+        assert!(extract_from_png(&png, None).is_err());
+    }
+
+    #[test]
+    fn test_survives_chunk_stripping() {
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        embed_in_png(&mut png, b"hidden in plain sight", &LsbOptions::default(), None).unwrap();
+
+        // Keep only the critical chunks, as optimizers do
+        let mut png = Png::try_from(png.as_bytes().as_ref()).unwrap();
+        png.chunks_mut().retain(|chunk| chunk.chunk_type().is_critical() && chunk.chunk_type().to_string() != "RuSt");
+        let png = Png::try_from(png.as_bytes().as_ref()).unwrap();
+
+        assert_eq!(extract_from_png(&png, None).unwrap(), b"hidden in plain sight");
+    }
+
+    #[test]
+    fn test_keyed_round_trip() {
+        let options = LsbOptions {
+            bits_per_channel: 2,
+            channel_mask: 0b0111,
+        };
+        let mut pixels = testing_pixels(ColorType::TruecolorAlpha, 8);
+        let data = message(capacity(pixels.ihdr(), &options));
+
+        embed(&mut pixels, &data, &options, Some(b"key")).unwrap();
+        assert!(is_embedded(&pixels, Some(b"key")));
+        assert_eq!(extract(&pixels, Some(b"key")).unwrap(), data);
+    }
+
+    #[test]
+    fn test_keyed_data_is_hidden_without_the_key() {
+        let mut pixels = testing_pixels(ColorType::Truecolor, 8);
+        embed(&mut pixels, b"only for key holders", &LsbOptions::default(), Some(b"key")).unwrap();
+
+        assert!(!is_embedded(&pixels, None));
+        assert!(!is_embedded(&pixels, Some(b"other key")));
+        assert!(extract(&pixels, Some(b"other key")).is_err());
+    }
+
+    #[test]
+    fn test_keyed_bits_are_spread() {
+        let original = testing_pixels(ColorType::Grayscale, 8);
+        let mut pixels = original.clone();
+        embed(&mut pixels, &message(40), &LsbOptions::default(), Some(b"key")).unwrap();
+
+        // Sequentially, 96 + 320 pixels would all fit in the first 11 rows
+        let changed_rows: Vec<u32> = (0..30)
+            .filter(|y| original.row(*y) != pixels.row(*y))
+            .collect();
+        assert!(changed_rows.iter().any(|y| *y >= 15));
+        assert!(changed_rows.len() > 20);
+    }
+
+    #[test]
+    fn test_shuffle_is_a_deterministic_permutation() {
+        let order: Vec<usize> = Shuffle::new(1000, b"key").collect();
+        assert_eq!(order, Shuffle::new(1000, b"key").collect::<Vec<_>>());
+        assert_ne!(order, Shuffle::new(1000, b"kez").collect::<Vec<_>>());
+        assert_ne!(order, (0..1000).collect::<Vec<_>>());
+
+        let mut sorted = order;
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..1000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_shuffle_matches_full_fisher_yates() {
+        // The order data was spread in when every index was stored
+        let mut shuffle = Shuffle::new(1000, b"key");
+        let mut indices: Vec<usize> = (0..1000).collect();
+        for next in 0..indices.len() {
+            let pick = next + shuffle.below((indices.len() - next) as u64) as usize;
+            indices.swap(next, pick);
+        }
+        assert_eq!(indices, Shuffle::new(1000, b"key").collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_shuffle_stores_only_swaps() {
+        let mut shuffle = Shuffle::new(100_000_000, b"key");
+        assert_eq!(shuffle.by_ref().take(HEADER_PIXELS).count(), HEADER_PIXELS);
+        assert!(shuffle.swapped.len() <= HEADER_PIXELS);
+    }
+}
@@ -1,18 +1,22 @@
+use std::process::ExitCode;
+
 use clap::StructOpt;
+mod atomic;
 mod commands;
 mod args;
-mod chunk_type;
-mod chunk;
-mod png;
 use commands::execute_command;
 use args::Cli;
 
-pub type Error = Box<dyn std::error::Error>;
-pub type Result<T> = std::result::Result<T, Error>;
-fn main() {
+fn main() -> ExitCode {
     let args = Cli::parse();
     match execute_command(args.command) {
-        Ok(()) => println!("Worked successfully."),
-        Err(why) => println!("{}", why),
+        Ok(()) => {
+            eprintln!("Worked successfully.");
+            ExitCode::SUCCESS
+        }
+        Err(why) => {
+            eprintln!("{}", why);
+            ExitCode::FAILURE
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,259 @@
+//! Framing of hidden files with the metadata needed to restore them.
+//!
+//! A framed payload starts with a small header:
+//!
+//! | Field                          | Size      |
+//! |--------------------------------|-----------|
+//! | Magic `pmPL`                   | 4         |
+//! | Version                        | 1         |
+//! | Size of the data               | 8         |
+//! | Name length, name (UTF-8)      | 2 + n     |
+//! | MIME type length, MIME type    | 1 + n     |
+//! | Data                           | remaining |
+//!
+//! Data without the header, such as messages written by earlier versions,
+//! is still readable as is.
+
+use crate::cursor::Cursor;
+use crate::{Error, Location, Result};
+
+const MAGIC: [u8; 4] = *b"pmPL";
+const VERSION: u8 = 1;
+
+/// MIME type of data nothing more specific is known about.
+pub const OCTET_STREAM: &str = "application/octet-stream";
+
+/// Hidden data together with what is known about the file it came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Payload {
+    /// Original file name, without any directory.
+    pub name: Option<String>,
+    /// MIME type of the data.
+    pub mime_type: Option<String>,
+    /// The hidden bytes.
+    pub data: Vec<u8>,
+}
+
+impl Payload {
+    /// A payload holding `data` with no metadata.
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            name: None,
+            mime_type: None,
+            data,
+        }
+    }
+
+    /// Number of bytes [`Payload::to_bytes`] adds to the data.
+    pub fn overhead(&self) -> usize {
+        MAGIC.len()
+            + 1
+            + 8
+            + 2
+            + self.name.as_ref().map_or(0, String::len)
+            + 1
+            + self.mime_type.as_ref().map_or(0, String::len)
+    }
+
+    /// Whether `data` starts with a payload header.
+    pub fn is_framed(data: &[u8]) -> bool {
+        data.starts_with(&MAGIC)
+    }
+
+    /// Serializes the header followed by the data.
+    ///
+    /// Fails when the name or MIME type are too long for the header.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let name = self.name.as_deref().unwrap_or_default().as_bytes();
+        let mime_type = self.mime_type.as_deref().unwrap_or_default().as_bytes();
+
+        let too_long = |field: &str| Error::InvalidPayload {
+            location: Location::default(),
+            reason: format!("{} is too long", field),
+        };
+        let name_len = u16::try_from(name.len()).map_err(|_| too_long("file name"))?;
+        let mime_type_len = u8::try_from(mime_type.len()).map_err(|_| too_long("MIME type"))?;
+
+        let mut bytes = Vec::with_capacity(self.overhead() + self.data.len());
+        bytes.extend_from_slice(&MAGIC);
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&(self.data.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(&name_len.to_be_bytes());
+        bytes.extend_from_slice(name);
+        bytes.push(mime_type_len);
+        bytes.extend_from_slice(mime_type);
+        bytes.extend_from_slice(&self.data);
+
+        Ok(bytes)
+    }
+
+    /// Parses framed data, or wraps unframed data in a payload without
+    /// metadata.
+    ///
+    /// Fails when the header is malformed or the data doesn't have the
+    /// recorded size.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if !Self::is_framed(bytes) {
+            return Ok(Self::new(bytes.to_vec()));
+        }
+
+        let mut cursor = Cursor::new(bytes);
+        cursor.read_array::<4>()?;
+
+        let version = cursor.read_u8()?;
+        if version != VERSION {
+            return Err(cursor.error(format!("unsupported payload version {}", version)));
+        }
+
+        let size = u64::from_be_bytes(cursor.read_array()?);
+        let name_len = u16::from_be_bytes(cursor.read_array()?) as usize;
+        let name = read_string(&mut cursor, name_len)?;
+        let mime_type_len = cursor.read_u8()? as usize;
+        let mime_type = read_string(&mut cursor, mime_type_len)?;
+
+        if cursor.rest().len() as u64 != size {
+            return Err(cursor.error(format!(
+                "expected {} bytes of data, found {}",
+                size,
+                cursor.rest().len()
+            )));
+        }
+
+        Ok(Self {
+            name,
+            mime_type,
+            data: cursor.rest().to_vec(),
+        })
+    }
+}
+
+fn read_string(cursor: &mut Cursor, len: usize) -> Result<Option<String>> {
+    let start = cursor.position();
+    let bytes = cursor.read_bytes(len)?;
+    let string = std::str::from_utf8(bytes).map_err(|source| Error::InvalidUtf8 {
+        location: Location::at((start + source.valid_up_to()) as u64),
+        source,
+    })?;
+
+    Ok((!string.is_empty()).then(|| string.to_string()))
+}
+
+/// Guesses the MIME type of a file from its leading bytes, falling back to
+/// the extension of `name`.
+pub fn guess_mime_type(name: Option<&str>, data: &[u8]) -> &'static str {
+    const SIGNATURES: [(&[u8], &str); 8] = [
+        (&[137, 80, 78, 71, 13, 10, 26, 10], "image/png"),
+        (&[0xff, 0xd8, 0xff], "image/jpeg"),
+        (b"GIF8", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (&[0x1f, 0x8b], "application/gzip"),
+        (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+        (b"\x7fELF", "application/x-executable"),
+    ];
+    if let Some((_, mime_type)) = SIGNATURES.iter().find(|(magic, _)| data.starts_with(magic)) {
+        return mime_type;
+    }
+
+    const EXTENSIONS: [(&str, &str); 8] = [
+        ("txt", "text/plain"),
+        ("md", "text/markdown"),
+        ("json", "application/json"),
+        ("html", "text/html"),
+        ("csv", "text/csv"),
+        ("xml", "application/xml"),
+        ("webp", "image/webp"),
+        ("wav", "audio/wav"),
+    ];
+    let extension = name
+        .and_then(|name| name.rsplit_once('.'))
+        .map(|(_, extension)| extension.to_ascii_lowercase());
+    if let Some((_, mime_type)) = EXTENSIONS
+        .iter()
+        .find(|(known, _)| Some(*known) == extension.as_deref())
+    {
+        return mime_type;
+    }
+
+    if std::str::from_utf8(data).is_ok() {
+        "text/plain"
+    } else {
+        OCTET_STREAM
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn testing_payload() -> Payload {
+        Payload {
+            name: Some("secret.bin".to_string()),
+            mime_type: Some(OCTET_STREAM.to_string()),
+            data: vec![0, 159, 146, 150, 255],
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let payload = testing_payload();
+        let bytes = payload.to_bytes().unwrap();
+        assert!(Payload::is_framed(&bytes));
+        assert_eq!(bytes.len(), payload.overhead() + 5);
+        assert_eq!(Payload::from_bytes(&bytes).unwrap(), payload);
+    }
+
+    #[test]
+    fn test_round_trip_without_metadata() {
+        let payload = Payload::new(b"data".to_vec());
+        let bytes = payload.to_bytes().unwrap();
+        assert_eq!(Payload::from_bytes(&bytes).unwrap(), payload);
+    }
+
+    #[test]
+    fn test_unframed_data() {
+        let payload = Payload::from_bytes(b"This is a plain message").unwrap();
+        assert_eq!(payload, Payload::new(b"This is a plain message".to_vec()));
+    }
+
+    #[test]
+    fn test_size_mismatch() {
+        let mut bytes = testing_payload().to_bytes().unwrap();
+        bytes.push(0);
+        assert!(matches!(Payload::from_bytes(&bytes), Err(Error::InvalidPayload { .. })));
+    }
+
+    #[test]
+    fn test_truncated_payload() {
+        let bytes = testing_payload().to_bytes().unwrap();
+        for end in MAGIC.len()..bytes.len() {
+            assert!(Payload::from_bytes(&bytes[..end]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_invalid_name() {
+        let mut bytes = testing_payload().to_bytes().unwrap();
+        // First byte of the name
+        bytes[15] = 0xff;
+        match Payload::from_bytes(&bytes) {
+            Err(Error::InvalidUtf8 { location, .. }) => assert_eq!(location, Location::at(15)),
+            other => panic!("expected utf-8 error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_name_too_long() {
+        let mut payload = testing_payload();
+        payload.name = Some("a".repeat(70_000));
+        assert!(payload.to_bytes().is_err());
+    }
+
+    #[test]
+    fn test_guess_mime_type() {
+        assert_eq!(guess_mime_type(Some("a.png"), b"\x89PNG\r\n\x1a\nrest"), "image/png");
+        assert_eq!(guess_mime_type(Some("notes.TXT"), &[0xff]), "text/plain");
+        assert_eq!(guess_mime_type(None, b"hello"), "text/plain");
+        assert_eq!(guess_mime_type(None, &[0xff, 0x00]), OCTET_STREAM);
+    }
+}
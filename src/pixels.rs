@@ -0,0 +1,888 @@
+//! Decoding of the image data stored in IDAT chunks into pixels, and
+//! encoding of pixels back into IDAT chunks.
+//!
+//! The data of all IDAT chunks forms one zlib stream of scanlines, each
+//! prefixed with the type of the filter applied to it. Interlaced images
+//! store seven reduced images, the Adam7 passes, one after the other.
+
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+
+use crate::chunk::Chunk;
+use crate::chunk_type::ChunkType;
+use crate::ihdr::{ColorType, Ihdr, Interlace};
+use crate::png::Png;
+use crate::{Error, Location, Result};
+
+/// The Adam7 passes as (first column, first row, column step, row step).
+const ADAM7: [(u32, u32, u32, u32); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+/// A reduced image stored in the data stream: the pixels of the image that
+/// fall on its grid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Pass {
+    pub x: u32,
+    pub y: u32,
+    pub dx: u32,
+    pub dy: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// The passes of an image in stream order, leaving out empty ones.
+pub(crate) fn passes(ihdr: &Ihdr) -> Vec<Pass> {
+    match ihdr.interlace {
+        Interlace::None => vec![Pass {
+            x: 0,
+            y: 0,
+            dx: 1,
+            dy: 1,
+            width: ihdr.width,
+            height: ihdr.height,
+        }],
+        Interlace::Adam7 => ADAM7
+            .iter()
+            .map(|&(x, y, dx, dy)| Pass {
+                x,
+                y,
+                dx,
+                dy,
+                width: ihdr.width.saturating_sub(x).div_ceil(dx),
+                height: ihdr.height.saturating_sub(y).div_ceil(dy),
+            })
+            .filter(|pass| pass.width > 0 && pass.height > 0)
+            .collect(),
+    }
+}
+
+/// Largest decompressed data stream decoded, 2 GiB: enough for a 16 bit
+/// RGBA image of 268 million pixels.
+const MAX_STREAM_LEN: usize = 1 << 31;
+
+/// Most bytes deflate can turn a single compressed byte into.
+const MAX_DEFLATE_RATIO: usize = 1032;
+
+/// Length of the decompressed data stream of an image, `None` if it
+/// doesn't fit in memory.
+pub(crate) fn stream_len(ihdr: &Ihdr) -> Option<usize> {
+    passes(ihdr).iter().try_fold(0usize, |total, pass| {
+        let row = (pass.width as usize)
+            .checked_mul(ihdr.bits_per_pixel())?
+            .div_ceil(8)
+            .checked_add(1)?;
+        total.checked_add(row.checked_mul(pass.height as usize)?)
+    })
+}
+
+/// Transformation applied to a scanline before compression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    None,
+    Sub,
+    Up,
+    Average,
+    Paeth,
+}
+
+impl Filter {
+    /// All filter types, in the order of their codes.
+    pub const ALL: [Filter; 5] = [
+        Filter::None,
+        Filter::Sub,
+        Filter::Up,
+        Filter::Average,
+        Filter::Paeth,
+    ];
+
+    /// The code stored before each scanline.
+    pub fn code(&self) -> u8 {
+        *self as u8
+    }
+}
+
+impl TryFrom<u8> for Filter {
+    type Error = u8;
+
+    fn try_from(code: u8) -> std::result::Result<Self, u8> {
+        Self::ALL.get(code as usize).copied().ok_or(code)
+    }
+}
+
+/// The Paeth predictor: whichever of left, above and upper left is closest
+/// to left + above - upper left.
+pub(crate) fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = ((p - a as i16).abs(), (p - b as i16).abs(), (p - c as i16).abs());
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// Reverses `filter` on a scanline in place. `previous` is the unfiltered
+/// previous row of the same pass, all zeros for its first row, and `bpp`
+/// the number of bytes per pixel rounded up to one.
+pub(crate) fn unfilter(filter: Filter, row: &mut [u8], previous: &[u8], bpp: usize) {
+    match filter {
+        Filter::None => {}
+        Filter::Sub => {
+            for i in bpp..row.len() {
+                row[i] = row[i].wrapping_add(row[i - bpp]);
+            }
+        }
+        Filter::Up => {
+            for (byte, above) in row.iter_mut().zip(previous) {
+                *byte = byte.wrapping_add(*above);
+            }
+        }
+        Filter::Average => {
+            for i in 0..row.len() {
+                let left = if i >= bpp { row[i - bpp] } else { 0 };
+                let average = ((left as u16 + previous[i] as u16) / 2) as u8;
+                row[i] = row[i].wrapping_add(average);
+            }
+        }
+        Filter::Paeth => {
+            for i in 0..row.len() {
+                let (left, upper_left) = if i >= bpp {
+                    (row[i - bpp], previous[i - bpp])
+                } else {
+                    (0, 0)
+                };
+                row[i] = row[i].wrapping_add(paeth(left, previous[i], upper_left));
+            }
+        }
+    }
+}
+
+/// Applies `filter` to a scanline, appending the result to `out`. The
+/// arguments are the same as for [`unfilter`].
+pub(crate) fn filter_row(filter: Filter, row: &[u8], previous: &[u8], bpp: usize, out: &mut Vec<u8>) {
+    out.extend((0..row.len()).map(|i| {
+        let (left, upper_left) = if i >= bpp {
+            (row[i - bpp], previous[i - bpp])
+        } else {
+            (0, 0)
+        };
+        let predictor = match filter {
+            Filter::None => 0,
+            Filter::Sub => left,
+            Filter::Up => previous[i],
+            Filter::Average => ((left as u16 + previous[i] as u16) / 2) as u8,
+            Filter::Paeth => paeth(left, previous[i], upper_left),
+        };
+        row[i].wrapping_sub(predictor)
+    }));
+}
+
+/// Copies a pixel of `bits` bits between two packed rows.
+pub(crate) fn copy_pixel(src: &[u8], src_bit: usize, dst: &mut [u8], dst_bit: usize, bits: usize) {
+    if bits.is_multiple_of(8) {
+        let len = bits / 8;
+        dst[dst_bit / 8..][..len].copy_from_slice(&src[src_bit / 8..][..len]);
+    } else {
+        // 1, 2 and 4 bit pixels never straddle a byte
+        let mask = ((1u16 << bits) - 1) as u8;
+        let value = (src[src_bit / 8] >> (8 - bits - src_bit % 8)) & mask;
+        let shift = 8 - bits - dst_bit % 8;
+        let byte = &mut dst[dst_bit / 8];
+        *byte = (*byte & !(mask << shift)) | (value << shift);
+    }
+}
+
+/// Unfiltered and de-interlaced image data.
+///
+/// Pixels are stored as `height` rows of [`Pixels::row_bytes`] bytes with
+/// samples packed as in the PNG stream: 16 bit samples are big-endian and
+/// samples smaller than a byte start from its most significant bit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pixels {
+    ihdr: Ihdr,
+    data: Vec<u8>,
+}
+
+impl Pixels {
+    /// Wraps packed rows of pixels described by `ihdr`.
+    pub fn new(ihdr: Ihdr, data: Vec<u8>) -> Result<Self> {
+        let expected = ihdr.row_bytes(ihdr.width) * ihdr.height as usize;
+        if data.len() != expected {
+            return Err(Error::InvalidPayload {
+                location: Location::default(),
+                reason: format!(
+                    "{} bytes of pixels, expected {} for a {} image",
+                    data.len(),
+                    expected,
+                    ihdr
+                ),
+            });
+        }
+
+        Ok(Self { ihdr, data })
+    }
+
+    /// The header describing the pixels.
+    pub fn ihdr(&self) -> &Ihdr {
+        &self.ihdr
+    }
+
+    /// Number of bytes in each row.
+    pub fn row_bytes(&self) -> usize {
+        self.ihdr.row_bytes(self.ihdr.width)
+    }
+
+    /// The packed pixels of row `y`.
+    ///
+    /// Panics if `y` is not less than the height.
+    pub fn row(&self, y: u32) -> &[u8] {
+        let row_bytes = self.row_bytes();
+        &self.data[y as usize * row_bytes..][..row_bytes]
+    }
+
+    /// All rows, one after the other.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// All rows, for editing in place.
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    /// Takes the rows out of the image.
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// The value of `channel` of the pixel at (`x`, `y`). Palette indices
+    /// are returned as is.
+    ///
+    /// Panics if the coordinates or channel are out of bounds.
+    pub fn sample(&self, x: u32, y: u32, channel: usize) -> u16 {
+        assert!(x < self.ihdr.width && channel < self.ihdr.color_type.channels());
+
+        let depth = self.ihdr.bit_depth as usize;
+        let bit = x as usize * self.ihdr.bits_per_pixel() + channel * depth;
+        let row = self.row(y);
+        match depth {
+            16 => u16::from_be_bytes([row[bit / 8], row[bit / 8 + 1]]),
+            8 => row[bit / 8] as u16,
+            _ => ((row[bit / 8] >> (8 - depth - bit % 8)) & ((1 << depth) - 1)) as u16,
+        }
+    }
+
+    /// Sets `channel` of the pixel at (`x`, `y`) to `value`, keeping only
+    /// as many low bits as the bit depth holds.
+    ///
+    /// Panics if the coordinates or channel are out of bounds.
+    pub fn set_sample(&mut self, x: u32, y: u32, channel: usize, value: u16) {
+        assert!(x < self.ihdr.width && channel < self.ihdr.color_type.channels());
+
+        let depth = self.ihdr.bit_depth as usize;
+        let bit = x as usize * self.ihdr.bits_per_pixel() + channel * depth;
+        let byte = y as usize * self.row_bytes() + bit / 8;
+        match depth {
+            16 => self.data[byte..byte + 2].copy_from_slice(&value.to_be_bytes()),
+            8 => self.data[byte] = value as u8,
+            _ => {
+                let mask = ((1u16 << depth) - 1) as u8;
+                let shift = 8 - depth - bit % 8;
+                self.data[byte] = (self.data[byte] & !(mask << shift)) | ((value as u8 & mask) << shift);
+            }
+        }
+    }
+}
+
+/// How the encoder picks the filter of each scanline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterStrategy {
+    /// Use the same filter for every scanline.
+    Fixed(Filter),
+    /// The heuristic recommended by the PNG spec: no filtering for
+    /// palette images and depths below 8 bits, otherwise the filter whose
+    /// output has the smallest sum of absolute values.
+    Adaptive,
+}
+
+/// Settings for turning pixels into IDAT chunks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncodeOptions {
+    pub filter: FilterStrategy,
+    /// zlib compression level, from 0 (store) to 9 (smallest).
+    pub compression: u32,
+    /// Largest amount of compressed data put in one IDAT chunk.
+    pub idat_size: usize,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        Self {
+            filter: FilterStrategy::Adaptive,
+            compression: 6,
+            idat_size: 8192,
+        }
+    }
+}
+
+impl EncodeOptions {
+    fn check(&self) -> Result<()> {
+        let reason = if self.compression > 9 {
+            format!("compression level {} is not between 0 and 9", self.compression)
+        } else if self.idat_size == 0 || self.idat_size > Chunk::MAX_LENGTH as usize {
+            format!("IDAT size {} is not between 1 and {}", self.idat_size, Chunk::MAX_LENGTH)
+        } else {
+            return Ok(());
+        };
+
+        Err(Error::InvalidPayload {
+            location: Location::default(),
+            reason,
+        })
+    }
+}
+
+impl Pixels {
+    /// Filters and interlaces the pixels into the uncompressed data stream.
+    fn to_stream(&self, strategy: FilterStrategy) -> Vec<u8> {
+        let ihdr = &self.ihdr;
+        let bits = ihdr.bits_per_pixel();
+        let bpp = bits.div_ceil(8);
+        let adaptive = strategy == FilterStrategy::Adaptive
+            && ihdr.color_type != ColorType::Indexed
+            && ihdr.bit_depth >= 8;
+
+        let mut stream = Vec::with_capacity(stream_len(ihdr).unwrap_or(0));
+        let mut candidate = Vec::new();
+        for pass in passes(ihdr) {
+            let mut previous = vec![0; ihdr.row_bytes(pass.width)];
+            let mut row = vec![0; previous.len()];
+
+            for y in 0..pass.height {
+                let source = self.row(pass.y + y * pass.dy);
+                if pass.dx == 1 {
+                    row.copy_from_slice(source);
+                } else {
+                    for x in 0..pass.width as usize {
+                        let column = (pass.x + x as u32 * pass.dx) as usize;
+                        copy_pixel(source, column * bits, &mut row, x * bits, bits);
+                    }
+                }
+
+                let filter = match strategy {
+                    FilterStrategy::Fixed(filter) => filter,
+                    FilterStrategy::Adaptive if !adaptive => Filter::None,
+                    FilterStrategy::Adaptive => *Filter::ALL
+                        .iter()
+                        .min_by_key(|filter| {
+                            candidate.clear();
+                            filter_row(**filter, &row, &previous, bpp, &mut candidate);
+                            candidate
+                                .iter()
+                                .map(|byte| (*byte as i8).unsigned_abs() as u64)
+                                .sum::<u64>()
+                        })
+                        .unwrap(),
+                };
+                stream.push(filter.code());
+                filter_row(filter, &row, &previous, bpp, &mut stream);
+                std::mem::swap(&mut previous, &mut row);
+            }
+        }
+
+        stream
+    }
+
+    /// Filters, compresses and splits the pixels into IDAT chunks.
+    pub fn to_idat_chunks(&self, options: &EncodeOptions) -> Result<Vec<Chunk>> {
+        options.check()?;
+
+        let io_error = |source| Error::io(0, source);
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(options.compression));
+        encoder
+            .write_all(&self.to_stream(options.filter))
+            .map_err(io_error)?;
+        let compressed = encoder.finish().map_err(io_error)?;
+
+        let idat = ChunkType::from_str("IDAT").unwrap();
+        Ok(compressed
+            .chunks(options.idat_size)
+            .map(|data| Chunk::new(idat.clone(), data.to_vec()))
+            .collect())
+    }
+
+    /// A minimal PNG holding the pixels: IHDR, IDAT chunks and IEND.
+    pub fn to_png(&self, options: &EncodeOptions) -> Result<Png> {
+        let mut chunks = vec![self.ihdr.to_chunk()];
+        chunks.extend(self.to_idat_chunks(options)?);
+        chunks.push(Chunk::new(ChunkType::from_str("IEND").unwrap(), Vec::new()));
+
+        Ok(Png::from_chunks(chunks))
+    }
+}
+
+impl Png {
+    /// Replaces the image data with `pixels`, which may also change the
+    /// header. Every other chunk is kept where it was; the new IDAT chunks
+    /// go where the first old one was, or before IEND.
+    pub fn set_pixels(&mut self, pixels: &Pixels, options: &EncodeOptions) -> Result<()> {
+        let idat_chunks = pixels.to_idat_chunks(options)?;
+        let ihdr = self
+            .chunks()
+            .iter()
+            .position(|chunk| chunk.chunk_type().bytes() == *b"IHDR")
+            .ok_or_else(|| Error::ChunkNotFound {
+                chunk_type: "IHDR".to_string(),
+            })?;
+
+        let chunks = self.chunks_mut();
+        chunks[ihdr] = pixels.ihdr().to_chunk();
+        let position = chunks
+            .iter()
+            .position(|chunk| chunk.chunk_type().bytes() == *b"IDAT")
+            .or_else(|| chunks.iter().position(|chunk| chunk.chunk_type().bytes() == *b"IEND"))
+            .unwrap_or(chunks.len());
+        chunks.retain(|chunk| chunk.chunk_type().bytes() != *b"IDAT");
+        chunks.splice(position..position, idat_chunks);
+
+        Ok(())
+    }
+
+    /// Decompresses, unfilters and de-interlaces the image data.
+    ///
+    /// Errors in the image data are located at the start of the first IDAT
+    /// chunk, since the compressed stream can't be traced back further.
+    pub fn pixels(&self) -> Result<Pixels> {
+        let ihdr = self.ihdr()?;
+        let first = self
+            .chunks()
+            .iter()
+            .position(|chunk| chunk.chunk_type().bytes() == *b"IDAT")
+            .ok_or_else(|| Error::ChunkNotFound {
+                chunk_type: "IDAT".to_string(),
+            })?;
+        let location = Location::in_chunk(self.chunk_data_offset(first), first);
+        let invalid = |reason: String| Error::InvalidPayload { location, reason };
+
+        let expected = stream_len(&ihdr)
+            .filter(|len| *len <= MAX_STREAM_LEN)
+            .ok_or_else(|| invalid(format!("{} image is too large", ihdr)))?;
+        let compressed: Vec<u8> = self
+            .chunks_by_type("IDAT")
+            .flat_map(|chunk| chunk.data())
+            .copied()
+            .collect();
+        // Refused before inflating anything: no zlib stream this short holds
+        // the whole image
+        if expected / MAX_DEFLATE_RATIO > compressed.len() {
+            return Err(invalid(format!(
+                "image data holds {} compressed bytes, too few for {} bytes",
+                compressed.len(),
+                expected
+            )));
+        }
+
+        // Never inflate more than the image needs, whatever the stream holds
+        let mut stream = Vec::new();
+        ZlibDecoder::new(compressed.as_slice())
+            .take(expected as u64 + 1)
+            .read_to_end(&mut stream)
+            .map_err(|e| invalid(format!("invalid zlib stream: {}", e)))?;
+        if stream.len() != expected {
+            return Err(invalid(format!(
+                "image data holds {} {} bytes, expected {}",
+                if stream.len() > expected { "more than" } else { "only" },
+                expected.min(stream.len()),
+                expected
+            )));
+        }
+
+        decode_stream(ihdr, &mut stream).map_err(invalid)
+    }
+}
+
+/// Unfilters every pass of the decompressed `stream` and puts its pixels in
+/// place.
+fn decode_stream(ihdr: Ihdr, stream: &mut [u8]) -> std::result::Result<Pixels, String> {
+    let bits = ihdr.bits_per_pixel();
+    let bpp = bits.div_ceil(8);
+    let row_bytes = ihdr.row_bytes(ihdr.width);
+    let mut data = vec![0; row_bytes * ihdr.height as usize];
+
+    let mut rows = stream;
+    for (index, pass) in passes(&ihdr).iter().enumerate() {
+        let pass_row_bytes = ihdr.row_bytes(pass.width);
+        let mut previous = vec![0; pass_row_bytes];
+
+        for y in 0..pass.height {
+            let (line, rest) = std::mem::take(&mut rows).split_at_mut(1 + pass_row_bytes);
+            rows = rest;
+            let (filter, row) = line.split_first_mut().unwrap();
+
+            let filter = Filter::try_from(*filter).map_err(|code| {
+                format!("unknown filter type {} in row {} of pass {}", code, y, index + 1)
+            })?;
+            unfilter(filter, row, &previous, bpp);
+
+            let target = &mut data[(pass.y + y * pass.dy) as usize * row_bytes..][..row_bytes];
+            if pass.dx == 1 {
+                target.copy_from_slice(row);
+            } else {
+                for x in 0..pass.width as usize {
+                    let column = (pass.x + x as u32 * pass.dx) as usize;
+                    copy_pixel(row, x * bits, target, column * bits, bits);
+                }
+            }
+            previous.copy_from_slice(row);
+        }
+    }
+
+    Ok(Pixels { ihdr, data })
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+    use crate::png::tests::PNG_FILE;
+
+    /// Encodes `pixels` with a fixed filter, splitting the stream over
+    /// several IDAT chunks.
+    fn encode_with(pixels: &Pixels, filter: Filter) -> Png {
+        let options = EncodeOptions {
+            filter: FilterStrategy::Fixed(filter),
+            idat_size: 50,
+            ..EncodeOptions::default()
+        };
+        pixels.to_png(&options).unwrap()
+    }
+
+    fn encode(pixels: &Pixels) -> Png {
+        encode_with(pixels, Filter::Paeth)
+    }
+
+    /// An image with arbitrary pixels and zeroed padding bits.
+    fn testing_pixels(ihdr: Ihdr) -> Pixels {
+        let row_bytes = ihdr.row_bytes(ihdr.width);
+        let used_bits = ihdr.width as usize * ihdr.bits_per_pixel();
+        let mut state = 0x2545_f491_u32;
+        let mut data = Vec::new();
+
+        for _ in 0..ihdr.height {
+            let mut row: Vec<u8> = (0..row_bytes)
+                .map(|_| {
+                    state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                    (state >> 16) as u8
+                })
+                .collect();
+            if !used_bits.is_multiple_of(8) {
+                row[row_bytes - 1] &= 0xff << (8 - used_bits % 8);
+            }
+            data.extend(row);
+        }
+
+        Pixels::new(ihdr, data).unwrap()
+    }
+
+    fn all_formats() -> Vec<(ColorType, u8)> {
+        [
+            ColorType::Grayscale,
+            ColorType::Truecolor,
+            ColorType::Indexed,
+            ColorType::GrayscaleAlpha,
+            ColorType::TruecolorAlpha,
+        ]
+        .iter()
+        .flat_map(|color_type| {
+            color_type
+                .allowed_bit_depths()
+                .iter()
+                .map(move |depth| (*color_type, *depth))
+        })
+        .collect()
+    }
+
+    fn ihdr(width: u32, height: u32, color_type: ColorType, bit_depth: u8, interlace: Interlace) -> Ihdr {
+        Ihdr {
+            width,
+            height,
+            bit_depth,
+            color_type,
+            interlace,
+        }
+    }
+
+    #[test]
+    fn test_image_file_pixels() {
+        let png = Png::try_from(&PNG_FILE[..]).unwrap();
+        let pixels = png.pixels().unwrap();
+
+        assert_eq!(pixels.row_bytes(), 200);
+        assert_eq!(pixels.data().len(), 50 * 200);
+        assert_eq!(&pixels.row(25)[100..104], &[240, 240, 240, 255]);
+        assert_eq!(pixels.sample(26, 25, 1), 177);
+        assert_eq!(pixels.sample(0, 0, 3), 0);
+        assert_eq!(
+            format!("{:x}", Sha256::digest(pixels.data())),
+            "e47d64e318aca6e06dd3b403b89792645514da854a38f97cacfa69e9eb081e5d"
+        );
+    }
+
+    #[test]
+    fn test_all_formats() {
+        for (color_type, depth) in all_formats() {
+            for interlace in [Interlace::None, Interlace::Adam7] {
+                let pixels = testing_pixels(ihdr(13, 9, color_type, depth, interlace));
+                for filter in Filter::ALL {
+                    let decoded = encode_with(&pixels, filter).pixels().unwrap();
+                    assert_eq!(decoded, pixels, "{:?} {} {:?} {:?}", color_type, depth, interlace, filter);
+                }
+
+                let decoded = pixels.to_png(&EncodeOptions::default()).unwrap().pixels().unwrap();
+                assert_eq!(decoded, pixels, "{:?} {} {:?}", color_type, depth, interlace);
+            }
+        }
+    }
+
+    #[test]
+    fn test_tiny_interlaced_images() {
+        for (width, height) in [(1, 1), (1, 7), (5, 1), (8, 8), (9, 2)] {
+            let pixels = testing_pixels(ihdr(width, height, ColorType::Grayscale, 2, Interlace::Adam7));
+            assert_eq!(encode(&pixels).pixels().unwrap(), pixels);
+        }
+    }
+
+    #[test]
+    fn test_image_file_round_trip() {
+        let pixels = Png::try_from(&PNG_FILE[..]).unwrap().pixels().unwrap();
+
+        for compression in [0, 9] {
+            let options = EncodeOptions {
+                compression,
+                ..EncodeOptions::default()
+            };
+            let png = Png::try_from(pixels.to_png(&options).unwrap().as_bytes().as_ref()).unwrap();
+            assert_eq!(png.pixels().unwrap(), pixels);
+        }
+    }
+
+    #[test]
+    fn test_idat_splitting() {
+        let pixels = testing_pixels(ihdr(64, 64, ColorType::Truecolor, 8, Interlace::None));
+        let options = EncodeOptions {
+            compression: 0,
+            idat_size: 1000,
+            ..EncodeOptions::default()
+        };
+        let chunks = pixels.to_idat_chunks(&options).unwrap();
+
+        assert!(chunks.len() > 10);
+        assert!(chunks.iter().all(|chunk| chunk.length() <= 1000));
+        assert!(chunks[..chunks.len() - 1].iter().all(|chunk| chunk.length() == 1000));
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        let pixels = testing_pixels(ihdr(2, 2, ColorType::Grayscale, 8, Interlace::None));
+        for options in [
+            EncodeOptions { compression: 10, ..EncodeOptions::default() },
+            EncodeOptions { idat_size: 0, ..EncodeOptions::default() },
+        ] {
+            assert!(pixels.to_idat_chunks(&options).is_err());
+        }
+    }
+
+    #[test]
+    fn test_adaptive_filter_choice() {
+        let stream_filters = |pixels: &Pixels| -> Vec<u8> {
+            let row_len = 1 + pixels.row_bytes();
+            pixels
+                .to_stream(FilterStrategy::Adaptive)
+                .chunks(row_len)
+                .map(|row| row[0])
+                .collect()
+        };
+
+        // A horizontal gradient is best predicted from the left
+        let ihdr = ihdr(16, 2, ColorType::Grayscale, 8, Interlace::None);
+        let gradient: Vec<u8> = (0..2).flat_map(|_| (0..16).map(|x| x * 16)).collect();
+        assert_eq!(stream_filters(&Pixels::new(ihdr, gradient.clone()).unwrap())[0], Filter::Sub.code());
+
+        // Palette indices are left alone
+        let mut ihdr = ihdr;
+        ihdr.color_type = ColorType::Indexed;
+        assert_eq!(stream_filters(&Pixels::new(ihdr, gradient).unwrap()), [0, 0]);
+    }
+
+    #[test]
+    fn test_set_pixels_keeps_other_chunks() {
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        let types = |png: &Png| -> Vec<String> {
+            let mut types: Vec<String> = png.chunks().iter().map(|c| c.chunk_type().to_string()).collect();
+            types.dedup();
+            types
+        };
+        let before = types(&png);
+
+        let mut pixels = png.pixels().unwrap();
+        pixels.data_mut()[0] ^= 1;
+        let options = EncodeOptions {
+            idat_size: 100,
+            ..EncodeOptions::default()
+        };
+        png.set_pixels(&pixels, &options).unwrap();
+
+        assert_eq!(types(&png), before);
+        assert!(png.chunks_by_type("IDAT").count() > 1);
+        assert_eq!(png.pixels().unwrap(), pixels);
+    }
+
+    #[test]
+    fn test_set_pixels_changes_header() {
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        let pixels = testing_pixels(ihdr(7, 3, ColorType::Indexed, 4, Interlace::Adam7));
+
+        png.set_pixels(&pixels, &EncodeOptions::default()).unwrap();
+        assert_eq!(png.ihdr().unwrap(), *pixels.ihdr());
+        assert_eq!(png.pixels().unwrap(), pixels);
+    }
+
+    #[test]
+    fn test_passes() {
+        let sizes: Vec<(u32, u32)> = passes(&ihdr(9, 2, ColorType::Grayscale, 8, Interlace::Adam7))
+            .iter()
+            .map(|pass| (pass.width, pass.height))
+            .collect();
+        // Passes 3 and 5 start below the second row
+        assert_eq!(sizes, [(2, 1), (1, 1), (2, 1), (4, 1), (9, 1)]);
+    }
+
+    #[test]
+    fn test_sample() {
+        let ihdr = ihdr(3, 1, ColorType::Grayscale, 2, Interlace::None);
+        let pixels = Pixels::new(ihdr, vec![0b0110_1100]).unwrap();
+        assert_eq!(pixels.sample(0, 0, 0), 1);
+        assert_eq!(pixels.sample(1, 0, 0), 2);
+        assert_eq!(pixels.sample(2, 0, 0), 3);
+
+        let ihdr = Ihdr {
+            width: 1,
+            height: 1,
+            bit_depth: 16,
+            color_type: ColorType::GrayscaleAlpha,
+            interlace: Interlace::None,
+        };
+        let pixels = Pixels::new(ihdr, vec![1, 2, 3, 4]).unwrap();
+        assert_eq!(pixels.sample(0, 0, 1), 0x0304);
+    }
+
+    #[test]
+    fn test_set_sample() {
+        for (color_type, depth) in all_formats() {
+            let mut pixels = testing_pixels(ihdr(5, 3, color_type, depth, Interlace::None));
+            let original = pixels.clone();
+            let channel = color_type.channels() - 1;
+            let value = (1u32 << (depth - 1)) as u16 | 1;
+
+            pixels.set_sample(3, 2, channel, value);
+            assert_eq!(pixels.sample(3, 2, channel), value);
+
+            pixels.set_sample(3, 2, channel, original.sample(3, 2, channel));
+            assert_eq!(pixels, original);
+        }
+    }
+
+    #[test]
+    fn test_pixels_length_is_checked() {
+        let ihdr = ihdr(3, 2, ColorType::Truecolor, 8, Interlace::None);
+        assert!(Pixels::new(ihdr, vec![0; 17]).is_err());
+    }
+
+    #[test]
+    fn test_invalid_filter_type() {
+        let pixels = testing_pixels(ihdr(4, 4, ColorType::Grayscale, 8, Interlace::None));
+        let png = encode(&pixels);
+
+        // Re-compress the stream with an unknown filter on the third row
+        let mut stream = Vec::new();
+        let compressed: Vec<u8> = png.chunks_by_type("IDAT").flat_map(|c| c.data()).copied().collect();
+        ZlibDecoder::new(compressed.as_slice()).read_to_end(&mut stream).unwrap();
+        stream[2 * 5] = 9;
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&stream).unwrap();
+
+        let mut chunks = png.chunks().to_vec();
+        chunks.retain(|chunk| chunk.chunk_type().to_string() != "IDAT");
+        chunks.insert(1, Chunk::new(ChunkType::from_str("IDAT").unwrap(), encoder.finish().unwrap()));
+
+        match Png::from_chunks(chunks).pixels() {
+            Err(Error::InvalidPayload { location, reason }) => {
+                assert_eq!(location, Location::in_chunk(8 + 25 + 8, 1));
+                assert!(reason.contains("unknown filter type 9 in row 2"));
+            }
+            other => panic!("expected payload error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_truncated_image_data() {
+        let pixels = testing_pixels(ihdr(20, 20, ColorType::Truecolor, 8, Interlace::None));
+        let mut png = encode(&pixels);
+        let last_idat = png.chunks().len() - 2;
+        png.chunks_mut().remove(last_idat);
+
+        assert!(matches!(png.pixels(), Err(Error::InvalidPayload { .. })));
+    }
+
+    #[test]
+    fn test_missing_image_data() {
+        let ihdr = ihdr(1, 1, ColorType::Grayscale, 8, Interlace::None);
+        let png = Png::from_chunks(vec![ihdr.to_chunk()]);
+        assert!(matches!(png.pixels(), Err(Error::ChunkNotFound { .. })));
+    }
+
+    #[test]
+    fn test_huge_dimensions_are_refused() {
+        let ihdr = ihdr(Ihdr::MAX_DIMENSION, Ihdr::MAX_DIMENSION, ColorType::Grayscale, 8, Interlace::None);
+        let idat = Chunk::new(ChunkType::from_str("IDAT").unwrap(), vec![0x78, 0x9c, 3, 0, 0, 0, 0, 1]);
+        let png = Png::from_chunks(vec![ihdr.to_chunk(), idat]);
+        assert!(png.pixels().is_err());
+    }
+
+    #[test]
+    fn test_image_data_is_bounded() {
+        // Far too little data for a 16384x16384 image
+        let huge = ihdr(1 << 14, 1 << 14, ColorType::Grayscale, 8, Interlace::None);
+        let compressed = ZlibEncoder::new(Vec::new(), Compression::default()).finish().unwrap();
+        let idat = Chunk::new(ChunkType::from_str("IDAT").unwrap(), compressed);
+        let png = Png::from_chunks(vec![huge.to_chunk(), idat]);
+        assert!(matches!(png.pixels(), Err(Error::InvalidPayload { reason, .. }) if reason.contains("too few")));
+
+        // More data than the image needs
+        let small = ihdr(4, 4, ColorType::Grayscale, 8, Interlace::None);
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[0; 21]).unwrap();
+        let idat = Chunk::new(ChunkType::from_str("IDAT").unwrap(), encoder.finish().unwrap());
+        let png = Png::from_chunks(vec![small.to_chunk(), idat]);
+        match png.pixels() {
+            Err(Error::InvalidPayload { reason, .. }) => {
+                assert_eq!(reason, "image data holds more than 20 bytes, expected 20")
+            }
+            other => panic!("expected payload error, got {:?}", other),
+        }
+    }
+}
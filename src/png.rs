@@ -1,119 +1,278 @@
+//! PNG files as a signature followed by a list of chunks.
+
 use std::{fmt::Display};
-use std::convert::{TryFrom, TryInto};
+use std::convert::TryFrom;
 
 use crate::{
-    chunk::{Chunk},
-    Error, Result,
+    chunk::{Chunk, ChunkRef},
+    ihdr::Ihdr,
+    Error, Location, Result,
 };
 
+/// A PNG file: the 8 byte signature followed by its chunks, and whatever
+/// bytes were appended after IEND.
 pub struct Png {
     header: [u8; 8],
     chunks: Vec<Chunk>,
+    trailing_data: Vec<u8>,
 }
 
 impl Png {
-    const STANDARD_HEADER: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+    pub(crate) const STANDARD_HEADER: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
 
 
+    /// Creates a PNG with the standard signature and the given chunks.
     pub fn from_chunks(chunks: Vec<Chunk>) -> Self {
         Self {
             header: Self::STANDARD_HEADER,
-            chunks: chunks,
+            chunks,
+            trailing_data: Vec::new(),
         }
     }
 
+    /// Appends a chunk after the last chunk of the file.
     pub fn append_chunk(&mut self, chunk: Chunk) {
         self.chunks.push(chunk);
     }
 
-    pub fn remove_chunk(&mut self, chunk_type: &str) -> Result<Chunk> {
-        let index = self.chunks
+    /// Inserts a chunk so that it ends up at `index`, shifting the chunks
+    /// after it. Fails if `index` is past the end of the file.
+    pub fn insert_at(&mut self, index: usize, chunk: Chunk) -> Result<()> {
+        if index > self.chunks.len() {
+            return Err(Error::InvalidChunkOrder {
+                location: Location::default(),
+                reason: format!(
+                    "can't insert at index {}, there are only {} chunks",
+                    index,
+                    self.chunks.len()
+                ),
+            });
+        }
+
+        self.chunks.insert(index, chunk);
+        Ok(())
+    }
+
+    /// Inserts a chunk right before the first chunk of the given type.
+    pub fn insert_before(&mut self, chunk_type: &str, chunk: Chunk) -> Result<()> {
+        let index = self.position(chunk_type)?;
+        self.insert_at(index, chunk)
+    }
+
+    /// Inserts a chunk right after the last chunk of the given type.
+    pub fn insert_after(&mut self, chunk_type: &str, chunk: Chunk) -> Result<()> {
+        let index = self
+            .chunks
             .iter()
-            .position(|chunk| chunk.chunk_type().to_string().as_str() == chunk_type);
+            .rposition(|chunk| chunk.chunk_type().to_string().as_str() == chunk_type)
+            .ok_or_else(|| Error::ChunkNotFound { chunk_type: chunk_type.to_string() })?;
+        self.insert_at(index + 1, chunk)
+    }
 
-        match index {
-            Some(i) => Ok(self.chunks.remove(i)),
-            None => Err("Chunk type not found".into())
-        }
+    /// Index of the first chunk of the given type.
+    fn position(&self, chunk_type: &str) -> Result<usize> {
+        self.chunks
+            .iter()
+            .position(|chunk| chunk.chunk_type().to_string().as_str() == chunk_type)
+            .ok_or_else(|| Error::ChunkNotFound { chunk_type: chunk_type.to_string() })
+    }
+
+    /// Removes the first chunk of the given type and returns it.
+    pub fn remove_chunk(&mut self, chunk_type: &str) -> Result<Chunk> {
+        let index = self.position(chunk_type)?;
+        Ok(self.chunks.remove(index))
     }
 
+    /// The 8 byte PNG signature.
     pub fn header(&self) -> &[u8; 8] {
         &self.header
     }
 
+    /// All chunks in file order.
     pub fn chunks(&self) -> &[Chunk] {
         self.chunks.as_slice()
     }
 
+    pub(crate) fn chunks_mut(&mut self) -> &mut Vec<Chunk> {
+        &mut self.chunks
+    }
+
+    /// Offset in the serialized file of the data of the chunk at `index`.
+    pub(crate) fn chunk_data_offset(&self, index: usize) -> u64 {
+        let before: usize = self.chunks[..index]
+            .iter()
+            .map(|chunk| chunk.length() + Chunk::METADATA_BYTES_LEN)
+            .sum();
+        (Self::STANDARD_HEADER.len() + before + Chunk::DATA_OFFSET) as u64
+    }
+
+    /// The first chunk of the given type, if any.
     pub fn chunk_by_type(&self, chunk_type: &str) -> Option<&Chunk> {
         self.chunks
             .iter()
             .find(|chunk| chunk.chunk_type().to_string().as_str() == chunk_type)
     }
 
+    /// The decoded image header.
+    ///
+    /// Fails if there is no IHDR chunk or it isn't valid.
+    pub fn ihdr(&self) -> Result<Ihdr> {
+        let index = self.position("IHDR")?;
+        let base = self.chunk_data_offset(index) - Chunk::DATA_OFFSET as u64;
+        Ihdr::try_from(&self.chunks[index]).map_err(|e| e.relocate(base, Some(index)))
+    }
+
+    /// All chunks of the given type, in file order.
+    pub fn chunks_by_type<'a>(&'a self, chunk_type: &'a str) -> impl Iterator<Item = &'a Chunk> + 'a {
+        self.chunks
+            .iter()
+            .filter(move |chunk| chunk.chunk_type().to_string().as_str() == chunk_type)
+    }
+
+    /// Removes every chunk of the given type and returns them in file order.
+    pub fn remove_chunks(&mut self, chunk_type: &str) -> Result<Vec<Chunk>> {
+        let (removed, kept) = std::mem::take(&mut self.chunks)
+            .into_iter()
+            .partition(|chunk| chunk.chunk_type().to_string().as_str() == chunk_type);
+        self.chunks = kept;
+
+        if removed.is_empty() {
+            return Err(Error::ChunkNotFound { chunk_type: chunk_type.to_string() });
+        }
+        Ok(removed)
+    }
+
+    /// Bytes stored after the IEND chunk, such as an appended archive.
+    /// Decoders ignore them.
+    pub fn trailing_data(&self) -> &[u8] {
+        &self.trailing_data
+    }
+
+    /// Replaces the bytes written after the last chunk.
+    ///
+    /// They are only told apart from chunks when read back if the last
+    /// chunk is IEND.
+    pub fn set_trailing_data(&mut self, data: Vec<u8>) {
+        self.trailing_data = data;
+    }
+
+    /// Removes the bytes after the last chunk and returns them.
+    pub fn take_trailing_data(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.trailing_data)
+    }
+
+    /// Parses the trailing data as a sequence of chunks, as written by
+    /// tools that append chunks after IEND. Fails if it isn't one; empty
+    /// trailing data gives no chunks.
+    pub fn trailing_chunks(&self) -> Result<Vec<Chunk>> {
+        let base = self.chunk_data_offset(self.chunks.len()) as usize - Chunk::DATA_OFFSET;
+        let mut pointer = 0;
+        let mut chunks = Vec::new();
+
+        while pointer < self.trailing_data.len() {
+            let chunk = Chunk::try_from(&self.trailing_data[pointer..])
+                .map_err(|e| e.relocate((base + pointer) as u64, Some(self.chunks.len() + chunks.len())))?;
+            pointer += chunk.length() + Chunk::METADATA_BYTES_LEN;
+            chunks.push(chunk);
+        }
+
+        Ok(chunks)
+    }
+
+    /// The PNG serialized as a file, trailing data included.
     pub fn as_bytes(&self) -> Vec<u8> {
         self.header
             .iter()
             .copied()
             .chain(self.chunks().iter().flat_map(|c| c.as_bytes()))
+            .chain(self.trailing_data.iter().copied())
             .collect()
     }
-}
-
-#[derive(Debug)]
-pub enum PngError {
-    InvalidLength,
-    InvalidHeader([u8; 8]),
-}
-
-impl std::error::Error for PngError {}
 
-impl Display for PngError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            PngError::InvalidLength => {
-                write!(f, "Invalid length for PNG")
-            }
-            PngError::InvalidHeader(header) => {
-                write!(
-                    f,
-                    "Invalid Header, expected: {:?} \nactual: {:?}",
-                    Png::STANDARD_HEADER,
-                    header
-                )
-            }
-        }
+    /// Length of [`Png::as_bytes`], without serializing the PNG.
+    pub fn encoded_len(&self) -> u64 {
+        self.chunk_data_offset(self.chunks.len()) - Chunk::DATA_OFFSET as u64 + self.trailing_data.len() as u64
     }
 }
 
 impl TryFrom<&[u8]> for Png {
     type Error = Error;
 
+    /// Parses the signature and the chunks up to IEND. Anything after IEND
+    /// is kept as [`Png::trailing_data`].
     fn try_from(value: &[u8]) -> Result<Png> {
-        if value.len() < 8 {
-            return Err(Box::from(PngError::InvalidLength));
-        }
+        PngRef::try_from(value).map(|png| png.to_png())
+    }
+}
+
+/// A PNG whose chunks borrow their data from the buffer it was parsed
+/// from, for scanning files without copying them. [`Png`] is the owned
+/// version, for editing.
+pub struct PngRef<'a> {
+    chunks: Vec<ChunkRef<'a>>,
+    trailing_data: &'a [u8],
+}
 
-        let header: [u8;8] = value[..8].try_into()?;
+impl<'a> PngRef<'a> {
+    /// All chunks in file order.
+    pub fn chunks(&self) -> &[ChunkRef<'a>] {
+        &self.chunks
+    }
+
+    /// The first chunk of the given type, if any.
+    pub fn chunk_by_type(&self, chunk_type: &str) -> Option<&ChunkRef<'a>> {
+        self.chunks
+            .iter()
+            .find(|chunk| chunk_type.as_bytes() == chunk.chunk_type().bytes())
+    }
+
+    /// Bytes stored after the IEND chunk.
+    pub fn trailing_data(&self) -> &'a [u8] {
+        self.trailing_data
+    }
+
+    /// Copies the PNG into an owned [`Png`].
+    pub fn to_png(&self) -> Png {
+        let mut png = Png::from_chunks(self.chunks.iter().map(ChunkRef::to_chunk).collect());
+        png.trailing_data = self.trailing_data.to_vec();
+        png
+    }
+}
 
-        if header != Self::STANDARD_HEADER {
-            return Err(Box::from(PngError::InvalidHeader(header)));
+impl<'a> TryFrom<&'a [u8]> for PngRef<'a> {
+    type Error = Error;
+
+    /// Parses the signature and the chunks up to IEND, failing like
+    /// [`Png::try_from`].
+    fn try_from(value: &'a [u8]) -> Result<Self> {
+        if value.len() < 8 || value[..8] != Png::STANDARD_HEADER {
+            return Err(Error::InvalidHeader {
+                location: Location::at(0),
+                found: value[..value.len().min(8)].to_vec(),
+            });
         }
 
         let mut pointer: usize = 8;
-        let mut chunks: Vec<Chunk> = Vec::new();
+        let mut chunks = Vec::new();
 
         while pointer < value.len() {
-            let length: usize = (u32::from_be_bytes(value[pointer..pointer+4].try_into()?) + 12) as usize;
+            // ChunkRef::try_from checks the declared length against what is
+            // left of the input, so the slice never has to be bounded here.
+            let chunk = ChunkRef::try_from(&value[pointer..])
+                .map_err(|e| e.relocate(pointer as u64, Some(chunks.len())))?;
 
-            let chunk: Chunk = Chunk::try_from(&value[pointer..pointer+length])?;
+            pointer += chunk.length() + Chunk::METADATA_BYTES_LEN;
+            let is_iend = chunk.chunk_type().bytes() == *b"IEND";
             chunks.push(chunk);
-
-            pointer += length;
+            if is_iend {
+                break;
+            }
         }
 
-
-        Ok(Self::from_chunks(chunks))
+        Ok(Self {
+            chunks,
+            trailing_data: &value[pointer..],
+        })
     }
 }
 
@@ -124,6 +283,9 @@ impl Display for Png {
         for chunk in self.chunks().iter() {
             write!(f, "{}\t", chunk)?;
         }
+        if !self.trailing_data.is_empty() {
+            write!(f, "{} bytes of trailing data\t", self.trailing_data.len())?;
+        }
 
         write!(f, "}}")
     }
@@ -131,21 +293,18 @@ impl Display for Png {
 
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use crate::chunk_type::ChunkType;
     use crate::chunk::Chunk;
-    use std::str::FromStr;
     use std::convert::TryFrom;
 
     fn testing_chunks() -> Vec<Chunk> {
-        let mut chunks = Vec::new();
-
-        chunks.push(chunk_from_strings("FrSt", "I am the first chunk").unwrap());
-        chunks.push(chunk_from_strings("miDl", "I am another chunk").unwrap());
-        chunks.push(chunk_from_strings("LASt", "I am the last chunk").unwrap());
-
-        chunks
+        vec![
+            chunk_from_strings("FrSt", "I am the first chunk").unwrap(),
+            chunk_from_strings("miDl", "I am another chunk").unwrap(),
+            chunk_from_strings("LASt", "I am the last chunk").unwrap(),
+        ]
     }
 
     fn testing_png() -> Png {
@@ -206,6 +365,72 @@ mod tests {
         assert!(png.is_err());
     }
 
+    #[test]
+    fn test_invalid_header_error() {
+        let png = Png::try_from(&PNG_FILE[..5]);
+        assert!(matches!(png, Err(Error::InvalidHeader { ref found, .. }) if found == &PNG_FILE[..5]));
+    }
+
+    #[test]
+    fn test_invalid_crc_location() {
+        let mut bytes = PNG_FILE.to_vec();
+        // The last byte of the sRGB chunk, the second chunk of the file
+        bytes[45] ^= 0xff;
+
+        match Png::try_from(bytes.as_ref()) {
+            Err(Error::InvalidCrc { location, .. }) => {
+                assert_eq!(location, Location::in_chunk(42, 1));
+            }
+            Err(other) => panic!("expected crc error, got {:?}", other),
+            Ok(_) => panic!("expected crc error"),
+        }
+    }
+
+    #[test]
+    fn test_remove_missing_chunk() {
+        let mut png = testing_png();
+        let error = png.remove_chunk("TeSt").unwrap_err();
+        assert!(matches!(error, Error::ChunkNotFound { ref chunk_type } if chunk_type == "TeSt"));
+    }
+
+    #[test]
+    fn test_truncated_file_does_not_panic() {
+        for end in 0..PNG_FILE.len() {
+            // Cutting the file between two chunks leaves a shorter valid file
+            if let Ok(png) = Png::try_from(&PNG_FILE[..end]) {
+                assert_eq!(png.as_bytes(), &PNG_FILE[..end]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_corrupted_file_does_not_panic() {
+        for position in 0..PNG_FILE.len() {
+            for mask in [0x01, 0x80, 0xff] {
+                let mut bytes = PNG_FILE.to_vec();
+                bytes[position] ^= mask;
+                let _ = Png::try_from(bytes.as_ref());
+            }
+        }
+    }
+
+    #[test]
+    fn test_hostile_chunk_length() {
+        let mut bytes = Png::STANDARD_HEADER.to_vec();
+        bytes.extend_from_slice(&u32::MAX.to_be_bytes());
+        bytes.extend_from_slice(b"IDAT");
+        bytes.extend_from_slice(&[0; 8]);
+
+        match Png::try_from(bytes.as_ref()) {
+            Err(Error::InvalidChunkLength { location, length, .. }) => {
+                assert_eq!(location, Location::in_chunk(8, 0));
+                assert_eq!(length, u32::MAX as u64);
+            }
+            Err(other) => panic!("expected length error, got {:?}", other),
+            Ok(_) => panic!("expected length error"),
+        }
+    }
+
     #[test]
     fn test_invalid_chunk() {
         let mut chunk_bytes: Vec<u8> = testing_chunks()
@@ -263,6 +488,71 @@ mod tests {
         assert!(chunk.is_none());
     }
 
+    #[test]
+    fn test_insert_at() {
+        let mut png = testing_png();
+        png.insert_at(0, chunk_from_strings("TeSt", "first").unwrap()).unwrap();
+        png.insert_at(4, chunk_from_strings("TeSt", "last").unwrap()).unwrap();
+
+        let types: Vec<String> = png.chunks().iter().map(|c| c.chunk_type().to_string()).collect();
+        assert_eq!(types, ["TeSt", "FrSt", "miDl", "LASt", "TeSt"]);
+        assert!(matches!(
+            png.insert_at(6, chunk_from_strings("TeSt", "x").unwrap()),
+            Err(Error::InvalidChunkOrder { .. })
+        ));
+    }
+
+    #[test]
+    fn test_insert_before_and_after() {
+        let mut png = testing_png();
+        png.insert_after("FrSt", chunk_from_strings("FrSt", "second first").unwrap()).unwrap();
+        png.insert_before("FrSt", chunk_from_strings("TeSt", "before").unwrap()).unwrap();
+        png.insert_after("FrSt", chunk_from_strings("TeSt", "after").unwrap()).unwrap();
+
+        let data: Vec<String> = png.chunks().iter().map(|c| c.data_as_string().unwrap()).collect();
+        assert_eq!(
+            data,
+            ["before", "I am the first chunk", "second first", "after", "I am another chunk", "I am the last chunk"]
+        );
+
+        let error = png.insert_before("IEND", chunk_from_strings("TeSt", "x").unwrap()).unwrap_err();
+        assert!(matches!(error, Error::ChunkNotFound { ref chunk_type } if chunk_type == "IEND"));
+        assert!(png.insert_after("IEND", chunk_from_strings("TeSt", "x").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_insert_before_iend_in_image_file() {
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        png.insert_before("IEND", chunk_from_strings("ruSt", "hidden").unwrap()).unwrap();
+
+        let chunks = png.chunks();
+        assert_eq!(chunks[chunks.len() - 2].chunk_type().to_string(), "ruSt");
+        assert_eq!(chunks[chunks.len() - 1].chunk_type().to_string(), "IEND");
+    }
+
+    #[test]
+    fn test_chunks_by_type() {
+        let mut png = testing_png();
+        png.append_chunk(chunk_from_strings("miDl", "I am the middle again").unwrap());
+
+        let data: Vec<String> = png
+            .chunks_by_type("miDl")
+            .map(|chunk| chunk.data_as_string().unwrap())
+            .collect();
+        assert_eq!(data, ["I am another chunk", "I am the middle again"]);
+    }
+
+    #[test]
+    fn test_remove_chunks() {
+        let mut png = testing_png();
+        png.append_chunk(chunk_from_strings("miDl", "I am the middle again").unwrap());
+
+        assert_eq!(png.remove_chunks("miDl").unwrap().len(), 2);
+        assert_eq!(png.chunks().len(), 2);
+        assert!(png.chunk_by_type("miDl").is_none());
+        assert!(matches!(png.remove_chunks("miDl"), Err(Error::ChunkNotFound { .. })));
+    }
+
     #[test]
     fn test_png_from_image_file() {
         let png = Png::try_from(&PNG_FILE[..]);
@@ -277,6 +567,89 @@ mod tests {
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn test_encoded_len() {
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        assert_eq!(png.encoded_len(), PNG_FILE.len() as u64);
+
+        png.set_trailing_data(b"trailing".to_vec());
+        assert_eq!(png.encoded_len(), png.as_bytes().len() as u64);
+        assert_eq!(Png::from_chunks(Vec::new()).encoded_len(), 8);
+    }
+
+    #[test]
+    fn test_trailing_data() {
+        let appended = b"PK\x03\x04 not a chunk at all";
+        let bytes = [&PNG_FILE[..], appended].concat();
+
+        let png = Png::try_from(bytes.as_ref()).unwrap();
+        assert_eq!(png.chunks().len(), 7);
+        assert_eq!(png.chunks().last().unwrap().chunk_type().to_string(), "IEND");
+        assert_eq!(png.trailing_data(), appended);
+        assert_eq!(png.as_bytes(), bytes);
+        assert!(Png::try_from(&PNG_FILE[..]).unwrap().trailing_data().is_empty());
+    }
+
+    #[test]
+    fn test_take_and_set_trailing_data() {
+        let bytes = [&PNG_FILE[..], b"appended"].concat();
+        let mut png = Png::try_from(bytes.as_ref()).unwrap();
+
+        assert_eq!(png.take_trailing_data(), b"appended");
+        assert_eq!(png.as_bytes(), PNG_FILE);
+
+        png.set_trailing_data(b"something else".to_vec());
+        let png = Png::try_from(png.as_bytes().as_ref()).unwrap();
+        assert_eq!(png.trailing_data(), b"something else");
+    }
+
+    #[test]
+    fn test_trailing_chunks() {
+        let chunk = chunk_from_strings("ruSt", "after the end").unwrap();
+        let bytes = [&PNG_FILE[..], &chunk.as_bytes()].concat();
+        let png = Png::try_from(bytes.as_ref()).unwrap();
+
+        let chunks = png.trailing_chunks().unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].data_as_string().unwrap(), "after the end");
+
+        let bytes = [&bytes[..], b"junk"].concat();
+        match Png::try_from(bytes.as_ref()).unwrap().trailing_chunks() {
+            Err(error) => {
+                let location = error.location().unwrap();
+                assert_eq!(location.offset, (PNG_FILE.len() + chunk.as_bytes().len()) as u64);
+                assert_eq!(location.chunk_index, Some(8));
+            }
+            Ok(_) => panic!("expected an error"),
+        }
+    }
+
+    #[test]
+    fn test_png_ref() {
+        let bytes = [&PNG_FILE[..], b"trailing"].concat();
+        let png_ref = PngRef::try_from(bytes.as_ref()).unwrap();
+        let png = Png::try_from(bytes.as_ref()).unwrap();
+
+        assert_eq!(png_ref.chunks().len(), png.chunks().len());
+        for (borrowed, owned) in png_ref.chunks().iter().zip(png.chunks()) {
+            assert_eq!(borrowed.to_chunk(), *owned);
+        }
+        let rust = png_ref.chunk_by_type("RuSt").unwrap();
+        assert!(bytes.as_ptr_range().contains(&rust.data().as_ptr()));
+        assert_eq!(png_ref.trailing_data(), b"trailing");
+        assert_eq!(png_ref.to_png().as_bytes(), bytes);
+    }
+
+    #[test]
+    fn test_png_ref_errors() {
+        let mut bytes = PNG_FILE.to_vec();
+        bytes[1000] ^= 1;
+        let error = PngRef::try_from(bytes.as_ref()).err().unwrap();
+        assert!(matches!(error, Error::InvalidCrc { .. }));
+        assert_eq!(error.location().unwrap().chunk_index, Some(4));
+        assert!(PngRef::try_from(&PNG_FILE[1..]).is_err());
+    }
+
     #[test]
     fn test_png_trait_impls() {
         let chunk_bytes: Vec<u8> = testing_chunks()
@@ -296,7 +669,7 @@ mod tests {
     }
 
     // This is the raw bytes for a shrunken version of the `dice.png` image on Wikipedia
-    const PNG_FILE: [u8; 4803] = [
+    pub(crate) const PNG_FILE: [u8; 4803] = [
         137, 80, 78, 71, 13, 10, 26, 10, 0, 0, 0, 13, 73, 72, 68, 82, 0, 0, 0, 50, 0, 0, 0, 50, 8,
         6, 0, 0, 0, 30, 63, 136, 177, 0, 0, 0, 1, 115, 82, 71, 66, 0, 174, 206, 28, 233, 0, 0, 0,
         4, 103, 65, 77, 65, 0, 0, 177, 143, 11, 252, 97, 5, 0, 0, 0, 9, 112, 72, 89, 115, 0, 0, 14,
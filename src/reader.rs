@@ -0,0 +1,359 @@
+//! Reading chunks one at a time from any [`Read`].
+
+use std::io::{self, Read};
+
+use crate::chunk::Chunk;
+use crate::chunk_type::ChunkType;
+use crate::png::Png;
+use crate::{Error, Location, Result};
+
+/// Size of the blocks the data of skipped chunks is read in.
+const SKIP_BLOCK_LEN: usize = 8 * 1024;
+
+/// Decides from its type whether a chunk is kept.
+type Filter = Box<dyn FnMut(&ChunkType) -> bool>;
+
+/// Yields the chunks of a PNG as they are read, checking their CRC, up to
+/// and including IEND.
+///
+/// Only the chunk being read is held in memory, and not even that for
+/// chunks left out by [`ChunkReader::keep_only`]. Errors are located like
+/// those of [`Png::try_from`], and end the iteration.
+///
+/// ```
+/// use pngme::reader::ChunkReader;
+///
+/// # let file = pngme::png::Png::from_chunks(Vec::new()).as_bytes();
+/// let reader = ChunkReader::new(file.as_slice())?
+///     .keep_only(|chunk_type| chunk_type.to_string() == "ruSt");
+/// for chunk in reader {
+///     println!("{}", chunk?.data_as_string()?);
+/// }
+/// # Ok::<(), pngme::Error>(())
+/// ```
+pub struct ChunkReader<R> {
+    reader: R,
+    offset: u64,
+    index: usize,
+    keep: Option<Filter>,
+    done: bool,
+}
+
+impl<R: Read> ChunkReader<R> {
+    /// Reads and checks the PNG signature.
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut signature = [0; 8];
+        let read = read_full(&mut reader, &mut signature).map_err(|source| Error::io(0, source))?;
+        if signature[..read] != Png::STANDARD_HEADER {
+            return Err(Error::InvalidHeader {
+                location: Location::at(0),
+                found: signature[..read].to_vec(),
+            });
+        }
+
+        Ok(Self {
+            reader,
+            offset: signature.len() as u64,
+            index: 0,
+            keep: None,
+            done: false,
+        })
+    }
+
+    /// Only yields the chunks `keep` returns true for. The others are still
+    /// checked against their CRC, but their data is dropped as it is read.
+    pub fn keep_only(mut self, keep: impl FnMut(&ChunkType) -> bool + 'static) -> Self {
+        self.keep = Some(Box::new(keep));
+        self
+    }
+
+    /// Number of bytes read so far. Once the reader is done, this is where
+    /// any trailing data starts.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// The underlying reader. After IEND, it is left at the first byte of
+    /// trailing data.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Reads chunks until one is kept, IEND has been read or the input ends.
+    fn read_kept_chunk(&mut self) -> Result<Option<Chunk>> {
+        while !self.done {
+            let start = self.offset;
+            let index = self.index;
+            let chunk = self
+                .read_chunk()
+                .map_err(|e| e.relocate(start, Some(index)))?;
+            self.index += 1;
+
+            match chunk {
+                Some(chunk) => {
+                    self.done = chunk.chunk_type().bytes() == *b"IEND";
+                    return Ok(Some(chunk));
+                }
+                // Nothing read at all: the input ended
+                None if self.offset == start => self.done = true,
+                None => {}
+            }
+        }
+        Ok(None)
+    }
+
+    /// Reads the chunk at the current offset. Returns `None` at the end of
+    /// the input, or after skipping a chunk that isn't kept. Error
+    /// locations are relative to the start of the chunk.
+    fn read_chunk(&mut self) -> Result<Option<Chunk>> {
+        let start = self.offset;
+        let mut header = [0; Chunk::DATA_OFFSET];
+        let read = self.read(&mut header)?;
+        if read == 0 {
+            return Ok(None);
+        }
+
+        let length = match header.get(..4) {
+            Some(bytes) if read >= 4 => u32::from_be_bytes(bytes.try_into().unwrap()),
+            _ => 0,
+        };
+        if read < header.len() {
+            return Err(Error::InvalidChunkLength {
+                location: Location::default(),
+                length: length as u64,
+                available: 0,
+            });
+        }
+        if length > Chunk::MAX_LENGTH {
+            return Err(Error::InvalidChunkLength {
+                location: Location::default(),
+                length: length as u64,
+                available: Chunk::MAX_LENGTH as u64,
+            });
+        }
+
+        let chunk_type = ChunkType::try_from(<[u8; 4]>::try_from(&header[4..]).unwrap())
+            .map_err(|e| e.relocate(4, None))?;
+        let kept = self.keep.as_mut().is_none_or(|keep| keep(&chunk_type));
+
+        let mut digest = Chunk::crc_digest(&chunk_type);
+        let mut data = Vec::new();
+        let data_read = if kept {
+            let read = (&mut self.reader)
+                .take(length as u64)
+                .read_to_end(&mut data)
+                .map_err(|source| Error::io(self.offset - start, source))?;
+            self.offset += read as u64;
+            digest.update(&data);
+            read
+        } else {
+            let mut block = [0; SKIP_BLOCK_LEN];
+            let mut remaining = length as usize;
+            while remaining > 0 {
+                let len = remaining.min(block.len());
+                let read = self.read(&mut block[..len])?;
+                digest.update(&block[..read]);
+                remaining -= read;
+                if read < len {
+                    break;
+                }
+            }
+            length as usize - remaining
+        };
+
+        let mut crc = [0; 4];
+        let crc_read = if data_read == length as usize {
+            self.read(&mut crc)?
+        } else {
+            0
+        };
+        if crc_read < crc.len() {
+            return Err(Error::InvalidChunkLength {
+                location: Location::default(),
+                length: length as u64,
+                available: (data_read + crc_read).saturating_sub(crc.len()) as u64,
+            });
+        }
+
+        let crc = u32::from_be_bytes(crc);
+        let actual = digest.finalize();
+        if crc != actual {
+            return Err(Error::InvalidCrc {
+                location: Location::at((Chunk::DATA_OFFSET + length as usize) as u64),
+                expected: crc,
+                actual,
+            });
+        }
+
+        if !kept {
+            // Skipped chunks only end the reader when they are IEND
+            self.done = chunk_type.bytes() == *b"IEND";
+            return Ok(None);
+        }
+        Ok(Some(Chunk::new(chunk_type, data)))
+    }
+
+    /// Fills as much of `buf` as the input allows, moving the offset on.
+    /// Errors are located relative to the start of the chunk being read.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let read = read_full(&mut self.reader, buf).map_err(|source| Error::io(0, source))?;
+        self.offset += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Read> Iterator for ChunkReader<R> {
+    type Item = Result<Chunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.read_kept_chunk() {
+            Ok(chunk) => chunk.map(Ok),
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Like [`Read::read_exact`], but returns how much was read when the input
+/// ends early instead of failing.
+pub(crate) fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png::tests::PNG_FILE;
+
+    fn chunk_types(chunks: &[Chunk]) -> Vec<String> {
+        chunks.iter().map(|chunk| chunk.chunk_type().to_string()).collect()
+    }
+
+    /// A reader handing out at most `step` bytes per call.
+    struct Trickle<'a> {
+        data: &'a [u8],
+        step: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.step).min(self.data.len());
+            buf[..len].copy_from_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn test_reads_all_chunks() {
+        let png = Png::try_from(&PNG_FILE[..]).unwrap();
+        for step in [1, 3, 4096] {
+            let reader = Trickle { data: &PNG_FILE, step };
+            let chunks: Vec<Chunk> = ChunkReader::new(reader)
+                .unwrap()
+                .collect::<Result<_>>()
+                .unwrap();
+            assert_eq!(chunks, png.chunks());
+        }
+    }
+
+    #[test]
+    fn test_keep_only() {
+        let mut reader = ChunkReader::new(&PNG_FILE[..])
+            .unwrap()
+            .keep_only(|chunk_type| chunk_type.is_critical());
+        let chunks: Vec<Chunk> = reader.by_ref().collect::<Result<_>>().unwrap();
+
+        assert_eq!(chunk_types(&chunks), ["IHDR", "IDAT", "RuSt", "IEND"]);
+        assert_eq!(reader.offset(), PNG_FILE.len() as u64);
+    }
+
+    #[test]
+    fn test_skipped_chunks_are_checked() {
+        let mut bytes = PNG_FILE.to_vec();
+        // A byte of the IDAT data
+        bytes[1000] ^= 0x01;
+        let mut reader = ChunkReader::new(bytes.as_slice())
+            .unwrap()
+            .keep_only(|chunk_type| chunk_type.to_string() == "RuSt");
+
+        let error = reader.next().unwrap().unwrap_err();
+        let expected = Png::try_from(bytes.as_slice()).err().unwrap();
+        assert_eq!(error.to_string(), expected.to_string());
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn test_stops_after_iend() {
+        let bytes = [&PNG_FILE[..], b"trailing"].concat();
+        let mut reader = ChunkReader::new(bytes.as_slice()).unwrap();
+        assert_eq!(reader.by_ref().count(), 7);
+        assert_eq!(reader.offset(), PNG_FILE.len() as u64);
+
+        let mut trailing = Vec::new();
+        reader.into_inner().read_to_end(&mut trailing).unwrap();
+        assert_eq!(trailing, b"trailing");
+    }
+
+    #[test]
+    fn test_errors_match_png() {
+        // Every truncation, kept or skipped
+        for len in 8..PNG_FILE.len() {
+            let bytes = &PNG_FILE[..len];
+            for keep_idat in [true, false] {
+                let reader = ChunkReader::new(bytes)
+                    .unwrap()
+                    .keep_only(move |chunk_type| keep_idat || chunk_type.to_string() != "IDAT");
+                let streamed = reader.collect::<Result<Vec<_>>>();
+
+                match Png::try_from(bytes) {
+                    Ok(png) => {
+                        let kept = png
+                            .chunks()
+                            .iter()
+                            .filter(|chunk| keep_idat || chunk.chunk_type().to_string() != "IDAT")
+                            .cloned()
+                            .collect::<Vec<_>>();
+                        assert_eq!(streamed.unwrap(), kept);
+                    }
+                    Err(e) => assert_eq!(streamed.unwrap_err().to_string(), e.to_string(), "at {}", len),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_signature() {
+        assert!(matches!(
+            ChunkReader::new(&PNG_FILE[..5]),
+            Err(Error::InvalidHeader { .. })
+        ));
+        let mut bytes = PNG_FILE.to_vec();
+        bytes[0] = 0;
+        assert!(matches!(
+            ChunkReader::new(bytes.as_slice()),
+            Err(Error::InvalidHeader { .. })
+        ));
+    }
+
+    #[test]
+    fn test_length_above_max() {
+        let mut bytes = PNG_FILE[..8].to_vec();
+        bytes.extend_from_slice(&u32::MAX.to_be_bytes());
+        bytes.extend_from_slice(b"IDAT");
+        let error = ChunkReader::new(bytes.as_slice()).unwrap().next().unwrap().unwrap_err();
+        assert!(matches!(error, Error::InvalidChunkLength { length, .. } if length == u32::MAX as u64));
+        assert_eq!(error.location(), Some(Location::in_chunk(8, 0)));
+    }
+}
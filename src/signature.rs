@@ -0,0 +1,450 @@
+//! Ed25519 signatures over a hidden message and the image carrying it.
+//!
+//! A signature is stored in its own `siGN` chunk, right after the message it
+//! covers. It records a SHA-256 digest of the message chunks and of each
+//! critical chunk type (`IHDR`, `PLTE`, `IDAT`), so verifying can tell which
+//! of them changed after signing:
+//!
+//! | Field                                        | Size        |
+//! |----------------------------------------------|-------------|
+//! | Magic `pmSG`                                 | 4           |
+//! | Version                                      | 1           |
+//! | Algorithm id (1 = Ed25519)                   | 1           |
+//! | Signer public key                            | 32          |
+//! | Message chunk type                           | 4           |
+//! | Digest count                                 | 1           |
+//! | Digests: chunk type and SHA-256 of its data  | count × 36  |
+//! | Signature over all the fields above          | 64          |
+//!
+//! The digest of a chunk type covers the data of every chunk of that type,
+//! in file order, so splitting `IDAT` differently doesn't change it.
+
+use std::fmt::{Debug, Display};
+use std::str::FromStr;
+
+use chacha20poly1305::aead::OsRng;
+use ed25519_dalek::{Signature, Signer};
+use sha2::{Digest, Sha256};
+
+use crate::chunk::Chunk;
+use crate::chunk_type::ChunkType;
+use crate::cursor::Cursor;
+use crate::encryption::parse_key;
+use crate::png::Png;
+use crate::{Error, Location, Result};
+
+/// Chunk type signatures are stored in.
+pub const SIGNATURE_CHUNK_TYPE: &str = "siGN";
+
+/// Critical chunk types covered by every signature.
+const SIGNED_CRITICAL_TYPES: [&str; 3] = ["IHDR", "PLTE", "IDAT"];
+
+const MAGIC: [u8; 4] = *b"pmSG";
+const VERSION: u8 = 1;
+const ALGORITHM_ED25519: u8 = 1;
+const DOMAIN: &[u8] = b"pngme signature v1";
+const DIGEST_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+
+const SIGNING_KEY_PREFIX: &str = "PNGME-SIGNING-KEY-";
+const VERIFYING_KEY_PREFIX: &str = "pngme-vk-";
+
+/// The outcome of checking a signature.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verification {
+    /// Public key the signature claims to be from.
+    pub signer: VerifyingKey,
+    /// Whether the signature matches the signer and the recorded digests.
+    pub signature_valid: bool,
+    /// Chunk types whose data no longer matches the recorded digests.
+    pub changed: Vec<ChunkType>,
+}
+
+impl Verification {
+    /// Whether the signature is valid and nothing changed since signing.
+    pub fn is_valid(&self) -> bool {
+        self.signature_valid && self.changed.is_empty()
+    }
+}
+
+/// Signs the chunks of type `message_type` of `png`, together with its
+/// critical chunks, and stores the signature right after the last of them.
+///
+/// A previous signature of the same message is replaced.
+pub fn sign(png: &mut Png, message_type: &ChunkType, key: &SigningKey) -> Result<()> {
+    if png.chunk_by_type(&message_type.to_string()).is_none() {
+        return Err(Error::ChunkNotFound {
+            chunk_type: message_type.to_string(),
+        });
+    }
+
+    let signature_type = signature_chunk_type();
+    png.chunks_mut()
+        .retain(|chunk| !is_signature_of(chunk, &signature_type, message_type));
+
+    let mut data = Vec::new();
+    data.extend_from_slice(&MAGIC);
+    data.push(VERSION);
+    data.push(ALGORITHM_ED25519);
+    data.extend_from_slice(key.0.verifying_key().as_bytes());
+    data.extend_from_slice(&message_type.bytes());
+
+    let digests = digests(png, message_type);
+    data.push(digests.len() as u8);
+    for (chunk_type, digest) in &digests {
+        data.extend_from_slice(&chunk_type.bytes());
+        data.extend_from_slice(digest);
+    }
+
+    let signature = key.0.sign(&signed_message(&data));
+    data.extend_from_slice(&signature.to_bytes());
+
+    let chunks = png.chunks_mut();
+    let last_message = chunks
+        .iter()
+        .rposition(|chunk| chunk.chunk_type() == message_type)
+        .unwrap();
+    chunks.insert(last_message + 1, Chunk::new(signature_type, data));
+
+    Ok(())
+}
+
+/// Checks the signature of the chunks of type `message_type` of `png`.
+///
+/// Fails with [`Error::ChunkNotFound`] when the message isn't signed, and
+/// with [`Error::InvalidPayload`] when the signature chunk is malformed. A
+/// signature that doesn't match, or chunks changed since signing, are
+/// reported in the returned [`Verification`].
+pub fn verify(png: &Png, message_type: &ChunkType) -> Result<Verification> {
+    let signature_type = signature_chunk_type();
+    let (index, chunk) = png
+        .chunks()
+        .iter()
+        .enumerate()
+        .find(|(_, chunk)| is_signature_of(chunk, &signature_type, message_type))
+        .ok_or_else(|| Error::ChunkNotFound {
+            chunk_type: SIGNATURE_CHUNK_TYPE.to_string(),
+        })?;
+
+    parse_and_verify(png, chunk.data())
+        .map_err(|e| e.relocate(png.chunk_data_offset(index), Some(index)))
+}
+
+fn parse_and_verify(png: &Png, data: &[u8]) -> Result<Verification> {
+    let mut cursor = Cursor::new(data);
+
+    if cursor.read_array::<4>()? != MAGIC {
+        return Err(Error::InvalidPayload {
+            location: Location::at(0),
+            reason: "not a signature".to_string(),
+        });
+    }
+    let version = cursor.read_u8()?;
+    if version != VERSION {
+        return Err(cursor.error(format!("unsupported signature version {}", version)));
+    }
+    let algorithm = cursor.read_u8()?;
+    if algorithm != ALGORITHM_ED25519 {
+        return Err(cursor.error(format!("unsupported signature algorithm {}", algorithm)));
+    }
+
+    let signer = ed25519_dalek::VerifyingKey::from_bytes(&cursor.read_array()?)
+        .map_err(|_| cursor.error("invalid signer public key"))?;
+    let message_type = ChunkType::try_from(cursor.read_array::<4>()?)
+        .map_err(|e| e.relocate(cursor.position() as u64 - 4, None))?;
+
+    let count = cursor.read_u8()? as usize;
+    let mut recorded = Vec::with_capacity(count);
+    for _ in 0..count {
+        let chunk_type = ChunkType::try_from(cursor.read_array::<4>()?)
+            .map_err(|e| e.relocate(cursor.position() as u64 - 4, None))?;
+        let digest: [u8; DIGEST_LEN] = cursor.read_array()?;
+        recorded.push((chunk_type, digest));
+    }
+
+    let signed = &data[..cursor.position()];
+    let signature = Signature::from_bytes(&cursor.read_array::<SIGNATURE_LEN>()?);
+    if !cursor.rest().is_empty() {
+        return Err(cursor.error("unexpected data after the signature"));
+    }
+
+    let signature_valid = signer
+        .verify_strict(&signed_message(signed), &signature)
+        .is_ok();
+
+    // Compare what was recorded with the image as it is now, including
+    // critical chunk types that appeared or vanished since signing.
+    let current = digests(png, &message_type);
+    let digest_of = |digests: &[(ChunkType, [u8; DIGEST_LEN])], chunk_type: &ChunkType| {
+        digests.iter().find(|(t, _)| t == chunk_type).map(|(_, d)| *d)
+    };
+    let mut changed: Vec<ChunkType> = Vec::new();
+    for (chunk_type, _) in recorded.iter().chain(current.iter()) {
+        if !changed.contains(chunk_type)
+            && digest_of(&recorded, chunk_type) != digest_of(&current, chunk_type)
+        {
+            changed.push(chunk_type.clone());
+        }
+    }
+
+    Ok(Verification {
+        signer: VerifyingKey(signer),
+        signature_valid,
+        changed,
+    })
+}
+
+fn signature_chunk_type() -> ChunkType {
+    ChunkType::from_str(SIGNATURE_CHUNK_TYPE).unwrap()
+}
+
+/// Whether `chunk` is a signature of the chunks of type `message_type`.
+fn is_signature_of(chunk: &Chunk, signature_type: &ChunkType, message_type: &ChunkType) -> bool {
+    let data = chunk.data();
+    chunk.chunk_type() == signature_type
+        && data.starts_with(&MAGIC)
+        && data.get(38..42) == Some(&message_type.bytes()[..])
+}
+
+/// Digests of the message chunks and of the critical chunk types present.
+fn digests(png: &Png, message_type: &ChunkType) -> Vec<(ChunkType, [u8; DIGEST_LEN])> {
+    let critical = SIGNED_CRITICAL_TYPES
+        .iter()
+        .map(|chunk_type| ChunkType::from_str(chunk_type).unwrap());
+
+    std::iter::once(message_type.clone())
+        .chain(critical)
+        .filter_map(|chunk_type| {
+            let mut hasher = Sha256::new();
+            let mut found = false;
+            for chunk in png.chunks().iter().filter(|c| *c.chunk_type() == chunk_type) {
+                hasher.update(chunk.data());
+                found = true;
+            }
+            found.then(|| (chunk_type, hasher.finalize().into()))
+        })
+        .collect()
+}
+
+fn signed_message(data: &[u8]) -> Vec<u8> {
+    [DOMAIN, data].concat()
+}
+
+/// An Ed25519 secret key used to sign messages.
+///
+/// Serialized as `PNGME-SIGNING-KEY-` followed by the key in hex.
+pub struct SigningKey(ed25519_dalek::SigningKey);
+
+impl SigningKey {
+    /// Generates a new random signing key.
+    pub fn generate() -> Self {
+        Self(ed25519_dalek::SigningKey::generate(&mut OsRng))
+    }
+
+    /// The public key signatures of this key are checked against.
+    pub fn verifying_key(&self) -> VerifyingKey {
+        VerifyingKey(self.0.verifying_key())
+    }
+}
+
+impl FromStr for SigningKey {
+    type Err = Error;
+
+    /// Parses a signing key, skipping blank lines and `#` comments so the
+    /// contents of a key file can be passed as is.
+    fn from_str(s: &str) -> Result<Self> {
+        parse_key(s, SIGNING_KEY_PREFIX).map(|key| Self(ed25519_dalek::SigningKey::from_bytes(&key)))
+    }
+}
+
+impl Display for SigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", SIGNING_KEY_PREFIX, hex::encode_upper(self.0.as_bytes()))
+    }
+}
+
+impl Debug for SigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("SigningKey").field(&self.verifying_key()).finish()
+    }
+}
+
+/// An Ed25519 public key identifying a signer.
+///
+/// Serialized as `pngme-vk-` followed by the key in hex.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct VerifyingKey(ed25519_dalek::VerifyingKey);
+
+impl FromStr for VerifyingKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let key = parse_key(s, VERIFYING_KEY_PREFIX)?;
+        ed25519_dalek::VerifyingKey::from_bytes(&key)
+            .map(Self)
+            .map_err(|_| Error::InvalidKey {
+                reason: "not a valid Ed25519 public key".to_string(),
+            })
+    }
+}
+
+impl Display for VerifyingKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", VERIFYING_KEY_PREFIX, hex::encode(self.0.as_bytes()))
+    }
+}
+
+impl Debug for VerifyingKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "VerifyingKey({})", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png::tests::PNG_FILE;
+
+    fn signed_png(key: &SigningKey) -> Png {
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        let chunk_type = ChunkType::from_str("ruSt").unwrap();
+        png.insert_before("IEND", Chunk::new(chunk_type.clone(), b"signed message".to_vec())).unwrap();
+        sign(&mut png, &chunk_type, key).unwrap();
+        png
+    }
+
+    fn replace_data(png: &mut Png, chunk_type: &str, data: &[u8]) {
+        let chunks = png.chunks_mut();
+        let index = chunks
+            .iter()
+            .position(|chunk| chunk.chunk_type().to_string() == chunk_type)
+            .unwrap();
+        let chunk_type = chunks[index].chunk_type().clone();
+        chunks[index] = Chunk::new(chunk_type, data.to_vec());
+    }
+
+    fn message_type() -> ChunkType {
+        ChunkType::from_str("ruSt").unwrap()
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let key = SigningKey::generate();
+        let png = signed_png(&key);
+
+        let verification = verify(&png, &message_type()).unwrap();
+        assert!(verification.is_valid());
+        assert_eq!(verification.signer, key.verifying_key());
+    }
+
+    #[test]
+    fn test_signature_follows_message() {
+        let png = signed_png(&SigningKey::generate());
+        let types: Vec<String> = png.chunks().iter().map(|c| c.chunk_type().to_string()).collect();
+        let message = types.iter().position(|t| t == "ruSt").unwrap();
+        assert_eq!(types[message + 1], SIGNATURE_CHUNK_TYPE);
+    }
+
+    #[test]
+    fn test_signature_survives_serialization() {
+        let png = signed_png(&SigningKey::generate());
+        let png = Png::try_from(png.as_bytes().as_ref()).unwrap();
+        assert!(verify(&png, &message_type()).unwrap().is_valid());
+    }
+
+    #[test]
+    fn test_changed_message() {
+        let mut png = signed_png(&SigningKey::generate());
+        replace_data(&mut png, "ruSt", b"forged message");
+
+        let verification = verify(&png, &message_type()).unwrap();
+        assert!(verification.signature_valid);
+        assert!(!verification.is_valid());
+        assert_eq!(verification.changed, vec![message_type()]);
+    }
+
+    #[test]
+    fn test_changed_pixels() {
+        let mut png = signed_png(&SigningKey::generate());
+        replace_data(&mut png, "IDAT", b"other pixels");
+
+        let verification = verify(&png, &message_type()).unwrap();
+        assert_eq!(verification.changed, vec![ChunkType::from_str("IDAT").unwrap()]);
+    }
+
+    #[test]
+    fn test_added_palette() {
+        let mut png = signed_png(&SigningKey::generate());
+        let palette = Chunk::new(ChunkType::from_str("PLTE").unwrap(), vec![0; 3]);
+        png.chunks_mut().insert(1, palette);
+
+        let verification = verify(&png, &message_type()).unwrap();
+        assert_eq!(verification.changed, vec![ChunkType::from_str("PLTE").unwrap()]);
+    }
+
+    #[test]
+    fn test_forged_signature() {
+        let mut png = signed_png(&SigningKey::generate());
+        let chunk = png.chunk_by_type(SIGNATURE_CHUNK_TYPE).unwrap();
+        let mut data = chunk.data().to_vec();
+        // Flip a bit of the recorded IDAT digest
+        let last_digest = data.len() - SIGNATURE_LEN - 1;
+        data[last_digest] ^= 1;
+        replace_data(&mut png, SIGNATURE_CHUNK_TYPE, &data);
+
+        let verification = verify(&png, &message_type()).unwrap();
+        assert!(!verification.signature_valid);
+        assert_eq!(verification.changed, vec![ChunkType::from_str("IDAT").unwrap()]);
+    }
+
+    #[test]
+    fn test_resign_replaces_signature() {
+        let key = SigningKey::generate();
+        let mut png = signed_png(&SigningKey::generate());
+        sign(&mut png, &message_type(), &key).unwrap();
+
+        let signatures = png
+            .chunks()
+            .iter()
+            .filter(|c| c.chunk_type().to_string() == SIGNATURE_CHUNK_TYPE)
+            .count();
+        assert_eq!(signatures, 1);
+        assert_eq!(verify(&png, &message_type()).unwrap().signer, key.verifying_key());
+    }
+
+    #[test]
+    fn test_unsigned_message() {
+        let png = Png::try_from(&PNG_FILE[..]).unwrap();
+        assert!(matches!(verify(&png, &message_type()), Err(Error::ChunkNotFound { .. })));
+
+        let mut png = png;
+        let missing = sign(&mut png, &message_type(), &SigningKey::generate());
+        assert!(matches!(missing, Err(Error::ChunkNotFound { .. })));
+    }
+
+    #[test]
+    fn test_truncated_signature() {
+        let mut png = signed_png(&SigningKey::generate());
+        let data = png.chunk_by_type(SIGNATURE_CHUNK_TYPE).unwrap().data().to_vec();
+        replace_data(&mut png, SIGNATURE_CHUNK_TYPE, &data[..data.len() - 1]);
+
+        match verify(&png, &message_type()) {
+            Err(Error::InvalidPayload { location, .. }) => assert!(location.chunk_index.is_some()),
+            other => panic!("expected payload error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_key_serialization() {
+        let key = SigningKey::generate();
+        let verifying_key = key.verifying_key();
+
+        let key_file = format!("# verifying key: {}\n{}\n", verifying_key, key);
+        let parsed = SigningKey::from_str(&key_file).unwrap();
+        assert_eq!(parsed.verifying_key(), verifying_key);
+
+        let parsed = VerifyingKey::from_str(&verifying_key.to_string()).unwrap();
+        assert_eq!(parsed, verifying_key);
+        assert!(VerifyingKey::from_str(&key.to_string()).is_err());
+    }
+}
@@ -0,0 +1,432 @@
+//! Checks of the chunk layout rules of the PNG specification.
+
+use std::fmt::Display;
+
+use crate::ihdr::{ColorType, Ihdr};
+use crate::png::Png;
+use crate::Error;
+
+/// How serious a problem found by [`Png::validate`] is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Decoders are allowed to ignore the problem, but the file doesn't
+    /// follow the specification.
+    Warning,
+    /// Conforming decoders may refuse the file.
+    Error,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Warning => write!(f, "warning"),
+            Self::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// A problem found in a PNG file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Index of the chunk at fault, if the problem is tied to one.
+    pub chunk_index: Option<usize>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub(crate) fn error(chunk_index: Option<usize>, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            chunk_index,
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn warning(chunk_index: Option<usize>, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            chunk_index,
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.severity)?;
+        if let Some(index) = self.chunk_index {
+            write!(f, " in chunk {}", index)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// Where a known ancillary chunk may appear relative to PLTE and IDAT.
+#[derive(Clone, Copy)]
+enum Placement {
+    BeforePlte,
+    AfterPlte,
+    BeforeIdat,
+    Anywhere,
+}
+
+/// Known ancillary chunks, where they go and whether they may repeat.
+const ANCILLARY_CHUNKS: [(&str, Placement, bool); 18] = [
+    ("cHRM", Placement::BeforePlte, false),
+    ("cICP", Placement::BeforePlte, false),
+    ("gAMA", Placement::BeforePlte, false),
+    ("iCCP", Placement::BeforePlte, false),
+    ("mDCV", Placement::BeforePlte, false),
+    ("cLLI", Placement::BeforePlte, false),
+    ("sBIT", Placement::BeforePlte, false),
+    ("sRGB", Placement::BeforePlte, false),
+    ("bKGD", Placement::AfterPlte, false),
+    ("hIST", Placement::AfterPlte, false),
+    ("tRNS", Placement::AfterPlte, false),
+    ("eXIf", Placement::BeforeIdat, false),
+    ("pHYs", Placement::BeforeIdat, false),
+    ("sPLT", Placement::BeforeIdat, true),
+    ("tIME", Placement::Anywhere, false),
+    ("iTXt", Placement::Anywhere, true),
+    ("tEXt", Placement::Anywhere, true),
+    ("zTXt", Placement::Anywhere, true),
+];
+
+const CRITICAL_CHUNKS: [&str; 4] = ["IHDR", "PLTE", "IDAT", "IEND"];
+
+/// Whether `chunk_type` is one of the chunks defined by the PNG spec.
+pub(crate) fn is_standard_chunk(chunk_type: &str) -> bool {
+    CRITICAL_CHUNKS.contains(&chunk_type) || ANCILLARY_CHUNKS.iter().any(|(t, _, _)| *t == chunk_type)
+}
+
+impl Png {
+    /// Checks the chunks against the ordering, multiplicity and critical
+    /// chunk rules of the PNG specification.
+    ///
+    /// An empty list means the chunk layout is valid. The pixel data itself
+    /// is not inspected.
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let types: Vec<String> = self
+            .chunks()
+            .iter()
+            .map(|chunk| chunk.chunk_type().to_string())
+            .collect();
+        let first = |chunk_type: &str| types.iter().position(|t| t == chunk_type);
+        let last = |chunk_type: &str| types.iter().rposition(|t| t == chunk_type);
+
+        let mut diagnostics = Vec::new();
+
+        match first("IHDR") {
+            None => diagnostics.push(Diagnostic::error(None, "IHDR is missing")),
+            Some(0) => {}
+            Some(index) => diagnostics.push(Diagnostic::error(Some(index), "IHDR is not the first chunk")),
+        }
+
+        match last("IEND") {
+            None => diagnostics.push(Diagnostic::error(None, "IEND is missing")),
+            Some(index) if index + 1 != types.len() => diagnostics.push(Diagnostic::error(
+                Some(index + 1),
+                format!("{} chunk(s) after IEND", types.len() - index - 1),
+            )),
+            Some(_) => {}
+        }
+        if !self.trailing_data().is_empty() {
+            // Parsing stops at IEND, so chunks appended after it end up in
+            // the trailing data
+            diagnostics.push(match self.trailing_chunks() {
+                Ok(chunks) => Diagnostic::error(None, format!("{} chunk(s) after IEND", chunks.len())),
+                Err(_) => Diagnostic::warning(
+                    None,
+                    format!("{} bytes of data after IEND", self.trailing_data().len()),
+                ),
+            });
+        }
+
+        for chunk_type in ["IHDR", "PLTE", "IEND"] {
+            let second = types
+                .iter()
+                .enumerate()
+                .filter(|(_, t)| *t == chunk_type)
+                .nth(1);
+            if let Some((index, _)) = second {
+                diagnostics.push(Diagnostic::error(
+                    Some(index),
+                    format!("{} appears more than once", chunk_type),
+                ));
+            }
+        }
+
+        let first_idat = first("IDAT");
+        match (first_idat, last("IDAT")) {
+            (Some(start), Some(end)) => {
+                if let Some(offset) = types[start..=end].iter().position(|t| t != "IDAT") {
+                    diagnostics.push(Diagnostic::error(
+                        Some(start + offset),
+                        "IDAT chunks are not consecutive",
+                    ));
+                }
+            }
+            _ => diagnostics.push(Diagnostic::error(None, "IDAT is missing")),
+        }
+
+        let plte = first("PLTE");
+        if let (Some(plte), Some(idat)) = (plte, first_idat) {
+            if plte > idat {
+                diagnostics.push(Diagnostic::error(Some(plte), "PLTE comes after IDAT"));
+            }
+        }
+
+        match self.ihdr() {
+            Ok(ihdr) => diagnostics.extend(check_palette(&ihdr, plte)),
+            Err(Error::ChunkNotFound { .. }) => {}
+            Err(error) => diagnostics.push(Diagnostic::error(first("IHDR"), error.to_string())),
+        }
+
+        for (index, chunk) in self.chunks().iter().enumerate() {
+            let chunk_type = chunk.chunk_type();
+            let name = &types[index];
+
+            if !chunk_type.is_reserved_bit_valid() {
+                diagnostics.push(Diagnostic::error(
+                    Some(index),
+                    format!("{} has the reserved bit set", name),
+                ));
+            } else if chunk_type.is_critical() && !CRITICAL_CHUNKS.contains(&name.as_str()) {
+                diagnostics.push(Diagnostic::error(
+                    Some(index),
+                    format!("unknown critical chunk {}", name),
+                ));
+            }
+
+            let (placement, repeatable) = match ANCILLARY_CHUNKS.iter().find(|(t, _, _)| t == name) {
+                Some((_, placement, repeatable)) => (*placement, *repeatable),
+                None => continue,
+            };
+
+            if !repeatable && first(name) != Some(index) {
+                diagnostics.push(Diagnostic::warning(
+                    Some(index),
+                    format!("{} appears more than once", name),
+                ));
+            }
+
+            let before_idat = first_idat.is_none_or(|idat| index < idat);
+            let misplaced = match placement {
+                Placement::BeforePlte => {
+                    !before_idat || plte.is_some_and(|plte| index > plte)
+                }
+                Placement::AfterPlte => {
+                    !before_idat || plte.is_some_and(|plte| index < plte)
+                }
+                Placement::BeforeIdat => !before_idat,
+                Placement::Anywhere => false,
+            };
+            if misplaced {
+                let rule = match placement {
+                    Placement::BeforePlte => "before PLTE and IDAT",
+                    Placement::AfterPlte => "after PLTE and before IDAT",
+                    _ => "before IDAT",
+                };
+                diagnostics.push(Diagnostic::warning(
+                    Some(index),
+                    format!("{} must come {}", name, rule),
+                ));
+            }
+        }
+
+        if let (Some(_), Some(srgb)) = (first("iCCP"), first("sRGB")) {
+            diagnostics.push(Diagnostic::warning(
+                Some(srgb),
+                "iCCP and sRGB must not both be present",
+            ));
+        }
+
+        diagnostics.sort_by_key(|diagnostic| diagnostic.chunk_index);
+        diagnostics
+    }
+}
+
+/// Checks that a palette is present exactly when the colour type of the
+/// image needs or allows it.
+fn check_palette(ihdr: &Ihdr, plte: Option<usize>) -> Vec<Diagnostic> {
+    match (ihdr.color_type, plte) {
+        (ColorType::Indexed, None) => {
+            vec![Diagnostic::error(None, "PLTE is missing for an indexed-colour image")]
+        }
+        (ColorType::Grayscale | ColorType::GrayscaleAlpha, Some(plte)) => vec![Diagnostic::error(
+            Some(plte),
+            "PLTE must not appear in a greyscale image",
+        )],
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::chunk::Chunk;
+    use crate::chunk_type::ChunkType;
+    use crate::png::tests::PNG_FILE;
+
+    fn chunk(chunk_type: &str) -> Chunk {
+        let data = match chunk_type {
+            // 1x1 truecolour image
+            "IHDR" => vec![0, 0, 0, 1, 0, 0, 0, 1, 8, 2, 0, 0, 0],
+            _ => Vec::new(),
+        };
+        Chunk::new(ChunkType::from_str(chunk_type).unwrap(), data)
+    }
+
+    fn png_of(chunk_types: &[&str]) -> Png {
+        Png::from_chunks(chunk_types.iter().map(|t| chunk(t)).collect())
+    }
+
+    fn messages(png: &Png) -> Vec<(Severity, Option<usize>, String)> {
+        png.validate()
+            .into_iter()
+            .map(|d| (d.severity, d.chunk_index, d.message))
+            .collect()
+    }
+
+    #[test]
+    fn test_image_file() {
+        // The test image carries a critical "RuSt" chunk no decoder knows
+        let png = Png::try_from(&PNG_FILE[..]).unwrap();
+        assert_eq!(
+            messages(&png),
+            [(Severity::Error, Some(5), "unknown critical chunk RuSt".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_minimal_png_is_valid() {
+        let png = png_of(&["IHDR", "gAMA", "IDAT", "IDAT", "tEXt", "IEND"]);
+        assert_eq!(png.validate(), Vec::new());
+    }
+
+    #[test]
+    fn test_missing_critical_chunks() {
+        let messages = messages(&png_of(&["tEXt"]));
+        assert_eq!(messages.len(), 3);
+        assert!(messages.iter().all(|(severity, index, _)| *severity == Severity::Error && index.is_none()));
+    }
+
+    #[test]
+    fn test_ihdr_not_first_and_duplicated() {
+        let png = png_of(&["gAMA", "IHDR", "IHDR", "IDAT", "IEND"]);
+        assert_eq!(
+            messages(&png),
+            [
+                (Severity::Error, Some(1), "IHDR is not the first chunk".to_string()),
+                (Severity::Error, Some(2), "IHDR appears more than once".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_chunks_after_iend() {
+        let png = png_of(&["IHDR", "IDAT", "IEND", "ruSt"]);
+        assert_eq!(
+            messages(&png),
+            [(Severity::Error, Some(3), "1 chunk(s) after IEND".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_chunks_in_trailing_data() {
+        let mut png = png_of(&["IHDR", "IDAT", "IEND"]);
+        png.set_trailing_data([chunk("ruSt").as_bytes(), chunk("ruSt").as_bytes()].concat());
+        assert_eq!(
+            messages(&Png::try_from(png.as_bytes().as_slice()).unwrap()),
+            [(Severity::Error, None, "2 chunk(s) after IEND".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_trailing_data() {
+        let mut png = png_of(&["IHDR", "IDAT", "IEND"]);
+        png.set_trailing_data(b"appended archive".to_vec());
+        assert_eq!(
+            messages(&png),
+            [(Severity::Warning, None, "16 bytes of data after IEND".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_non_consecutive_idat() {
+        let png = png_of(&["IHDR", "IDAT", "tEXt", "IDAT", "IEND"]);
+        assert_eq!(
+            messages(&png),
+            [(Severity::Error, Some(2), "IDAT chunks are not consecutive".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_plte_after_idat() {
+        let png = png_of(&["IHDR", "IDAT", "PLTE", "IEND"]);
+        let messages = messages(&png);
+        assert!(messages.contains(&(Severity::Error, Some(2), "PLTE comes after IDAT".to_string())));
+    }
+
+    #[test]
+    fn test_palette_rules() {
+        let mut png = png_of(&["IHDR", "IDAT", "IEND"]);
+        let mut ihdr = chunk("IHDR").data().to_vec();
+        ihdr[9] = 3;
+        png.chunks_mut()[0] = Chunk::new(ChunkType::from_str("IHDR").unwrap(), ihdr.clone());
+        assert_eq!(
+            messages(&png),
+            [(Severity::Error, None, "PLTE is missing for an indexed-colour image".to_string())]
+        );
+
+        ihdr[9] = 0;
+        png.chunks_mut()[0] = Chunk::new(ChunkType::from_str("IHDR").unwrap(), ihdr);
+        png.insert_before("IDAT", chunk("PLTE")).unwrap();
+        assert_eq!(
+            messages(&png),
+            [(Severity::Error, Some(1), "PLTE must not appear in a greyscale image".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_ancillary_rules() {
+        let png = png_of(&["IHDR", "PLTE", "gAMA", "tRNS", "IDAT", "pHYs", "tIME", "tIME", "IEND"]);
+        assert_eq!(
+            messages(&png),
+            [
+                (Severity::Warning, Some(2), "gAMA must come before PLTE and IDAT".to_string()),
+                (Severity::Warning, Some(5), "pHYs must come before IDAT".to_string()),
+                (Severity::Warning, Some(7), "tIME appears more than once".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_ihdr() {
+        let mut png = png_of(&["IHDR", "IDAT", "IEND"]);
+        png.chunks_mut()[0] = Chunk::new(ChunkType::from_str("IHDR").unwrap(), vec![0; 12]);
+
+        let messages = messages(&png);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].1, Some(0));
+        assert!(messages[0].2.contains("IHDR holds 12 bytes"));
+    }
+
+    #[test]
+    fn test_unknown_critical_chunk() {
+        let png = png_of(&["IHDR", "RUSt", "IDAT", "IEND"]);
+        assert_eq!(
+            messages(&png),
+            [(Severity::Error, Some(1), "unknown critical chunk RUSt".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_diagnostic_display() {
+        let diagnostic = Diagnostic::warning(Some(4), "tIME appears more than once");
+        assert_eq!(diagnostic.to_string(), "warning in chunk 4: tIME appears more than once");
+    }
+}
@@ -0,0 +1,240 @@
+//! Writing chunks one at a time to any [`Write`].
+
+use std::io::{self, Read, Write};
+
+use crate::chunk::Chunk;
+use crate::chunk_type::ChunkType;
+use crate::png::Png;
+use crate::reader::ChunkReader;
+use crate::{Error, Location, Result};
+
+/// Writes the PNG signature, then chunks as they are handed over, without
+/// serializing them to a buffer first.
+///
+/// Nothing checks that the chunks make a valid PNG: writing IHDR first and
+/// IEND last is up to the caller.
+pub struct ChunkWriter<W> {
+    writer: W,
+    offset: u64,
+}
+
+impl<W: Write> ChunkWriter<W> {
+    /// Writes the PNG signature.
+    pub fn new(writer: W) -> Result<Self> {
+        let mut chunk_writer = Self { writer, offset: 0 };
+        chunk_writer.write(&Png::STANDARD_HEADER)?;
+        Ok(chunk_writer)
+    }
+
+    /// Number of bytes written so far.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Writes `chunk` with the CRC it holds.
+    pub fn write_chunk(&mut self, chunk: &Chunk) -> Result<()> {
+        self.write(&(chunk.length() as u32).to_be_bytes())?;
+        self.write(&chunk.chunk_type().bytes())?;
+        self.write(chunk.data())?;
+        self.write(&chunk.crc().to_be_bytes())
+    }
+
+    /// Writes a chunk holding `data`, computing its CRC as it goes.
+    pub fn write_new_chunk(&mut self, chunk_type: &ChunkType, data: &[u8]) -> Result<()> {
+        let length = u32::try_from(data.len())
+            .ok()
+            .filter(|length| *length <= Chunk::MAX_LENGTH)
+            .ok_or(Error::InvalidChunkLength {
+                location: Location::at(self.offset),
+                length: data.len() as u64,
+                available: Chunk::MAX_LENGTH as u64,
+            })?;
+
+        let mut digest = Chunk::crc_digest(chunk_type);
+        digest.update(data);
+        self.write(&length.to_be_bytes())?;
+        self.write(&chunk_type.bytes())?;
+        self.write(data)?;
+        self.write(&digest.finalize().to_be_bytes())
+    }
+
+    /// Writes bytes that aren't part of any chunk, such as data after IEND.
+    pub fn write_trailing_data(&mut self, data: &[u8]) -> Result<()> {
+        self.write(data)
+    }
+
+    /// Flushes what was written and returns the underlying writer.
+    pub fn finish(mut self) -> Result<W> {
+        let offset = self.offset;
+        self.writer.flush().map_err(|source| Error::io(offset, source))?;
+        Ok(self.writer)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        let offset = self.offset;
+        self.writer
+            .write_all(bytes)
+            .map_err(|source| Error::io(offset, source))?;
+        self.offset += bytes.len() as u64;
+        Ok(())
+    }
+}
+
+impl Png {
+    /// Writes the PNG to `writer` chunk by chunk, trailing data included.
+    /// Writes the same bytes as [`Png::as_bytes`] without copying them.
+    pub fn write_to<W: Write>(&self, writer: W) -> Result<W> {
+        let mut writer = ChunkWriter::new(writer)?;
+        for chunk in self.chunks() {
+            writer.write_chunk(chunk)?;
+        }
+        writer.write_trailing_data(self.trailing_data())?;
+        writer.finish()
+    }
+}
+
+/// Copies the PNG read from `input` to `output` one chunk at a time,
+/// writing the chunks `edit` returns in place of each chunk read: the chunk
+/// itself to keep it, nothing to drop it, other chunks to replace it or
+/// chunks around it to inject them. Data after IEND is copied as it is.
+///
+/// Only one chunk is held in memory at a time.
+///
+/// ```
+/// use std::str::FromStr;
+///
+/// use pngme::chunk::Chunk;
+/// use pngme::chunk_type::ChunkType;
+/// use pngme::writer::rewrite;
+///
+/// # let input = pngme::png::Png::from_chunks(vec![Chunk::new(ChunkType::from_str("IEND")?, Vec::new())]).as_bytes();
+/// let message = Chunk::new(ChunkType::from_str("ruSt")?, b"hidden".to_vec());
+/// let output = rewrite(input.as_slice(), Vec::new(), |chunk| {
+///     Ok(match chunk.chunk_type().to_string().as_str() {
+///         "IEND" => vec![message.clone(), chunk],
+///         "ruSt" => Vec::new(),
+///         _ => vec![chunk],
+///     })
+/// })?;
+/// # Ok::<(), pngme::Error>(())
+/// ```
+pub fn rewrite<R, W>(input: R, output: W, mut edit: impl FnMut(Chunk) -> Result<Vec<Chunk>>) -> Result<W>
+where
+    R: Read,
+    W: Write,
+{
+    let mut reader = ChunkReader::new(input)?;
+    let mut writer = ChunkWriter::new(output)?;
+
+    for chunk in reader.by_ref() {
+        for chunk in edit(chunk?)? {
+            writer.write_chunk(&chunk)?;
+        }
+    }
+
+    let offset = reader.offset();
+    let mut trailing_data = reader.into_inner();
+    io::copy(&mut trailing_data, &mut writer.writer).map_err(|source| Error::io(offset, source))?;
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::png::tests::PNG_FILE;
+
+    /// A writer failing once `limit` bytes have been written.
+    struct Full {
+        written: usize,
+        limit: usize,
+    }
+
+    impl Write for Full {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.written + buf.len() > self.limit {
+                return Err(io::Error::new(io::ErrorKind::StorageFull, "disk full"));
+            }
+            self.written += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_to_matches_as_bytes() {
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        png.set_trailing_data(b"trailing".to_vec());
+        assert_eq!(png.write_to(Vec::new()).unwrap(), png.as_bytes());
+    }
+
+    #[test]
+    fn test_write_new_chunk() {
+        let chunk_type = ChunkType::from_str("ruSt").unwrap();
+        let mut writer = ChunkWriter::new(Vec::new()).unwrap();
+        writer.write_new_chunk(&chunk_type, b"hidden").unwrap();
+        assert_eq!(writer.offset(), 8 + 12 + 6);
+
+        let chunk = Chunk::new(chunk_type, b"hidden".to_vec());
+        let bytes = writer.finish().unwrap();
+        assert_eq!(bytes[8..], chunk.as_bytes());
+    }
+
+    #[test]
+    fn test_write_error_location() {
+        let png = Png::try_from(&PNG_FILE[..]).unwrap();
+        let error = png.write_to(Full { written: 0, limit: 100 }).err().unwrap();
+        // Fails writing the IDAT data, after its header at byte 83
+        assert!(matches!(error, Error::Io { .. }));
+        assert_eq!(error.location(), Some(Location::at(91)));
+    }
+
+    #[test]
+    fn test_rewrite_unchanged() {
+        let bytes = [&PNG_FILE[..], b"trailing"].concat();
+        let output = rewrite(bytes.as_slice(), Vec::new(), |chunk| Ok(vec![chunk])).unwrap();
+        assert_eq!(output, bytes);
+    }
+
+    #[test]
+    fn test_rewrite_edits() {
+        let message = Chunk::new(ChunkType::from_str("ruSt").unwrap(), b"hidden".to_vec());
+        let output = rewrite(&PNG_FILE[..], Vec::new(), |chunk| {
+            Ok(match chunk.chunk_type().to_string().as_str() {
+                "gAMA" => Vec::new(),
+                "RuSt" => vec![message.clone()],
+                "IEND" => vec![message.clone(), chunk],
+                _ => vec![chunk],
+            })
+        })
+        .unwrap();
+
+        let mut expected = Png::try_from(&PNG_FILE[..]).unwrap();
+        expected.remove_chunk("gAMA").unwrap();
+        expected.remove_chunk("RuSt").unwrap();
+        expected.insert_before("IEND", message.clone()).unwrap();
+        expected.insert_before("IEND", message).unwrap();
+        assert_eq!(output, expected.as_bytes());
+    }
+
+    #[test]
+    fn test_rewrite_stops_at_errors() {
+        let mut bytes = PNG_FILE.to_vec();
+        bytes[1000] ^= 0x01;
+        assert!(matches!(
+            rewrite(bytes.as_slice(), Vec::new(), |chunk| Ok(vec![chunk])),
+            Err(Error::InvalidCrc { .. })
+        ));
+
+        let error = rewrite(&PNG_FILE[..], Vec::new(), |_| {
+            Err(Error::ChunkNotFound {
+                chunk_type: "ruSt".to_string(),
+            })
+        });
+        assert!(matches!(error, Err(Error::ChunkNotFound { .. })));
+    }
+}